@@ -9,35 +9,134 @@ extern "C" {
 extern int nvim_set_option_bool(const char *name, int value);
 extern int nvim_set_option_int(const char *name, int value);
 extern int nvim_set_option_string(const char *name, const char *value);
+extern int nvim_set_option_multi(const char *name, const char *pairs_json);
+extern int nvim_set_option_global_local(const char *name,
+                                        const char *value_json);
+extern int nvim_reset_option(const char *name);
+extern int nvim_option_append_unique(const char *name, const char *value);
 extern int nvim_set_global(const char *name, const char *value);
+extern int nvim_set_var(const char *scope, const char *name,
+                        const char *value_json);
 extern int nvim_create_keymap(const char *mode, const char *lhs,
                               const char *rhs, const char *opts);
 extern int nvim_create_user_command(const char *name, const char *command,
                                     const char *opts);
+extern int nvim_create_user_command_fn(const char *name, const char *fn_name,
+                                       const char *opts);
 extern int nvim_create_autocmd(const char *event, const char *pattern,
                                const char *command, const char *group);
 extern int nvim_create_augroup(const char *name, int clear);
 extern int nvim_buf_set_keymap(int buffer, const char *mode, const char *lhs,
                                const char *rhs, const char *opts);
 extern int nvim_exec_command(const char *command);
+extern int nvim_exec2_block(const char *script);
+extern int nvim_create_abbrev(const char *mode, const char *lhs,
+                              const char *rhs);
+extern int nvim_set_default_map_opts(const char *opts);
+extern int nvim_set_warn_overwrite(int enabled);
+extern int nvim_set_warn_shadowed(int enabled);
+extern int nvim_set_legacy_maps(int enabled);
+extern int nvim_clear_buffer_keymaps(int buffer);
+extern int nvim_map_desc(const char *mode, const char *lhs, const char *rhs,
+                         const char *desc);
+extern int nvim_map_fn(const char *mode, const char *lhs, const char *fn_path,
+                       const char *opts);
+extern int nvim_set_keymap_expr(const char *mode, const char *lhs,
+                                const char *rhs, const char *opts);
+extern int nvim_del_var(const char *scope, const char *name);
+extern int nvim_echo(const char *msg, const char *hl_group, int history);
+extern int nvim_confirm(const char *msg, const char *choices,
+                        int default_choice);
+extern int nvim_feedkeys_wait(const char *keys, int ms);
+extern int nvim_highlight_link(const char *from, const char *to);
+extern int nvim_source_runtime(const char *pattern, int all);
+extern char *nvim_get_runtime_file(const char *pattern, int all);
+extern int nvim_redraw(void);
+extern int nvim_redrawstatus(void);
+
+// Signs
+extern int nvim_sign_define(const char *name, const char *text,
+                            const char *texthl);
+extern int nvim_sign_place(int id, const char *group, const char *name,
+                           int buffer, int lnum);
+extern int nvim_sign_unplace(const char *group, int buffer);
+
+// Namespaces and extmarks
+extern int nvim_create_namespace(const char *name);
+extern int nvim_buf_clear_namespace(int buffer, int ns, int line_start,
+                                    int line_end);
+extern int nvim_buf_set_extmark(int buffer, int ns, int line, int col,
+                                const char *opts_json);
+extern int nvim_buf_add_highlight(int buffer, int ns, const char *hl_group,
+                                  int line, int col_start, int col_end);
+
+// Buffer and window management
+extern int nvim_set_current_buf(int buffer);
+extern int nvim_buf_get_changedtick(int buffer);
+extern int nvim_buf_is_modified(int buffer);
+extern int nvim_buf_set_name(int buffer, const char *name);
+extern int nvim_set_current_line(const char *text);
+extern int nvim_buf_set_var(int buffer, const char *name,
+                            const char *value_json);
+extern int nvim_buf_attach(int buffer, const char *on_lines_fn);
+extern int nvim_set_current_win(int win);
+extern int nvim_win_close(int win, int force);
+extern int nvim_win_hide(int win);
+extern int nvim_set_current_tabpage(int tabpage);
+extern int nvim_split(const char *direction, const char *file);
+extern int nvim_tabnew(const char *file);
+extern int nvim_set_current_dir(const char *path);
 
 // Enhanced Lua API for autocmds
 extern int nvim_create_augroup_lua(const char *name, int clear);
 extern int nvim_create_autocmd_lua(const char *event, const char *pattern,
                                    const char *command, const char *group);
+extern int nvim_exec_autocmds(const char *event, const char *pattern);
+extern int nvim_set_autocmd_callback(const char *event, const char *pattern,
+                                     const char *fn_name, int schedule);
+
+// LSP
+extern int nvim_lsp_format(int buffer, int async_format);
+
+// Command palette actions
+extern int register_action(const char *name, const char *fn_path,
+                           const char *desc);
+
+// Jobs
+extern int nvim_jobstart(const char *cmd_list_json, const char *on_exit_fn);
+extern int nvim_term_open(const char *cmd);
+extern int stop_all_jobs(void);
+
+// Error handling
+extern int set_safe_mode(int enabled);
+extern int set_defer_commands(int enabled);
+extern int set_strict_options(int enabled);
+extern int set_skip_noop(int enabled);
+extern int nvim_notify_once(const char *key, const char *msg, int level);
+extern int defer_until(const char *condition_fn, const char *action_fn,
+                       int interval_ms, int max_polls);
+
+// State
+extern int reset_state(void);
 
 // Legacy functions
 extern int opt(const char *key, const char *old_val, const char *new_val);
 extern int autocmd(const char *event, const char *pattern, const char *command);
 extern int exec_lua(const char *code);
+extern int exec_lua_file_with_args(const char *path, const char *const *args,
+                                   int nargs);
 extern int setup_lsp(const char *server, const char *config_json);
 
 // Plugin manager
 extern int register_plugin(const char *name, const char *url);
 extern int configure_plugin(const char *name, const char *config);
 extern int install_plugins(void);
+extern int set_clone_depth(int depth);
 extern int load_plugin_configs(void);
+extern int load_plugin_config(const char *name);
 extern int update_plugins(void);
+extern int check_plugin_updates(void);
+extern int check_health(void);
 
 // Structured plugin configuration API
 extern int plugin_config_begin(const char *plugin_name);
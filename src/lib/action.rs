@@ -0,0 +1,193 @@
+use std::os::raw::{c_char, c_int};
+use std::sync::{Mutex, OnceLock};
+
+use crate::{extract_c_string, fn_ref, lua_quote, Error, Lua, LuaState, Result};
+
+/// A named action offered to command palette UIs
+#[derive(Debug, Clone)]
+struct Action {
+    name: String,
+    /// Path to the Lua function run when the action is picked
+    fn_path: String,
+    desc: String,
+}
+
+impl Action {
+    /// Renders the action as a `{ name, desc, fn }` Lua table literal
+    fn to_lua(&self) -> Result<String> {
+        Ok(format!(
+            "{{ name = {}, desc = {}, fn = {} }}",
+            lua_quote(&self.name),
+            lua_quote(&self.desc),
+            fn_ref(&self.fn_path)?
+        ))
+    }
+}
+
+// Registered actions, in registration order
+static ACTIONS: OnceLock<Mutex<Vec<Action>>> = OnceLock::new();
+
+fn actions() -> &'static Mutex<Vec<Action>> {
+    ACTIONS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Forgets every registered action, for `rns.reset`
+pub(crate) fn reset() {
+    if let Ok(mut actions) = actions().lock() {
+        actions.clear();
+    }
+}
+
+/// Registers an action, replacing any earlier one of the same name in place
+fn add_action(name: &str, fn_path: &str, desc: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+
+    // Reject bad paths now rather than when the palette is opened
+    fn_ref(fn_path)?;
+
+    let action = Action {
+        name: name.to_string(),
+        fn_path: fn_path.to_string(),
+        desc: desc.to_string(),
+    };
+
+    let mut actions = actions().lock().map_err(|_| Error::CommandExecution)?;
+    match actions.iter_mut().find(|existing| existing.name == name) {
+        Some(existing) => *existing = action,
+        None => actions.push(action),
+    }
+
+    Ok(())
+}
+
+/// Builds a Lua array of every registered action, in registration order
+fn actions_expr() -> Result<String> {
+    let actions = actions().lock().map_err(|_| Error::CommandExecution)?;
+    if actions.is_empty() {
+        return Ok("{}".to_string());
+    }
+
+    let items = actions
+        .iter()
+        .map(Action::to_lua)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(format!("{{ {} }}", items.join(", ")))
+}
+
+/// Registers a named action for command palettes
+///
+/// # Safety
+///
+/// `name`, `fn_path`, and `desc` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn register_action(
+    name: *const c_char,
+    fn_path: *const c_char,
+    desc: *const c_char,
+) -> c_int {
+    match (
+        extract_c_string(name),
+        extract_c_string(fn_path),
+        extract_c_string(desc),
+    ) {
+        (Ok(name), Ok(fn_path), Ok(desc)) => match add_action(&name, &fn_path, &desc) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Registers Lua bindings for the action registry
+pub fn register_action_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_register_action(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let fn_path = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let desc = match lua.opt_string(3) {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => return 0,
+        };
+
+        match add_action(&name, &fn_path, &desc) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_get_actions(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        // Return the actions as an array of `{ name, desc, fn }` tables
+        match actions_expr().and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_register_action, 0);
+    lua.set_field(-2, "register_action")?;
+
+    lua.push_cclosure(lua_get_actions, 0);
+    lua.set_field(-2, "get_actions")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn renders_actions() {
+        let action = Action {
+            name: "Format".to_string(),
+            fn_path: "vim.lsp.buf.format".to_string(),
+            desc: "Format the buffer".to_string(),
+        };
+        assert_eq!(
+            action.to_lua().unwrap(),
+            "{ name = 'Format', desc = 'Format the buffer', \
+             fn = function(...) return vim.lsp.buf.format(...) end }"
+        );
+    }
+
+    #[test]
+    fn replaces_actions_of_the_same_name_in_place() {
+        let _serial = mock::serial();
+        add_action("test.first", "vim.cmd.w", "Write").unwrap();
+        add_action("test.second", "vim.cmd.q", "Quit").unwrap();
+        add_action("test.first", "vim.cmd.wa", "Write all").unwrap();
+        assert!(add_action("", "vim.cmd.w", "").is_err());
+        assert!(add_action("test.bad", "vim.cmd.w()", "").is_err());
+
+        let expr = actions_expr().unwrap();
+        let first = expr
+            .find("name = 'test.first', desc = 'Write all'")
+            .unwrap();
+        let second = expr.find("name = 'test.second'").unwrap();
+        assert!(first < second);
+        assert!(!expr.contains("name = 'test.bad'"));
+        reset();
+    }
+}
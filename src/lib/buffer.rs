@@ -0,0 +1,780 @@
+use std::convert::TryFrom;
+use std::os::raw::{c_char, c_int};
+
+use crate::json::Json;
+use crate::{
+    callback_ref, extract_c_string, lua_quote, validate_identifier, Error, Lua, LuaState, Result,
+    Value,
+};
+
+/// Makes `buffer` the current buffer
+fn set_current_buf(buffer: i64) -> Result<()> {
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!("vim.api.nvim_set_current_buf({buffer})"))
+}
+
+/// Sets an option local to `buffer`, which may be 0 for the current buffer
+fn buf_set_option(buffer: i64, name: &str, value: &Value) -> Result<()> {
+    validate_identifier(name)?;
+    if buffer < 0 || *value == Value::Nil {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!(
+        "vim.api.nvim_set_option_value({}, {}, {{ buf = {buffer} }})",
+        lua_quote(name),
+        value.to_lua()
+    ))
+}
+
+/// Sets the file name of `buffer`, which may be 0 for the current buffer
+fn buf_set_name(buffer: i64, name: &str) -> Result<()> {
+    if buffer < 0 || name.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!(
+        "vim.api.nvim_buf_set_name({buffer}, {})",
+        lua_quote(name)
+    ))
+}
+
+/// Builds the expression reading a buffer variable, evaluating to `nil` if unset
+fn buf_get_var_expr(buffer: i64, name: &str) -> Result<String> {
+    validate_identifier(name)?;
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!("vim.b[{buffer}][{}]", lua_quote(name)))
+}
+
+/// Sets a variable of `buffer` to `value`, given as a Lua expression
+fn buf_set_var(buffer: i64, name: &str, value: &str) -> Result<()> {
+    validate_identifier(name)?;
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!(
+        "vim.api.nvim_buf_set_var({buffer}, {}, {value})",
+        lua_quote(name)
+    ))
+}
+
+/// Replaces the line under the cursor with `text`
+fn set_current_line(text: &str) -> Result<()> {
+    // A line cannot span several lines; the API would reject it anyway
+    if text.contains('\n') {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!(
+        "vim.api.nvim_set_current_line({})",
+        lua_quote(text)
+    ))
+}
+
+/// Builds the `nvim_buf_attach` call forwarding `on_lines` events to `on_lines_fn`
+///
+/// The call evaluates to `false` if the buffer could not be attached to. A
+/// scheduled callback cannot detach by returning `true`.
+fn buf_attach_expr(buffer: i64, on_lines_fn: &str, schedule: bool) -> Result<String> {
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!(
+        "vim.api.nvim_buf_attach({buffer}, false, {{ on_lines = {} }})",
+        callback_ref(on_lines_fn, schedule)?
+    ))
+}
+
+/// Builds the `nvim_buf_line_count` call for `buffer`, which may be 0 for the current buffer
+fn buf_line_count_expr(buffer: i64) -> Result<String> {
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!("vim.api.nvim_buf_line_count({buffer})"))
+}
+
+/// Builds the `nvim_buf_get_changedtick` call for `buffer`, which may be 0 for the current buffer
+fn buf_get_changedtick_expr(buffer: i64) -> Result<String> {
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!("vim.api.nvim_buf_get_changedtick({buffer})"))
+}
+
+/// Returns the changedtick of the given buffer, or 0 on failure
+///
+/// The tick increases with every change to the buffer, so a cache keyed on
+/// it is stale once the tick moves on.
+#[no_mangle]
+pub extern "C" fn nvim_buf_get_changedtick(buffer: c_int) -> c_int {
+    buf_get_changedtick_expr(buffer.into())
+        .and_then(|expr| crate::eval_integer(&expr))
+        .and_then(|tick| c_int::try_from(tick).map_err(|_| Error::InvalidArgument))
+        .unwrap_or(0)
+}
+
+/// Builds an expression evaluating to whether `buffer`, which may be 0 for the current buffer, has unsaved changes
+fn buf_is_modified_expr(buffer: i64) -> Result<String> {
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!("vim.bo[{buffer}].modified"))
+}
+
+/// Returns 1 if the given buffer has unsaved changes, or 0 if not or on failure
+#[no_mangle]
+pub extern "C" fn nvim_buf_is_modified(buffer: c_int) -> c_int {
+    buf_is_modified_expr(buffer.into())
+        .and_then(|expr| crate::eval_integer(&format!("{expr} and 1 or 0")))
+        .map_or(0, |modified| c_int::from(modified == 1))
+}
+
+/// Makes the given buffer the current buffer
+#[no_mangle]
+pub extern "C" fn nvim_set_current_buf(buffer: c_int) -> c_int {
+    match set_current_buf(buffer.into()) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Sets the file name of the given buffer
+///
+/// # Safety
+///
+/// `name` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_buf_set_name(buffer: c_int, name: *const c_char) -> c_int {
+    let name = match extract_c_string(name) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    match buf_set_name(buffer.into(), &name) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Sets a buffer-scoped variable to a JSON value
+///
+/// Numbers, strings, booleans, arrays, and objects keep their type; `null`
+/// is rejected, since a variable cannot hold `nil`.
+///
+/// # Safety
+///
+/// `name` and `value_json` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_buf_set_var(
+    buffer: c_int,
+    name: *const c_char,
+    value_json: *const c_char,
+) -> c_int {
+    let name = match extract_c_string(name) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let value = match extract_c_string(value_json).and_then(|json| Json::parse(&json)) {
+        Ok(Json::Null) | Err(_) => return 0,
+        Ok(value) => value,
+    };
+
+    match buf_set_var(buffer.into(), &name, &value.to_lua()) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Replaces the line under the cursor
+///
+/// # Safety
+///
+/// `text` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_set_current_line(text: *const c_char) -> c_int {
+    match extract_c_string(text).and_then(|text| set_current_line(&text)) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+// Evaluates to the text of the last visual selection in the current buffer,
+// with lines joined by newlines, or nil if there has been none. The `'<` and
+// `'>` marks hold byte columns, and in charwise mode `'>` points at the first
+// byte of the last selected character, so that character is widened to its
+// last byte. Blockwise selections are cut at the same byte columns on every
+// line, which is only exact for single-width text.
+const VISUAL_SELECTION_EXPR: &str = r"(function()
+    local start_pos = vim.fn.getpos([['<]])
+    local end_pos = vim.fn.getpos([['>]])
+    local srow, scol = start_pos[2], start_pos[3]
+    local erow, ecol = end_pos[2], end_pos[3]
+    if srow == 0 or erow == 0 then return nil end
+    if srow > erow or (srow == erow and scol > ecol) then
+        srow, scol, erow, ecol = erow, ecol, srow, scol
+    end
+
+    local lines = vim.api.nvim_buf_get_lines(0, srow - 1, erow, false)
+    if #lines == 0 then return nil end
+
+    local mode = vim.fn.visualmode()
+    if mode == 'V' then
+        return table.concat(lines, '\n')
+    end
+
+    if mode == '\22' then
+        local left, right = math.min(scol, ecol), math.max(scol, ecol)
+        for i, line in ipairs(lines) do
+            lines[i] = line:sub(left, right)
+        end
+        return table.concat(lines, '\n')
+    end
+
+    local last = lines[#lines]
+    if ecol > #last then
+        ecol = #last
+    elseif ecol > 0 and vim.str_utf_end then
+        ecol = ecol + vim.str_utf_end(last, ecol)
+    end
+    lines[#lines] = last:sub(1, ecol)
+    lines[1] = lines[1]:sub(scol)
+    return table.concat(lines, '\n')
+end)()";
+
+/// Calls a Lua function whenever lines in the given buffer change
+///
+/// # Safety
+///
+/// `on_lines_fn` must be a valid null-terminated C string naming a Lua function.
+#[no_mangle]
+pub extern "C" fn nvim_buf_attach(buffer: c_int, on_lines_fn: *const c_char) -> c_int {
+    let on_lines_fn = match extract_c_string(on_lines_fn) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    match buf_attach_expr(buffer.into(), &on_lines_fn, false).and_then(|expr| crate::run_lua(&expr))
+    {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Registers Lua bindings for buffer functions
+pub fn register_buffer_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_buf_is_valid(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match lua.eval(&format!("vim.api.nvim_buf_is_valid({buffer})")) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_current_buf(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match set_current_buf(buffer) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_set_option(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let value = match lua.check_value(3) {
+            Ok(v) => v,
+            Err(_) => return 0,
+        };
+
+        match buf_set_option(buffer, &name, &value) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_line_count(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua
+            .check_integer(1)
+            .and_then(buf_line_count_expr)
+            .and_then(|expr| lua.eval(&expr))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_get_changedtick(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua
+            .check_integer(1)
+            .and_then(buf_get_changedtick_expr)
+            .and_then(|expr| lua.eval(&expr))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_is_modified(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua
+            .check_integer(1)
+            .and_then(buf_is_modified_expr)
+            .and_then(|expr| lua.eval(&expr))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_get_name(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) if n >= 0 => n,
+            _ => return 0,
+        };
+
+        match lua.eval(&format!("vim.api.nvim_buf_get_name({buffer})")) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_set_name(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match buf_set_name(buffer, &name) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_get_var(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match buf_get_var_expr(buffer, &name).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_set_var(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let value = match lua.check_value(3) {
+            Ok(Value::Nil) | Err(_) => return 0,
+            Ok(v) => v,
+        };
+
+        match buf_set_var(buffer, &name, &value.to_lua()) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_get_current_line(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.eval("vim.api.nvim_get_current_line()") {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_get_visual_selection(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.eval(VISUAL_SELECTION_EXPR) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_current_line(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.check_string(1).and_then(|text| set_current_line(&text)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_attach(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let on_lines_fn = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match buf_attach_expr(buffer, &on_lines_fn, lua.to_boolean(3))
+            .and_then(|expr| lua.eval(&expr))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_buf_is_valid, 0);
+    lua.set_field(-2, "buf_is_valid")?;
+
+    lua.push_cclosure(lua_set_current_buf, 0);
+    lua.set_field(-2, "set_current_buf")?;
+
+    lua.push_cclosure(lua_buf_set_option, 0);
+    lua.set_field(-2, "buf_set_option")?;
+
+    lua.push_cclosure(lua_buf_line_count, 0);
+    lua.set_field(-2, "buf_line_count")?;
+
+    lua.push_cclosure(lua_buf_get_changedtick, 0);
+    lua.set_field(-2, "buf_get_changedtick")?;
+
+    lua.push_cclosure(lua_buf_is_modified, 0);
+    lua.set_field(-2, "buf_is_modified")?;
+
+    lua.push_cclosure(lua_buf_get_name, 0);
+    lua.set_field(-2, "buf_get_name")?;
+
+    lua.push_cclosure(lua_buf_set_name, 0);
+    lua.set_field(-2, "buf_set_name")?;
+
+    lua.push_cclosure(lua_buf_get_var, 0);
+    lua.set_field(-2, "buf_get_var")?;
+
+    lua.push_cclosure(lua_buf_set_var, 0);
+    lua.set_field(-2, "buf_set_var")?;
+
+    lua.push_cclosure(lua_get_current_line, 0);
+    lua.set_field(-2, "get_current_line")?;
+
+    lua.push_cclosure(lua_get_visual_selection, 0);
+    lua.set_field(-2, "get_visual_selection")?;
+
+    lua.push_cclosure(lua_set_current_line, 0);
+    lua.set_field(-2, "set_current_line")?;
+
+    lua.push_cclosure(lua_buf_attach, 0);
+    lua.set_field(-2, "buf_attach")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn reports_buffer_validity_as_a_boolean() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_buffer_functions(&lua).unwrap();
+        let buf_is_valid = mock::get(-1).field("buf_is_valid");
+
+        mock::set_result("return vim.api.nvim_buf_is_valid(3)", true.into());
+        let results = mock::call(&buf_is_valid, [3.0.into()]);
+        assert!(matches!(results[..], [mock::MockValue::Bool(true)]));
+
+        mock::set_result("return vim.api.nvim_buf_is_valid(9)", false.into());
+        let results = mock::call(&buf_is_valid, [9.0.into()]);
+        assert!(matches!(results[..], [mock::MockValue::Bool(false)]));
+    }
+
+    #[test]
+    fn sets_the_current_buffer() {
+        mock::lua();
+        assert_eq!(nvim_set_current_buf(2), 1);
+        assert_eq!(nvim_set_current_buf(-1), 0);
+        assert_eq!(mock::commands(), ["lua vim.api.nvim_set_current_buf(2)"]);
+    }
+
+    #[test]
+    fn sets_buffer_local_options() {
+        mock::lua();
+        buf_set_option(0, "shiftwidth", &Value::Number(2.0)).unwrap();
+        assert!(buf_set_option(0, "shiftwidth", &Value::Nil).is_err());
+        assert!(buf_set_option(-1, "shiftwidth", &Value::Number(2.0)).is_err());
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.api.nvim_set_option_value('shiftwidth', 2, { buf = 0 })"]
+        );
+    }
+
+    #[test]
+    fn attaches_with_an_optionally_scheduled_callback() {
+        assert_eq!(
+            buf_attach_expr(0, "require('x').on_lines", true).unwrap(),
+            "vim.api.nvim_buf_attach(0, false, { on_lines = \
+             vim.schedule_wrap(function(...) return require('x').on_lines(...) end) })"
+        );
+        assert!(buf_attach_expr(-1, "f", false).is_err());
+        assert!(buf_attach_expr(0, "f()", false).is_err());
+    }
+
+    #[test]
+    fn gets_and_sets_buffer_names() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_buffer_functions(&lua).unwrap();
+        let buf_get_name = mock::get(-1).field("buf_get_name");
+
+        mock::set_result(
+            "return vim.api.nvim_buf_get_name(0)",
+            mock::string("/tmp/notes.md"),
+        );
+        let results = mock::call(&buf_get_name, [0.0.into()]);
+        assert_eq!(results[0].as_str(), Some("/tmp/notes.md"));
+        assert!(mock::call(&buf_get_name, [(-1.0).into()]).is_empty());
+
+        let name = CString::new("it's a \"draft\".txt").unwrap();
+        assert_eq!(nvim_buf_set_name(0, name.as_ptr()), 1);
+        assert_eq!(
+            mock::commands(),
+            [r#"lua vim.api.nvim_buf_set_name(0, 'it\'s a "draft".txt')"#]
+        );
+    }
+
+    #[test]
+    fn gets_and_sets_the_current_line() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_buffer_functions(&lua).unwrap();
+        let get_current_line = mock::get(-1).field("get_current_line");
+
+        mock::set_result(
+            "return vim.api.nvim_get_current_line()",
+            mock::string("local x = 1"),
+        );
+        let results = mock::call(&get_current_line, []);
+        assert_eq!(results[0].as_str(), Some("local x = 1"));
+
+        set_current_line("it's").unwrap();
+        assert!(set_current_line("a\nb").is_err());
+        assert_eq!(
+            mock::commands(),
+            [r"lua vim.api.nvim_set_current_line('it\'s')"]
+        );
+    }
+
+    #[test]
+    fn counts_buffer_lines_as_an_integer() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_buffer_functions(&lua).unwrap();
+        let buf_line_count = mock::get(-1).field("buf_line_count");
+
+        assert_eq!(
+            buf_line_count_expr(0).unwrap(),
+            "vim.api.nvim_buf_line_count(0)"
+        );
+        assert!(buf_line_count_expr(-1).is_err());
+
+        mock::set_result("return vim.api.nvim_buf_line_count(3)", 42.0.into());
+        let results = mock::call(&buf_line_count, [3.0.into()]);
+        assert!(matches!(results[..], [mock::MockValue::Number(n)] if n == 42.0));
+        assert!(mock::call(&buf_line_count, [(-1.0).into()]).is_empty());
+    }
+
+    #[test]
+    fn sets_buffer_variables_keeping_their_type() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_buffer_functions(&lua).unwrap();
+        let buf_set_var = mock::get(-1).field("buf_set_var");
+
+        let name = CString::new("rns_count").unwrap();
+        for json in ["3", r#""three""#, "true", "[1, 2]", r#"{"a": 1}"#, "null"] {
+            let json = CString::new(json).unwrap();
+            nvim_buf_set_var(2, name.as_ptr(), json.as_ptr());
+        }
+        mock::call(&buf_set_var, [0.0.into(), "rns_flag".into(), false.into()]);
+        mock::call(
+            &buf_set_var,
+            [0.0.into(), "rns_flag".into(), mock::MockValue::Nil],
+        );
+
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.api.nvim_buf_set_var(2, 'rns_count', 3)",
+                "lua vim.api.nvim_buf_set_var(2, 'rns_count', 'three')",
+                "lua vim.api.nvim_buf_set_var(2, 'rns_count', true)",
+                "lua vim.api.nvim_buf_set_var(2, 'rns_count', { 1, 2 })",
+                "lua vim.api.nvim_buf_set_var(2, 'rns_count', { ['a'] = 1 })",
+                "lua vim.api.nvim_buf_set_var(0, 'rns_flag', false)",
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_buffer_variable_lookups() {
+        assert_eq!(
+            buf_get_var_expr(2, "did_ftplugin").unwrap(),
+            "vim.b[2]['did_ftplugin']"
+        );
+        assert!(buf_get_var_expr(-1, "did_ftplugin").is_err());
+        assert!(buf_get_var_expr(0, "not valid").is_err());
+    }
+
+    #[test]
+    fn builds_changedtick_lookups() {
+        assert_eq!(
+            buf_get_changedtick_expr(0).unwrap(),
+            "vim.api.nvim_buf_get_changedtick(0)"
+        );
+        assert_eq!(
+            buf_get_changedtick_expr(4).unwrap(),
+            "vim.api.nvim_buf_get_changedtick(4)"
+        );
+        assert!(buf_get_changedtick_expr(-1).is_err());
+    }
+
+    #[test]
+    fn visual_selection_handles_charwise_and_linewise_modes() {
+        let expr = VISUAL_SELECTION_EXPR;
+        let linewise = expr.find("if mode == 'V' then\n        return table.concat(lines, '\\n')");
+        let charwise =
+            expr.find("lines[#lines] = last:sub(1, ecol)\n    lines[1] = lines[1]:sub(scol)");
+        assert!(linewise.is_some() && linewise < charwise, "{}", expr);
+
+        // A selection made backwards is normalized before lines are read
+        assert!(expr.contains("srow, scol, erow, ecol = erow, ecol, srow, scol"));
+
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_buffer_functions(&lua).unwrap();
+        let get_visual_selection = mock::get(-1).field("get_visual_selection");
+
+        mock::set_result(&format!("return {expr}"), "llo wor".into());
+        let results = mock::call(&get_visual_selection, []);
+        assert_eq!(results[0].as_str(), Some("llo wor"));
+    }
+
+    #[test]
+    fn builds_modified_checks() {
+        assert_eq!(buf_is_modified_expr(0).unwrap(), "vim.bo[0].modified");
+        assert_eq!(buf_is_modified_expr(3).unwrap(), "vim.bo[3].modified");
+        assert!(buf_is_modified_expr(-1).is_err());
+    }
+}
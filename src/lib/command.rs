@@ -0,0 +1,352 @@
+use std::os::raw::{c_char, c_int};
+
+use crate::keymap::parse_flag;
+use crate::{
+    extract_c_string, fn_ref, lua_quote, validate_identifier, Error, Lua, LuaState, Result,
+};
+
+/// How a user command completes its arguments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Complete {
+    /// A built-in completion type such as `file` or `buffer`
+    Builtin(String),
+    /// Path to a Lua function returning the list of candidates
+    Function(String),
+}
+
+/// Options understood by the user command emitter
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOpts {
+    pub nargs: Option<String>,
+    pub bang: Option<bool>,
+    pub bar: Option<bool>,
+    /// `true` for the current line, or a default range such as `%` or a count
+    pub range: Option<String>,
+    pub complete: Option<Complete>,
+    pub desc: Option<String>,
+}
+
+impl CommandOpts {
+    /// Parses options from a packed string such as `nargs=*,bang,desc=Run it`
+    ///
+    /// Entries follow the keymap options format: a bare flag means `true` and
+    /// `desc=` consumes the remainder of the string. Function completion is
+    /// written `complete=customlist,<fn>`, where the entry after `customlist`
+    /// is the Lua function path.
+    pub fn parse(opts: &str) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut rest = opts.trim();
+
+        while !rest.is_empty() {
+            if let Some(desc) = rest.strip_prefix("desc=") {
+                parsed.desc = Some(desc.to_string());
+                break;
+            }
+
+            let (entry, tail) = rest.split_once(',').unwrap_or((rest, ""));
+            rest = tail.trim_start();
+
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim())),
+                None => (entry.trim(), None),
+            };
+
+            match (key, value) {
+                ("", None) => {}
+                ("nargs", Some(nargs @ ("0" | "1" | "*" | "?" | "+"))) => {
+                    parsed.nargs = Some(nargs.to_string());
+                }
+                ("bang", value) => parsed.bang = Some(value.map_or(Ok(true), parse_flag)?),
+                ("bar", value) => parsed.bar = Some(value.map_or(Ok(true), parse_flag)?),
+                ("range", None) => parsed.range = Some("true".to_string()),
+                ("range", Some(range)) if range == "%" || range.parse::<u32>().is_ok() => {
+                    parsed.range = Some(range.to_string());
+                }
+                ("complete", Some("customlist")) => {
+                    let (path, tail) = rest.split_once(',').unwrap_or((rest, ""));
+                    rest = tail.trim_start();
+                    parsed.complete = Some(Complete::Function(path.trim().to_string()));
+                }
+                ("complete", Some(kind)) => {
+                    validate_identifier(kind)?;
+                    parsed.complete = Some(Complete::Builtin(kind.to_string()));
+                }
+                _ => return Err(Error::InvalidArgument),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Parses a packed options string that may be null, meaning no options
+    pub(crate) fn from_ptr(opts: *const c_char) -> Result<Self> {
+        if opts.is_null() {
+            Ok(Self::default())
+        } else {
+            extract_c_string(opts).and_then(|s| Self::parse(&s))
+        }
+    }
+
+    /// Renders the options as a Lua table literal for `nvim_create_user_command`
+    fn to_lua(&self) -> Result<String> {
+        let mut fields = Vec::new();
+
+        if let Some(nargs) = &self.nargs {
+            fields.push(format!("nargs = {}", lua_quote(nargs)));
+        }
+
+        if let Some(bang) = self.bang {
+            fields.push(format!("bang = {bang}"));
+        }
+
+        if let Some(bar) = self.bar {
+            fields.push(format!("bar = {bar}"));
+        }
+
+        match self.range.as_deref() {
+            Some("%") => fields.push("range = '%'".to_string()),
+            Some(range) => fields.push(format!("range = {range}")),
+            None => {}
+        }
+
+        match &self.complete {
+            Some(Complete::Builtin(kind)) => fields.push(format!("complete = {}", lua_quote(kind))),
+            Some(Complete::Function(path)) => fields.push(format!("complete = {}", fn_ref(path)?)),
+            None => {}
+        }
+
+        if let Some(desc) = &self.desc {
+            fields.push(format!("desc = {}", lua_quote(desc)));
+        }
+
+        if fields.is_empty() {
+            Ok("{}".to_string())
+        } else {
+            Ok(format!("{{ {} }}", fields.join(", ")))
+        }
+    }
+}
+
+/// Checks that `name` is usable as a user command name
+///
+/// User commands start with an uppercase letter followed by letters and digits.
+fn validate_command_name(name: &str) -> Result<()> {
+    let valid = name.starts_with(|c: char| c.is_ascii_uppercase())
+        && name.chars().all(|c| c.is_ascii_alphanumeric());
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgument)
+    }
+}
+
+/// What a user command runs when invoked
+#[derive(Debug, Clone)]
+pub enum CommandBody {
+    /// An Ex command string
+    Command(String),
+    /// Path to a Lua function receiving the command's opts table
+    Function(String),
+}
+
+impl CommandBody {
+    /// Renders the body as a Lua expression for `nvim_create_user_command`
+    fn to_lua(&self) -> Result<String> {
+        match self {
+            Self::Command(command) => Ok(lua_quote(command)),
+            Self::Function(path) => fn_ref(path),
+        }
+    }
+}
+
+/// Creates or replaces a user command
+fn create_user_command(name: &str, body: &CommandBody, opts: &CommandOpts) -> Result<()> {
+    validate_command_name(name)?;
+
+    crate::run_lua(&format!(
+        "vim.api.nvim_create_user_command({}, {}, {})",
+        lua_quote(name),
+        body.to_lua()?,
+        opts.to_lua()?
+    ))
+}
+
+/// Extracts the arguments shared by the user command exports and creates the command
+fn create_user_command_ffi(
+    name: *const c_char,
+    body: *const c_char,
+    opts: *const c_char,
+    make_body: fn(String) -> CommandBody,
+) -> c_int {
+    let name = match extract_c_string(name) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let body = match extract_c_string(body) {
+        Ok(s) => make_body(s),
+        Err(_) => return 0,
+    };
+
+    let opts = match CommandOpts::from_ptr(opts) {
+        Ok(opts) => opts,
+        Err(_) => return 0,
+    };
+
+    match create_user_command(&name, &body, &opts) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Creates a user command in Neovim
+///
+/// # Safety
+///
+/// `name` and `command` must be valid null-terminated C strings.
+/// `opts` must be either null or a valid C string in the packed options format.
+#[no_mangle]
+pub extern "C" fn nvim_create_user_command(
+    name: *const c_char,
+    command: *const c_char,
+    opts: *const c_char,
+) -> c_int {
+    create_user_command_ffi(name, command, opts, CommandBody::Command)
+}
+
+/// Creates a user command that calls a Lua function
+///
+/// The function receives the opts table Neovim passes to command callbacks.
+///
+/// # Safety
+///
+/// `name` and `fn_name` must be valid null-terminated C strings.
+/// `opts` must be either null or a valid C string in the packed options format.
+#[no_mangle]
+pub extern "C" fn nvim_create_user_command_fn(
+    name: *const c_char,
+    fn_name: *const c_char,
+    opts: *const c_char,
+) -> c_int {
+    create_user_command_ffi(name, fn_name, opts, CommandBody::Function)
+}
+
+/// Registers Lua bindings for user command functions
+pub fn register_command_functions(lua: &Lua<'_>) -> Result<()> {
+    /// Reads `(name, body, opts)` arguments and creates the command
+    fn create_from_lua(l: *mut LuaState, make_body: fn(String) -> CommandBody) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let body = match lua.check_string(2) {
+            Ok(s) => make_body(s),
+            Err(_) => return 0,
+        };
+
+        let opts = match lua.opt_string(3) {
+            Ok(opts) => opts.unwrap_or_default(),
+            Err(_) => return 0,
+        };
+
+        match CommandOpts::parse(&opts).and_then(|opts| create_user_command(&name, &body, &opts)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_create_user_command(l: *mut LuaState) -> c_int {
+        create_from_lua(l, CommandBody::Command)
+    }
+
+    extern "C" fn lua_create_user_command_fn(l: *mut LuaState) -> c_int {
+        create_from_lua(l, CommandBody::Function)
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_create_user_command, 0);
+    lua.set_field(-2, "create_user_command")?;
+
+    lua.push_cclosure(lua_create_user_command_fn, 0);
+    lua.set_field(-2, "create_user_command_fn")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn parses_packed_options() {
+        let opts =
+            CommandOpts::parse("nargs=*, bang, range=%, complete=file, desc=Run it, now").unwrap();
+        assert_eq!(
+            opts,
+            CommandOpts {
+                nargs: Some("*".to_string()),
+                bang: Some(true),
+                bar: None,
+                range: Some("%".to_string()),
+                complete: Some(Complete::Builtin("file".to_string())),
+                desc: Some("Run it, now".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_function_completion() {
+        let opts = CommandOpts::parse("complete=customlist,require('x').complete,bar=0").unwrap();
+        assert_eq!(
+            opts.complete,
+            Some(Complete::Function("require('x').complete".to_string()))
+        );
+        assert_eq!(opts.bar, Some(false));
+        assert_eq!(
+            opts.to_lua().unwrap(),
+            "{ bar = false, complete = function(...) return require('x').complete(...) end }"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert!(CommandOpts::parse("nargs=2").is_err());
+        assert!(CommandOpts::parse("range=x").is_err());
+        assert!(CommandOpts::parse("complete=a b").is_err());
+        assert!(CommandOpts::parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn validates_command_names() {
+        assert!(validate_command_name("Format2").is_ok());
+        assert!(validate_command_name("format").is_err());
+        assert!(validate_command_name("Bad-Name").is_err());
+        assert!(validate_command_name("").is_err());
+    }
+
+    #[test]
+    fn creates_commands_through_lua() {
+        mock::lua();
+        let opts = CommandOpts::parse("nargs=1").unwrap();
+        create_user_command(
+            "Grep",
+            &CommandBody::Command("grep <args>".to_string()),
+            &opts,
+        )
+        .unwrap();
+
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.api.nvim_create_user_command('Grep', 'grep <args>', { nargs = '1' })"]
+        );
+    }
+}
@@ -0,0 +1,451 @@
+use std::convert::TryFrom;
+use std::os::raw::{c_char, c_int};
+
+use crate::json::Json;
+use crate::{extract_c_string, lua_quote, validate_hl_group, Error, Lua, LuaState, Result};
+
+/// Builds the `nvim_create_namespace` call, evaluating to the namespace id
+///
+/// Creating a namespace that already exists returns its existing id, and an
+/// empty `name` creates an anonymous namespace.
+fn create_namespace_expr(name: &str) -> Result<String> {
+    if name.contains(['\n', '\0']) {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!(
+        "vim.api.nvim_create_namespace({})",
+        lua_quote(name)
+    ))
+}
+
+/// Builds the `nvim_buf_add_highlight` call highlighting part of a line
+///
+/// `line` and the columns are zero-based byte positions, with a `col_end` of
+/// -1 meaning the end of the line. An `ns` of -1 adds the highlight without a
+/// namespace.
+fn buf_add_highlight_expr(
+    buffer: i64,
+    ns: i64,
+    hl_group: &str,
+    line: i64,
+    col_start: i64,
+    col_end: i64,
+) -> Result<String> {
+    validate_hl_group(hl_group)?;
+
+    let valid_end = col_end == -1 || col_end >= col_start;
+    if buffer < 0 || ns < -1 || line < 0 || col_start < 0 || !valid_end {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!(
+        "vim.api.nvim_buf_add_highlight({buffer}, {ns}, {}, {line}, {col_start}, {col_end})",
+        lua_quote(hl_group)
+    ))
+}
+
+/// Renders a `virt_text` list of `[text, hl]` chunks as a Lua table literal
+///
+/// The highlight group of a chunk may be omitted, as in `["text"]`.
+fn virt_text_to_lua(chunks: &[Json]) -> Result<String> {
+    let mut rendered = Vec::with_capacity(chunks.len());
+
+    for chunk in chunks {
+        let valid = match chunk {
+            Json::Array(pair) => matches!(
+                pair.as_slice(),
+                [Json::String(_)] | [Json::String(_), Json::String(_)]
+            ),
+            _ => false,
+        };
+
+        if !valid {
+            return Err(Error::InvalidArgument);
+        }
+        rendered.push(chunk.to_lua());
+    }
+
+    if rendered.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!("{{ {} }}", rendered.join(", ")))
+}
+
+/// Translates the extmark options JSON object into a Lua table literal
+///
+/// Supported keys are `virt_text`, `hl_group`, `sign_text`, and `priority`;
+/// any other key is rejected so typos do not silently drop decorations.
+fn extmark_opts_to_lua(opts_json: &str) -> Result<String> {
+    let members = match Json::parse(opts_json)? {
+        Json::Object(members) => members,
+        _ => return Err(Error::InvalidArgument),
+    };
+
+    let mut fields = Vec::with_capacity(members.len());
+    for (key, value) in &members {
+        let value = match (key.as_str(), value) {
+            ("virt_text", Json::Array(chunks)) => virt_text_to_lua(chunks)?,
+            ("hl_group" | "sign_text", Json::String(s)) if !s.is_empty() => lua_quote(s),
+            ("priority", Json::Number(n)) if n.parse::<u16>().is_ok() => n.clone(),
+            _ => return Err(Error::InvalidArgument),
+        };
+        fields.push(format!("{key} = {value}"));
+    }
+
+    if fields.is_empty() {
+        Ok("{}".to_string())
+    } else {
+        Ok(format!("{{ {} }}", fields.join(", ")))
+    }
+}
+
+/// Builds the `nvim_buf_set_extmark` call, evaluating to the extmark id
+///
+/// `line` and `col` are zero-based, and `opts_json` is a JSON object of
+/// extmark options such as
+/// `{"virt_text": [["2 refs", "Comment"]], "priority": 100}`.
+fn buf_set_extmark_expr(
+    buffer: i64,
+    ns: i64,
+    line: i64,
+    col: i64,
+    opts_json: &str,
+) -> Result<String> {
+    if buffer < 0 || ns < 0 || line < 0 || col < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!(
+        "vim.api.nvim_buf_set_extmark({buffer}, {ns}, {line}, {col}, {})",
+        extmark_opts_to_lua(opts_json)?
+    ))
+}
+
+/// Builds the `nvim_buf_clear_namespace` call for a range of lines
+///
+/// Lines are zero-based and end-exclusive, with a `line_end` of -1 meaning the
+/// end of the buffer. An `ns` of -1 clears every namespace.
+fn buf_clear_namespace_expr(
+    buffer: i64,
+    ns: i64,
+    line_start: i64,
+    line_end: i64,
+) -> Result<String> {
+    let valid_end = line_end == -1 || line_end >= line_start;
+    if buffer < 0 || ns < -1 || line_start < 0 || !valid_end {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!(
+        "vim.api.nvim_buf_clear_namespace({buffer}, {ns}, {line_start}, {line_end})"
+    ))
+}
+
+/// Creates a namespace, returning its id or 0 on failure
+///
+/// # Safety
+///
+/// `name` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_create_namespace(name: *const c_char) -> c_int {
+    extract_c_string(name)
+        .and_then(|name| create_namespace_expr(&name))
+        .and_then(|expr| crate::eval_integer(&expr))
+        .and_then(|id| c_int::try_from(id).map_err(|_| Error::InvalidArgument))
+        .unwrap_or(0)
+}
+
+/// Places an extmark, returning its id or 0 on failure
+///
+/// # Safety
+///
+/// `opts_json` must be a valid null-terminated C string containing a JSON object.
+#[no_mangle]
+pub extern "C" fn nvim_buf_set_extmark(
+    buffer: c_int,
+    ns: c_int,
+    line: c_int,
+    col: c_int,
+    opts_json: *const c_char,
+) -> c_int {
+    extract_c_string(opts_json)
+        .and_then(|opts| {
+            buf_set_extmark_expr(buffer.into(), ns.into(), line.into(), col.into(), &opts)
+        })
+        .and_then(|expr| crate::eval_integer(&expr))
+        .and_then(|id| c_int::try_from(id).map_err(|_| Error::InvalidArgument))
+        .unwrap_or(0)
+}
+
+/// Highlights a range of a line with the given highlight group
+///
+/// # Safety
+///
+/// `hl_group` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_buf_add_highlight(
+    buffer: c_int,
+    ns: c_int,
+    hl_group: *const c_char,
+    line: c_int,
+    col_start: c_int,
+    col_end: c_int,
+) -> c_int {
+    match extract_c_string(hl_group)
+        .and_then(|hl_group| {
+            buf_add_highlight_expr(
+                buffer.into(),
+                ns.into(),
+                &hl_group,
+                line.into(),
+                col_start.into(),
+                col_end.into(),
+            )
+        })
+        .and_then(|code| crate::run_lua(&code))
+    {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Clears the extmarks and highlights of a namespace from a range of lines
+#[no_mangle]
+pub extern "C" fn nvim_buf_clear_namespace(
+    buffer: c_int,
+    ns: c_int,
+    line_start: c_int,
+    line_end: c_int,
+) -> c_int {
+    match buf_clear_namespace_expr(buffer.into(), ns.into(), line_start.into(), line_end.into())
+        .and_then(|code| crate::run_lua(&code))
+    {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Registers Lua bindings for namespace and extmark functions
+pub fn register_extmark_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_create_namespace(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        // Return the namespace id
+        match lua
+            .check_string(1)
+            .and_then(|name| create_namespace_expr(&name))
+            .and_then(|expr| lua.eval(&expr))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_clear_namespace(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let ns = match lua.check_integer(2) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let line_start = match lua.check_integer(3) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let line_end = match lua.check_integer(4) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match buf_clear_namespace_expr(buffer, ns, line_start, line_end)
+            .and_then(|code| crate::run_lua(&code))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_set_extmark(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let ns = match lua.check_integer(2) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let line = match lua.check_integer(3) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let col = match lua.check_integer(4) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let opts_json = match lua.opt_string(5) {
+            Ok(s) => s.unwrap_or_else(|| "{}".to_string()),
+            Err(_) => return 0,
+        };
+
+        // Return the extmark id
+        match buf_set_extmark_expr(buffer, ns, line, col, &opts_json)
+            .and_then(|expr| lua.eval(&expr))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_buf_add_highlight(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let ns = match lua.check_integer(2) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let hl_group = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let line = match lua.check_integer(4) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let col_start = match lua.check_integer(5) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let col_end = match lua.check_integer(6) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match buf_add_highlight_expr(buffer, ns, &hl_group, line, col_start, col_end)
+            .and_then(|code| crate::run_lua(&code))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_create_namespace, 0);
+    lua.set_field(-2, "create_namespace")?;
+
+    lua.push_cclosure(lua_buf_clear_namespace, 0);
+    lua.set_field(-2, "buf_clear_namespace")?;
+
+    lua.push_cclosure(lua_buf_set_extmark, 0);
+    lua.set_field(-2, "buf_set_extmark")?;
+
+    lua.push_cclosure(lua_buf_add_highlight, 0);
+    lua.set_field(-2, "buf_add_highlight")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_clear_namespace_calls() {
+        assert_eq!(
+            buf_clear_namespace_expr(0, -1, 0, -1).unwrap(),
+            "vim.api.nvim_buf_clear_namespace(0, -1, 0, -1)"
+        );
+        assert!(buf_clear_namespace_expr(0, 1, 5, 2).is_err());
+    }
+
+    #[test]
+    fn builds_namespace_calls() {
+        assert_eq!(
+            create_namespace_expr("rns").unwrap(),
+            "vim.api.nvim_create_namespace('rns')"
+        );
+        assert!(create_namespace_expr("a\nb").is_err());
+    }
+
+    #[test]
+    fn translates_extmark_options() {
+        assert_eq!(
+            extmark_opts_to_lua(
+                r#"{"virt_text": [["2 refs", "Comment"], ["!"]], "priority": 100}"#
+            )
+            .unwrap(),
+            "{ virt_text = { { '2 refs', 'Comment' }, { '!' } }, priority = 100 }"
+        );
+        assert_eq!(extmark_opts_to_lua("{}").unwrap(), "{}");
+    }
+
+    #[test]
+    fn rejects_unknown_or_malformed_options() {
+        for opts in [
+            "[]",
+            r#"{"virt_txt": []}"#,
+            r#"{"virt_text": []}"#,
+            r#"{"virt_text": [["a", "b", "c"]]}"#,
+            r#"{"hl_group": ""}"#,
+            r#"{"priority": 70000}"#,
+            r#"{"priority": -1}"#,
+        ] {
+            assert!(extmark_opts_to_lua(opts).is_err(), "accepted {}", opts);
+        }
+    }
+
+    #[test]
+    fn builds_extmark_calls() {
+        assert_eq!(
+            buf_set_extmark_expr(0, 3, 4, 0, r#"{"hl_group": "Search"}"#).unwrap(),
+            "vim.api.nvim_buf_set_extmark(0, 3, 4, 0, { hl_group = 'Search' })"
+        );
+        assert!(buf_set_extmark_expr(0, -1, 0, 0, "{}").is_err());
+    }
+
+    #[test]
+    fn builds_highlight_calls() {
+        assert_eq!(
+            buf_add_highlight_expr(1, -1, "@comment.lua", 0, 2, -1).unwrap(),
+            "vim.api.nvim_buf_add_highlight(1, -1, '@comment.lua', 0, 2, -1)"
+        );
+        assert!(buf_add_highlight_expr(1, 0, "Search", 0, 4, 2).is_err());
+        assert!(buf_add_highlight_expr(1, 0, "no good", 0, 0, -1).is_err());
+    }
+}
@@ -1,7 +1,64 @@
+use std::cell::RefCell;
+use std::convert::TryFrom;
 use std::ffi::CString;
 use std::os::raw::{c_char, c_int};
 
-use crate::{extract_c_string, Lua, LuaState, Result};
+use crate::json::Json;
+use crate::keymap::{emit_keymap, KeymapOpts, KeymapSpec, Rhs};
+use crate::{
+    extract_c_string, lua_quote, validate_hl_group, validate_identifier, Error, Lua, LuaState,
+    Result, Value,
+};
+
+thread_local! {
+    // Augroup applied to autocmds created inside an `rns.augroup` callback
+    static CURRENT_AUGROUP: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // Error raised by the most recent `rns.eval`, cleared when one succeeds
+    static LAST_EVAL_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Returns the augroup currently being populated by `rns.augroup`, if any
+pub(crate) fn current_augroup() -> Option<String> {
+    CURRENT_AUGROUP.with(|group| group.borrow().clone())
+}
+
+/// Leaves any active `rns.augroup` scope and forgets the last eval error, for `rns.reset`
+pub(crate) fn reset() {
+    CURRENT_AUGROUP.with(|group| group.borrow_mut().take());
+    LAST_EVAL_ERROR.with(|err| err.borrow_mut().take());
+}
+
+/// Builds an `:autocmd` command, placing it in the current augroup if one is active
+pub(crate) fn autocmd_cmd(event: &str, pattern: &str, command: &str) -> String {
+    match current_augroup() {
+        Some(group) => format!("autocmd {group} {event} {pattern} {command}"),
+        None => format!("autocmd {event} {pattern} {command}"),
+    }
+}
+
+/// Renders the `group = '...'` field for `nvim_create_autocmd`
+///
+/// An explicit group takes precedence over the one set by `rns.augroup`.
+fn autocmd_group_field(group: *const c_char) -> String {
+    let group = if group.is_null() {
+        current_augroup()
+    } else {
+        extract_c_string(group).ok()
+    };
+
+    match group {
+        Some(group_str) => format!(", group = {} ", lua_quote(&group_str)),
+        None => String::new(),
+    }
+}
+
+/// Extracts an option name, checking that the option exists in strict mode
+fn option_name(name: *const c_char) -> Result<String> {
+    let name = extract_c_string(name)?;
+    crate::check_option(None, &name)?;
+    Ok(name)
+}
 
 /// Sets a boolean Neovim option
 ///
@@ -10,8 +67,12 @@ use crate::{extract_c_string, Lua, LuaState, Result};
 /// `name` must be a valid null-terminated C string pointing to a valid option name.
 #[no_mangle]
 pub extern "C" fn nvim_set_option_bool(name: *const c_char, value: c_int) -> c_int {
-    match extract_c_string(name) {
+    match option_name(name) {
         Ok(name_str) => {
+            if crate::option_unchanged(None, &name_str, &(value != 0).to_string()) {
+                return 1;
+            }
+
             let cmd = if value != 0 {
                 format!("set {name_str}")
             } else {
@@ -34,8 +95,12 @@ pub extern "C" fn nvim_set_option_bool(name: *const c_char, value: c_int) -> c_i
 /// `name` must be a valid null-terminated C string pointing to a valid option name.
 #[no_mangle]
 pub extern "C" fn nvim_set_option_int(name: *const c_char, value: c_int) -> c_int {
-    match extract_c_string(name) {
+    match option_name(name) {
         Ok(name_str) => {
+            if crate::option_unchanged(None, &name_str, &value.to_string()) {
+                return 1;
+            }
+
             let cmd = format!("set {name_str}={value}");
             match crate::run_cmd(&cmd) {
                 Ok(()) => 1,
@@ -53,9 +118,13 @@ pub extern "C" fn nvim_set_option_int(name: *const c_char, value: c_int) -> c_in
 /// `name` and `value` must be valid null-terminated C strings.
 #[no_mangle]
 pub extern "C" fn nvim_set_option_string(name: *const c_char, value: *const c_char) -> c_int {
-    match extract_c_string(name) {
+    match option_name(name) {
         Ok(name_str) => match extract_c_string(value) {
             Ok(value_str) => {
+                if crate::option_unchanged(None, &name_str, &lua_quote(&value_str)) {
+                    return 1;
+                }
+
                 let cmd = format!("set {name_str}={value_str}");
                 match crate::run_cmd(&cmd) {
                     Ok(()) => 1,
@@ -68,6 +137,166 @@ pub extern "C" fn nvim_set_option_string(name: *const c_char, value: *const c_ch
     }
 }
 
+/// Renders a JSON object as a `key:val,key:val` option value, as `listchars` takes
+///
+/// Keys must be identifiers and values strings or numbers. Values cannot
+/// contain commas, since those separate the pairs.
+fn option_pairs_value(pairs_json: &str) -> Result<String> {
+    let members = match Json::parse(pairs_json)? {
+        Json::Object(members) if !members.is_empty() => members,
+        _ => return Err(Error::InvalidArgument),
+    };
+
+    let mut pairs = Vec::with_capacity(members.len());
+    for (key, value) in members {
+        validate_identifier(&key)?;
+
+        let value = match value {
+            Json::String(s) if !s.is_empty() && !s.contains(',') => s,
+            Json::Number(n) => n,
+            _ => return Err(Error::InvalidArgument),
+        };
+        pairs.push(format!("{key}:{value}"));
+    }
+
+    Ok(pairs.join(","))
+}
+
+/// Sets option `name` to the pairs of a JSON object, replacing its previous value
+fn set_option_multi(lua: Option<&Lua<'_>>, name: &str, pairs_json: &str) -> Result<()> {
+    validate_identifier(name)?;
+    crate::check_option(lua, name)?;
+
+    let value = lua_quote(&option_pairs_value(pairs_json)?);
+    if crate::option_unchanged(lua, name, &value) {
+        return Ok(());
+    }
+
+    crate::run_lua(&format!("vim.o[{}] = {value}", lua_quote(name)))
+}
+
+/// Sets a `key:val` list option such as `listchars` from a JSON object
+///
+/// `{"tab": "» ", "trail": "·"}` sets the option to `tab:» ,trail:·`.
+///
+/// # Safety
+///
+/// `name` and `pairs_json` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_set_option_multi(name: *const c_char, pairs_json: *const c_char) -> c_int {
+    let name = match extract_c_string(name) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let pairs_json = match extract_c_string(pairs_json) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    match set_option_multi(None, &name, &pairs_json) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Sets both the global and the current local value of option `name`
+///
+/// For global-local options such as `signcolumn`, setting only the global
+/// value leaves existing windows and buffers untouched.
+fn set_option_global_local(lua: Option<&Lua<'_>>, name: &str, value: &Value) -> Result<()> {
+    validate_identifier(name)?;
+    crate::check_option(lua, name)?;
+    if *value == Value::Nil {
+        return Err(Error::InvalidArgument);
+    }
+
+    let (name, value) = (lua_quote(name), value.to_lua());
+    crate::run_lua(&format!(
+        "vim.api.nvim_set_option_value({name}, {value}, {{ scope = 'global' }})\n\
+         vim.api.nvim_set_option_value({name}, {value}, {{ scope = 'local' }})"
+    ))
+}
+
+/// Sets every `name = value` pair of the table at `idx` in a single Lua chunk
+///
+/// All pairs are validated before anything is recorded or run, so a bad
+/// entry leaves every option, and any open transaction, untouched. Options
+/// already at the requested value are left out of the chunk.
+fn set_options(lua: &Lua<'_>, idx: c_int) -> Result<()> {
+    let mut pairs = Vec::new();
+
+    lua.for_each_pair(idx, |lua| {
+        let name = lua.check_string(-2)?;
+        validate_identifier(&name)?;
+        crate::check_option(Some(lua), &name)?;
+
+        let value = lua.check_value(-1)?;
+        if value == Value::Nil {
+            return Err(Error::InvalidArgument);
+        }
+
+        if !crate::option_unchanged(Some(lua), &name, &value.to_lua()) {
+            pairs.push((name, value));
+        }
+        Ok(())
+    })?;
+
+    if pairs.is_empty() {
+        return Ok(());
+    }
+
+    for (name, _) in &pairs {
+        let option = format!("vim.o[{}]", lua_quote(name));
+        crate::transaction::record(lua, &option, |prior| format!("{option} = {prior}"))?;
+    }
+
+    let statements = pairs
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "vim.api.nvim_set_option_value({}, {}, {{}})",
+                lua_quote(name),
+                value.to_lua()
+            )
+        })
+        .collect::<Vec<_>>();
+    crate::run_lua(&statements.join("\n"))
+}
+
+/// Sets a global-local option both globally and for the current window and buffer
+///
+/// `value_json` is a JSON string, number, or boolean, matching the option's type.
+///
+/// # Safety
+///
+/// `name` and `value_json` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_set_option_global_local(
+    name: *const c_char,
+    value_json: *const c_char,
+) -> c_int {
+    let name = match extract_c_string(name) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let value = match extract_c_string(value_json).and_then(|json| Json::parse(&json)) {
+        Ok(Json::Bool(b)) => Value::Bool(b),
+        Ok(Json::Number(n)) => match n.parse() {
+            Ok(n) => Value::Number(n),
+            Err(_) => return 0,
+        },
+        Ok(Json::String(s)) => Value::String(s),
+        _ => return 0,
+    };
+
+    match set_option_global_local(None, &name, &value) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
 /// Sets a Neovim global variable
 ///
 /// # Safety
@@ -75,73 +304,80 @@ pub extern "C" fn nvim_set_option_string(name: *const c_char, value: *const c_ch
 /// `name` and `value` must be valid null-terminated C strings.
 #[no_mangle]
 pub extern "C" fn nvim_set_global(name: *const c_char, value: *const c_char) -> c_int {
-    match extract_c_string(name) {
-        Ok(name_str) => match extract_c_string(value) {
-            Ok(value_str) => {
-                let cmd = format!("let g:{}=\"{}\"", name_str, value_str.replace('"', "\\\""));
-                match crate::run_cmd(&cmd) {
-                    Ok(()) => 1,
-                    Err(_) => 0,
-                }
-            }
+    match (extract_c_string(name), extract_c_string(value)) {
+        (Ok(name_str), Ok(value_str)) => match set_var("g", &name_str, &lua_quote(&value_str)) {
+            Ok(()) => 1,
             Err(_) => 0,
         },
-        Err(_) => 0,
+        _ => 0,
     }
 }
 
-/// Creates a keymap in Neovim
+/// Sets a variable in scope `g`, `b`, `w`, or `t` to a JSON value
+///
+/// Numbers, strings, booleans, arrays, and objects keep their type; `null`
+/// is rejected, since a variable cannot hold `nil`. Use `nvim_del_var` to
+/// unset a variable instead.
 ///
 /// # Safety
 ///
-/// `mode`, `lhs`, `rhs` must be valid null-terminated C strings.
-/// `_opts` is currently unused but must be either null or a valid C string.
+/// `scope`, `name`, and `value_json` must be valid null-terminated C strings.
 #[no_mangle]
-pub extern "C" fn nvim_create_keymap(
-    mode: *const c_char,
-    lhs: *const c_char,
-    rhs: *const c_char,
-    _opts: *const c_char,
+pub extern "C" fn nvim_set_var(
+    scope: *const c_char,
+    name: *const c_char,
+    value_json: *const c_char,
 ) -> c_int {
-    match extract_c_string(mode) {
-        Ok(mode_str) => match (extract_c_string(lhs), extract_c_string(rhs)) {
-            (Ok(lhs_str), Ok(rhs_str)) => {
-                let cmd = format!("{mode_str}map {lhs_str} {rhs_str}");
-                match crate::run_cmd(&cmd) {
-                    Ok(()) => 1,
-                    Err(_) => 0,
-                }
-            }
-            _ => 0,
-        },
+    let (scope, name) = match (extract_c_string(scope), extract_c_string(name)) {
+        (Ok(scope), Ok(name)) => (scope, name),
+        _ => return 0,
+    };
+
+    let value = match extract_c_string(value_json).and_then(|json| Json::parse(&json)) {
+        Ok(Json::Null) | Err(_) => return 0,
+        Ok(value) => value,
+    };
+
+    match set_var(&scope, &name, &value.to_lua()) {
+        Ok(()) => 1,
         Err(_) => 0,
     }
 }
 
-/// Creates a user command in Neovim
+/// Creates a keymap in Neovim
 ///
 /// # Safety
 ///
-/// `name` and `command` must be valid null-terminated C strings.
-/// `_opts` is currently unused but must be either null or a valid C string.
+/// `mode`, `lhs`, `rhs` must be valid null-terminated C strings.
+/// `opts` must be either null or a valid C string in the packed options format.
 #[no_mangle]
-pub extern "C" fn nvim_create_user_command(
-    name: *const c_char,
-    command: *const c_char,
-    _opts: *const c_char,
+pub extern "C" fn nvim_create_keymap(
+    mode: *const c_char,
+    lhs: *const c_char,
+    rhs: *const c_char,
+    opts: *const c_char,
 ) -> c_int {
-    match extract_c_string(name) {
-        Ok(name_str) => match extract_c_string(command) {
-            Ok(cmd_str) => {
-                let cmd = format!("command! {name_str} {cmd_str}");
-                match crate::run_cmd(&cmd) {
-                    Ok(()) => 1,
-                    Err(_) => 0,
-                }
+    match (
+        extract_c_string(mode),
+        extract_c_string(lhs),
+        extract_c_string(rhs),
+        KeymapOpts::from_ptr(opts),
+    ) {
+        (Ok(mode), Ok(lhs), Ok(rhs), Ok(opts)) => {
+            let spec = KeymapSpec {
+                mode,
+                lhs,
+                rhs: Rhs::Command(rhs),
+                opts,
+                buffer: None,
+            };
+
+            match emit_keymap(&spec) {
+                Ok(()) => 1,
+                Err(_) => 0,
             }
-            Err(_) => 0,
-        },
-        Err(_) => 0,
+        }
+        _ => 0,
     }
 }
 
@@ -150,7 +386,8 @@ pub extern "C" fn nvim_create_user_command(
 /// # Safety
 ///
 /// `event`, `pattern`, and `command` must be valid null-terminated C strings.
-/// `group` must be either null or a valid null-terminated C string.
+/// `group` must be either null or a valid null-terminated C string. A null
+/// `group` falls back to the group being populated by `rns.augroup`, if any.
 #[no_mangle]
 pub extern "C" fn nvim_create_autocmd(
     event: *const c_char,
@@ -166,17 +403,10 @@ pub extern "C" fn nvim_create_autocmd(
                     event_str,
                     pattern_str,
                     cmd_str,
-                    if group.is_null() {
-                        String::new()
-                    } else {
-                        match extract_c_string(group) {
-                            Ok(group_str) => format!(", group = '{group_str}' "),
-                            Err(_) => String::new(),
-                        }
-                    }
+                    autocmd_group_field(group)
                 );
 
-                match crate::run_cmd(&format!("lua {lua_cmd}")) {
+                match crate::run_lua(&lua_cmd) {
                     Ok(()) => 1,
                     Err(_) => 0,
                 }
@@ -187,6 +417,73 @@ pub extern "C" fn nvim_create_autocmd(
     }
 }
 
+/// Builds the `nvim_create_autocmd` call running the Lua function `fn_name`
+///
+/// `event` may list several events separated by commas, and an empty
+/// `pattern` matches everything. The autocmd is placed in the group being
+/// populated by `rns.augroup`, if any.
+fn autocmd_callback_expr(
+    event: &str,
+    pattern: &str,
+    fn_name: &str,
+    schedule: bool,
+) -> Result<String> {
+    let events = event
+        .split(',')
+        .map(|event| validate_identifier(event).map(|()| lua_quote(event)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut fields = vec![format!(
+        "callback = {}",
+        crate::callback_ref(fn_name, schedule)?
+    )];
+    if !pattern.is_empty() {
+        fields.push(format!("pattern = {}", lua_quote(pattern)));
+    }
+
+    if let Some(group) = current_augroup() {
+        fields.push(format!("group = {}", lua_quote(&group)));
+    }
+
+    Ok(format!(
+        "vim.api.nvim_create_autocmd({{ {} }}, {{ {} }})",
+        events.join(", "),
+        fields.join(", ")
+    ))
+}
+
+/// Creates an autocommand that calls a Lua function
+///
+/// With `schedule` non-zero the function is wrapped in `vim.schedule_wrap`,
+/// for events that fire in contexts where most of the API is unavailable.
+///
+/// # Safety
+///
+/// `event`, `pattern`, and `fn_name` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_set_autocmd_callback(
+    event: *const c_char,
+    pattern: *const c_char,
+    fn_name: *const c_char,
+    schedule: c_int,
+) -> c_int {
+    match (
+        extract_c_string(event),
+        extract_c_string(pattern),
+        extract_c_string(fn_name),
+    ) {
+        (Ok(event), Ok(pattern), Ok(fn_name)) => {
+            match autocmd_callback_expr(&event, &pattern, &fn_name, schedule != 0)
+                .and_then(|code| crate::run_lua(&code))
+            {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
 /// Creates an autocommand group in Neovim
 ///
 /// # Safety
@@ -197,12 +494,12 @@ pub extern "C" fn nvim_create_augroup(name: *const c_char, clear: c_int) -> c_in
     match extract_c_string(name) {
         Ok(name_str) => {
             let lua_cmd = format!(
-                "vim.api.nvim_create_augroup('{}', {{ clear = {} }})",
-                name_str,
+                "vim.api.nvim_create_augroup({}, {{ clear = {} }})",
+                lua_quote(&name_str),
                 if clear != 0 { "true" } else { "false" }
             );
 
-            match crate::run_cmd(&format!("lua {lua_cmd}")) {
+            match crate::run_lua(&lua_cmd) {
                 Ok(()) => 1,
                 Err(_) => 0,
             }
@@ -221,12 +518,12 @@ pub extern "C" fn nvim_create_augroup_lua(name: *const c_char, clear: c_int) ->
     match extract_c_string(name) {
         Ok(name_str) => {
             let lua_cmd = format!(
-                "vim.api.nvim_create_augroup('{}', {{ clear = {} }})",
-                name_str,
+                "vim.api.nvim_create_augroup({}, {{ clear = {} }})",
+                lua_quote(&name_str),
                 if clear != 0 { "true" } else { "false" }
             );
 
-            match crate::run_cmd(&format!("lua {lua_cmd}")) {
+            match crate::run_lua(&lua_cmd) {
                 Ok(()) => 1,
                 Err(_) => 0,
             }
@@ -240,7 +537,8 @@ pub extern "C" fn nvim_create_augroup_lua(name: *const c_char, clear: c_int) ->
 /// # Safety
 ///
 /// `event`, `pattern`, and `command` must be valid null-terminated C strings.
-/// `group` must be either null or a valid null-terminated C string.
+/// `group` must be either null or a valid null-terminated C string. A null
+/// `group` falls back to the group being populated by `rns.augroup`, if any.
 #[no_mangle]
 pub extern "C" fn nvim_create_autocmd_lua(
     event: *const c_char,
@@ -256,17 +554,10 @@ pub extern "C" fn nvim_create_autocmd_lua(
                     event_str,
                     pattern_str,
                     cmd_str,
-                    if group.is_null() {
-                        String::new()
-                    } else {
-                        match extract_c_string(group) {
-                            Ok(group_str) => format!(", group = '{group_str}' "),
-                            Err(_) => String::new(),
-                        }
-                    }
+                    autocmd_group_field(group)
                 );
 
-                match crate::run_cmd(&format!("lua {lua_cmd}")) {
+                match crate::run_lua(&lua_cmd) {
                     Ok(()) => 1,
                     Err(_) => 0,
                 }
@@ -282,28 +573,37 @@ pub extern "C" fn nvim_create_autocmd_lua(
 /// # Safety
 ///
 /// `mode`, `lhs`, and `rhs` must be valid null-terminated C strings.
-/// `_opts` is currently unused but must be either null or a valid C string.
-/// `_buffer` is currently unused.
+/// `opts` must be either null or a valid C string in the packed options format.
+/// A `buffer` of 0 refers to the current buffer.
 #[no_mangle]
 pub extern "C" fn nvim_buf_set_keymap(
-    _buffer: c_int,
+    buffer: c_int,
     mode: *const c_char,
     lhs: *const c_char,
     rhs: *const c_char,
-    _opts: *const c_char,
+    opts: *const c_char,
 ) -> c_int {
-    match extract_c_string(mode) {
-        Ok(mode_str) => match (extract_c_string(lhs), extract_c_string(rhs)) {
-            (Ok(lhs_str), Ok(rhs_str)) => {
-                let cmd = format!("{mode_str}map <buffer> {lhs_str} {rhs_str}");
-                match crate::run_cmd(&cmd) {
-                    Ok(()) => 1,
-                    Err(_) => 0,
-                }
+    match (
+        extract_c_string(mode),
+        extract_c_string(lhs),
+        extract_c_string(rhs),
+        KeymapOpts::from_ptr(opts),
+    ) {
+        (Ok(mode), Ok(lhs), Ok(rhs), Ok(opts)) => {
+            let spec = KeymapSpec {
+                mode,
+                lhs,
+                rhs: Rhs::Command(rhs),
+                opts,
+                buffer: Some(buffer),
+            };
+
+            match emit_keymap(&spec) {
+                Ok(()) => 1,
+                Err(_) => 0,
             }
-            _ => 0,
-        },
-        Err(_) => 0,
+        }
+        _ => 0,
     }
 }
 
@@ -323,77 +623,1947 @@ pub extern "C" fn nvim_exec_command(command: *const c_char) -> c_int {
     }
 }
 
-/// Registers Neovim interop functions with the Lua state
-pub fn register_nvim_interop_functions(lua: &Lua<'_>) -> Result<()> {
-    extern "C" fn lua_nvim_set_option_bool(l: *mut LuaState) -> c_int {
-        let lua = match unsafe { Lua::new(l) } {
-            Ok(lua) => lua,
-            Err(_) => return 0,
-        };
+/// Builds an expression running a Vimscript block, evaluating to whether it succeeded
+///
+/// Failures are reported through `vim.notify` with the error from Neovim,
+/// which names the failing line.
+fn exec2_block_expr(script: &str) -> String {
+    format!(
+        "(function() \
+         local ok, err = pcall(vim.api.nvim_exec2, {}, {{ output = false }}) \
+         if not ok then vim.notify('rns: ' .. tostring(err), vim.log.levels.ERROR) end \
+         return ok \
+         end)()",
+        lua_quote(script)
+    )
+}
 
-        let name = match lua.check_string(1) {
-            Ok(s) => s,
-            Err(_) => return 0,
-        };
+/// Executes a multi-line Vimscript block in one call
+///
+/// Unlike running a script one `nvim_exec_command` at a time, the block is
+/// parsed as a whole, so `if`/`endif`, functions, and line continuations
+/// work, and execution stops at the first error. Returns 0 and notifies the
+/// error if any line fails.
+///
+/// # Safety
+///
+/// `script` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_exec2_block(script: *const c_char) -> c_int {
+    extract_c_string(script)
+        .and_then(|script| {
+            crate::eval_integer(&format!("{} and 1 or 0", exec2_block_expr(&script)))
+        })
+        .map_or(0, |ok| c_int::from(ok == 1))
+}
 
-        let value = unsafe { lua_toboolean(l, 2) };
+/// Builds the Lua expression for a scoped variable such as `vim.b['name']`
+///
+/// `scope` is one of `g`, `b`, `w`, or `t`.
+pub(crate) fn scoped_var(scope: &str, name: &str) -> Result<String> {
+    if !matches!(scope, "g" | "b" | "w" | "t") {
+        return Err(Error::InvalidArgument);
+    }
+
+    validate_identifier(name)?;
+    Ok(format!("vim.{scope}[{}]", lua_quote(name)))
+}
+
+/// Sets a scoped variable to `value`, given as a Lua expression
+fn set_var(scope: &str, name: &str, value: &str) -> Result<()> {
+    let var = scoped_var(scope, name)?;
+    crate::run_lua(&format!("{var} = {value}"))
+}
 
-        nvim_set_option_bool(CString::new(name).unwrap().as_ptr(), value)
+/// Sources runtime files matching `pattern`, or every match if `all` is set
+///
+/// # Safety
+///
+/// `pattern` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_source_runtime(pattern: *const c_char, all: c_int) -> c_int {
+    let pattern = match extract_c_string(pattern) {
+        Ok(s) if !s.is_empty() => s,
+        _ => return 0,
+    };
+
+    match crate::run_lua(&crate::runtime_cmd(&[&pattern], all != 0)) {
+        Ok(()) => 1,
+        Err(_) => 0,
     }
+}
 
-    extern "C" fn lua_nvim_create_keymap(l: *mut LuaState) -> c_int {
-        let lua = match unsafe { Lua::new(l) } {
-            Ok(lua) => lua,
-            Err(_) => return 0,
-        };
+/// Builds the expression listing runtime files matching `pattern`, or only the first if `all` is unset
+fn runtime_file_expr(pattern: &str, all: bool) -> String {
+    format!(
+        "vim.api.nvim_get_runtime_file({}, {all})",
+        lua_quote(pattern)
+    )
+}
 
-        let mode = match lua.check_string(1) {
-            Ok(s) => s,
-            Err(_) => return 0,
-        };
+/// Finds runtime files matching `pattern`, or only the first match unless `all` is set
+///
+/// Returns the paths separated by newlines in a string allocated by Neovim,
+/// which the caller frees with `xfree`, or null on failure.
+///
+/// # Safety
+///
+/// `pattern` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_get_runtime_file(pattern: *const c_char, all: c_int) -> *mut c_char {
+    let pattern = match extract_c_string(pattern) {
+        Ok(s) if !s.is_empty() => s,
+        _ => return std::ptr::null_mut(),
+    };
 
-        let lhs = match lua.check_string(2) {
-            Ok(s) => s,
-            Err(_) => return 0,
-        };
+    let expr = format!(
+        "table.concat({}, '\\n')",
+        runtime_file_expr(&pattern, all != 0)
+    );
+    let paths = match crate::eval_string(&expr)
+        .and_then(|paths| CString::new(paths).map_err(|_| Error::StringConversion))
+    {
+        Ok(paths) => paths,
+        Err(_) => return std::ptr::null_mut(),
+    };
 
-        let rhs = match lua.check_string(3) {
-            Ok(s) => s,
-            Err(_) => return 0,
-        };
+    // Copy into Neovim's allocator so the caller can free it like any other API string
+    unsafe { crate::concat_str(paths.as_ptr(), b"\0".as_ptr().cast()) }
+}
+
+/// Echoes `msg` highlighted with `hl_group`, adding it to `:messages` if `history` is set
+///
+/// An empty `hl_group` echoes the message without highlighting.
+fn echo(msg: &str, hl_group: &str, history: bool) -> Result<()> {
+    let chunk = if hl_group.is_empty() {
+        format!("{{ {} }}", lua_quote(msg))
+    } else {
+        validate_hl_group(hl_group)?;
+        format!("{{ {}, {} }}", lua_quote(msg), lua_quote(hl_group))
+    };
 
-        let opts = lua.check_string(4).unwrap_or_default();
+    crate::run_lua(&format!(
+        "vim.api.nvim_echo({{ {chunk} }}, {history}, {{}})"
+    ))
+}
 
-        let opts_ptr = if opts.is_empty() {
-            std::ptr::null()
-        } else {
-            CString::new(opts).unwrap().as_ptr()
-        };
+/// Echoes a message with an optional highlight group
+///
+/// # Safety
+///
+/// `msg` and `hl_group` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_echo(msg: *const c_char, hl_group: *const c_char, history: c_int) -> c_int {
+    let msg = match extract_c_string(msg) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let hl_group = match extract_c_string(hl_group) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
 
-        nvim_create_keymap(
-            CString::new(mode).unwrap().as_ptr(),
-            CString::new(lhs).unwrap().as_ptr(),
-            CString::new(rhs).unwrap().as_ptr(),
-            opts_ptr,
-        )
+    match echo(&msg, &hl_group, history != 0) {
+        Ok(()) => 1,
+        Err(_) => 0,
     }
+}
 
-    lua.push_cclosure(lua_nvim_set_option_bool, 0);
-    lua.set_field(-2, "set_option_bool")?;
+/// Builds the `confirm` call asking `msg` with `choices`, which evaluates to the chosen index
+///
+/// `choices` are separated by newlines, with `&` marking each accelerator key.
+/// The result is 1-based, or 0 if the dialog was dismissed.
+fn confirm_expr(msg: &str, choices: &str, default: i64) -> Result<String> {
+    if default < 0 {
+        return Err(Error::InvalidArgument);
+    }
 
-    lua.push_cclosure(lua_nvim_create_keymap, 0);
-    lua.set_field(-2, "create_keymap")?;
+    Ok(format!(
+        "vim.fn.confirm({}, {}, {default})",
+        lua_quote(msg),
+        lua_quote(choices)
+    ))
+}
 
-    Ok(())
+/// Asks the user to pick one of `choices` and returns the 1-based index of the choice
+///
+/// Returns 0 if the dialog was dismissed or could not be shown. `default` is
+/// the index picked by pressing Enter, or 0 for none.
+///
+/// # Safety
+///
+/// `msg` and `choices` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_confirm(
+    msg: *const c_char,
+    choices: *const c_char,
+    default: c_int,
+) -> c_int {
+    match (extract_c_string(msg), extract_c_string(choices)) {
+        (Ok(msg), Ok(choices)) => confirm_expr(&msg, &choices, default.into())
+            .and_then(|expr| crate::eval_integer(&expr))
+            .and_then(|choice| c_int::try_from(choice).map_err(|_| Error::InvalidArgument))
+            .unwrap_or(0),
+        _ => 0,
+    }
 }
 
-// Lua API bindings used in the functions
-extern "C" {
-    /// Converts a Lua value at the given index to a boolean
-    ///
-    /// # Safety
-    ///
-    /// `l` must be a valid pointer to a properly initialized Lua state.
+/// Feeds `keys` as if typed and processes them, then waits `ms` milliseconds
+///
+/// Keys go through `nvim_replace_termcodes`, so `<CR>` and friends work, and
+/// are fed with mode `tx`: `t` so that mappings see them as typed, and `x`
+/// so that the typeahead is executed before the call returns. Without `x`
+/// the keys would only be queued, and `vim.wait` would not run them.
+fn feedkeys_wait(keys: &str, ms: i64) -> Result<()> {
+    if keys.is_empty() || ms < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!(
+        "vim.api.nvim_feedkeys(vim.api.nvim_replace_termcodes({}, true, false, true), 'tx', false)\n\
+         vim.wait({ms})",
+        lua_quote(keys)
+    ))
+}
+
+/// Feeds keys as if typed and waits for them to be processed
+///
+/// Useful in tests and scripted sequences that drive mappings.
+///
+/// # Safety
+///
+/// `keys` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_feedkeys_wait(keys: *const c_char, ms: c_int) -> c_int {
+    match extract_c_string(keys) {
+        Ok(keys_str) => match feedkeys_wait(&keys_str, ms.into()) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        Err(_) => 0,
+    }
+}
+
+/// Unsets a scoped variable, as `:unlet!` would
+fn del_var(scope: &str, name: &str) -> Result<()> {
+    let var = scoped_var(scope, name)?;
+    crate::run_lua(&format!("{var} = nil"))
+}
+
+/// Deletes a `g`, `b`, `w`, or `t` scoped variable
+///
+/// Deleting a variable that does not exist is not an error.
+///
+/// # Safety
+///
+/// `scope` and `name` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_del_var(scope: *const c_char, name: *const c_char) -> c_int {
+    let scope = match extract_c_string(scope) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let name = match extract_c_string(name) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    match del_var(&scope, &name) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Builds an expression evaluating to an option's `default`, `scope`, and `type`
+///
+/// Unknown options evaluate to `nil` instead of raising an error.
+pub(crate) fn option_info_expr(name: &str) -> Result<String> {
+    validate_identifier(name)?;
+    Ok(format!(
+        "(function() \
+         local ok, info = pcall(vim.api.nvim_get_option_info2, {}, {{}}) \
+         if not ok then return nil end \
+         return {{ default = info.default, scope = info.scope, type = info.type }} \
+         end)()",
+        lua_quote(name)
+    ))
+}
+
+/// Builds an expression evaluating to an option's default value, or `nil` for unknown options
+fn option_default_expr(name: &str) -> Result<String> {
+    Ok(format!("({} or {{}}).default", option_info_expr(name)?))
+}
+
+/// Resets an option to its default value, as `:set name&` does
+fn reset_option(lua: Option<&Lua<'_>>, name: &str) -> Result<()> {
+    validate_identifier(name)?;
+    crate::check_option(lua, name)?;
+
+    if let Some(lua) = lua {
+        let option = format!("vim.o[{}]", lua_quote(name));
+        crate::transaction::record(lua, &option, |prior| format!("{option} = {prior}"))?;
+    }
+
+    crate::run_cmd(&format!("set {name}&"))
+}
+
+/// Resets a Neovim option to its default value
+///
+/// # Safety
+///
+/// `name` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_reset_option(name: *const c_char) -> c_int {
+    match extract_c_string(name).and_then(|name| reset_option(None, &name)) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Builds an expression evaluating to the definition of highlight group `name` in namespace `ns`
+///
+/// A group that is not defined evaluates to an empty table.
+fn get_hl_expr(ns: i64, name: &str) -> Result<String> {
+    validate_hl_group(name)?;
+    if ns < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!(
+        "(function() \
+         local ok, hl = pcall(vim.api.nvim_get_hl, {ns}, {{ name = {} }}) \
+         return ok and hl or {{}} \
+         end)()",
+        lua_quote(name)
+    ))
+}
+
+/// Links highlight group `from` to `to`, as `:hi link` would
+///
+/// Any existing definition of `from` is replaced by the link.
+fn highlight_link(from: &str, to: &str) -> Result<()> {
+    validate_hl_group(from)?;
+    validate_hl_group(to)?;
+
+    crate::run_lua(&format!(
+        "vim.api.nvim_set_hl(0, {}, {{ link = {} }})",
+        lua_quote(from),
+        lua_quote(to)
+    ))
+}
+
+/// Links one highlight group to another
+///
+/// # Safety
+///
+/// `from` and `to` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_highlight_link(from: *const c_char, to: *const c_char) -> c_int {
+    let from = match extract_c_string(from) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let to = match extract_c_string(to) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    match highlight_link(&from, &to) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Evaluates a Vimscript expression and pushes its value onto the stack
+///
+/// This goes through `vim.api.nvim_eval` rather than the C symbol, which
+/// takes Neovim's internal `String` and `Error` structs. The value keeps its
+/// type: strings, numbers, and booleans arrive as their Lua counterparts, and
+/// `v:null`, which the API returns as `vim.NIL`, as `nil`. If evaluation fails
+/// `nil` is pushed instead and the error is kept for `last_eval_error`.
+fn eval_vim(lua: &Lua<'_>, expr: &str) -> Result<()> {
+    lua.ensure_stack(2)?;
+    lua.exec(
+        &format!(
+            "local ok, result = pcall(vim.api.nvim_eval, {}) \
+             if ok then \
+               if result == vim.NIL then result = nil end \
+               return result, nil \
+             end \
+             return nil, tostring(result)",
+            lua_quote(expr)
+        ),
+        2,
+    )?;
+
+    let error = if lua.is_string(-1) {
+        lua.check_string(-1).ok()
+    } else {
+        None
+    };
+    lua.pop(1);
+
+    LAST_EVAL_ERROR.with(|last| *last.borrow_mut() = error);
+    Ok(())
+}
+
+/// Builds an expression calling `vim.api[method]` with arguments from a JSON array
+///
+/// An empty `args_json` means no arguments. Methods missing from `vim.api`
+/// are reported through `vim.notify` and evaluate to `nil`.
+fn api_call_expr(method: &str, args_json: &str) -> Result<String> {
+    validate_identifier(method)?;
+
+    let args = if args_json.trim().is_empty() {
+        Vec::new()
+    } else {
+        match Json::parse(args_json)? {
+            Json::Array(args) => args,
+            _ => return Err(Error::InvalidArgument),
+        }
+    };
+
+    // The count is passed explicitly so that `null` arguments survive `unpack`
+    let count = args.len();
+    let method_lua = lua_quote(method);
+    let msg = lua_quote(&format!("rns: unknown API method '{method}'"));
+    Ok(format!(
+        "(function() \
+         local fn = vim.api[{method_lua}] \
+         if not fn then vim.notify({msg}, vim.log.levels.ERROR) return nil end \
+         return fn(unpack({}, 1, {count})) \
+         end)()",
+        Json::Array(args).to_lua()
+    ))
+}
+
+/// Splits a comma-separated option value into its items
+///
+/// A comma escaped as `\,`, as allowed in options such as `path`, is kept as
+/// part of the item with the backslash removed. Empty items are dropped.
+fn split_option_list(value: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                item.push(',');
+                chars.next();
+            }
+            ',' => items.push(std::mem::take(&mut item)),
+            _ => item.push(c),
+        }
+    }
+    items.push(item);
+
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+/// Reads the value of option `name` as a string, from `lua` or the host state if `None`
+fn option_string(lua: Option<&Lua<'_>>, name: &str) -> Result<String> {
+    validate_identifier(name)?;
+
+    let expr = format!("tostring(vim.o[{}])", lua_quote(name));
+    match lua {
+        Some(lua) => {
+            lua.eval(&expr)?;
+            let value = lua.check_string(-1);
+            lua.pop(1);
+            value
+        }
+        None => crate::eval_string(&expr),
+    }
+}
+
+/// Builds a Lua array literal holding the items of a comma-separated option
+fn option_list_expr(lua: &Lua<'_>, name: &str) -> Result<String> {
+    let items = split_option_list(&option_string(Some(lua), name)?)
+        .iter()
+        .map(|item| lua_quote(item))
+        .collect::<Vec<_>>();
+    Ok(format!("{{ {} }}", items.join(", ")))
+}
+
+/// Appends `value` to the comma-separated option `name` unless it is already an item
+///
+/// Sourcing a config again would otherwise add the same `runtimepath` or
+/// `packpath` entry on every reload.
+fn option_append_unique(lua: Option<&Lua<'_>>, name: &str, value: &str) -> Result<()> {
+    crate::check_option(lua, name)?;
+    if value.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+
+    if split_option_list(&option_string(lua, name)?)
+        .iter()
+        .any(|item| item == value)
+    {
+        return Ok(());
+    }
+
+    let option = format!("vim.o[{}]", lua_quote(name));
+    if let Some(lua) = lua {
+        crate::transaction::record(lua, &option, |prior| format!("{option} = {prior}"))?;
+    }
+
+    crate::run_lua(&format!(
+        "vim.opt[{}]:append({})",
+        lua_quote(name),
+        lua_quote(value)
+    ))
+}
+
+/// Appends an item to a comma-separated option if it is not already present
+///
+/// # Safety
+///
+/// `name` and `value` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_option_append_unique(name: *const c_char, value: *const c_char) -> c_int {
+    match (extract_c_string(name), extract_c_string(value)) {
+        (Ok(name), Ok(value)) => match option_append_unique(None, &name, &value) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Builds the `nvim_exec_autocmds` call for an event
+///
+/// A two-word event such as `User MyEvent` is split into the event and its
+/// pattern, matching how `:doautocmd` is written. An empty pattern is omitted.
+fn exec_autocmds_code(event: &str, pattern: &str) -> Result<String> {
+    let mut words = event.split_whitespace();
+    let event = words.next().ok_or(Error::InvalidArgument)?;
+    let inline_pattern = words.next();
+
+    if words.next().is_some() {
+        return Err(Error::InvalidArgument);
+    }
+
+    validate_identifier(event)?;
+
+    let pattern = match (inline_pattern, pattern.is_empty()) {
+        (Some(inline), true) => inline,
+        (Some(_), false) => return Err(Error::InvalidArgument),
+        (None, _) => pattern,
+    };
+
+    if pattern.is_empty() {
+        Ok(format!(
+            "vim.api.nvim_exec_autocmds({}, {{}})",
+            lua_quote(event)
+        ))
+    } else {
+        Ok(format!(
+            "vim.api.nvim_exec_autocmds({}, {{ pattern = {} }})",
+            lua_quote(event),
+            lua_quote(pattern)
+        ))
+    }
+}
+
+/// Triggers the autocommands for an event
+///
+/// # Safety
+///
+/// `event` must be a valid null-terminated C string, optionally of the form
+/// `User <name>`. `pattern` must be either null or a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_exec_autocmds(event: *const c_char, pattern: *const c_char) -> c_int {
+    let pattern = if pattern.is_null() {
+        Ok(String::new())
+    } else {
+        extract_c_string(pattern)
+    };
+
+    match (extract_c_string(event), pattern) {
+        (Ok(event_str), Ok(pattern_str)) => {
+            match exec_autocmds_code(&event_str, &pattern_str)
+                .and_then(|code| crate::run_lua(&code))
+            {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// Builds an abbreviation command for the given mode
+///
+/// `mode` is `i` for insert mode, `c` for command-line mode, or empty for both.
+fn abbrev_cmd(mode: &str, lhs: &str, rhs: &str) -> Result<String> {
+    if !matches!(mode, "" | "i" | "c") {
+        return Err(Error::InvalidArgument);
+    }
+
+    // The lhs ends at the first whitespace, so it cannot contain any
+    if lhs.is_empty() || lhs.chars().any(char::is_whitespace) || rhs.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!("{mode}abbrev {lhs} {rhs}"))
+}
+
+/// Creates an abbreviation in Neovim
+///
+/// # Safety
+///
+/// `mode`, `lhs`, and `rhs` must be valid null-terminated C strings.
+/// `mode` must be `"i"`, `"c"`, or empty for both modes.
+#[no_mangle]
+pub extern "C" fn nvim_create_abbrev(
+    mode: *const c_char,
+    lhs: *const c_char,
+    rhs: *const c_char,
+) -> c_int {
+    match (
+        extract_c_string(mode),
+        extract_c_string(lhs),
+        extract_c_string(rhs),
+    ) {
+        (Ok(mode_str), Ok(lhs_str), Ok(rhs_str)) => {
+            match abbrev_cmd(&mode_str, &lhs_str, &rhs_str).and_then(|cmd| crate::run_cmd(&cmd)) {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// Redraws the screen
+#[no_mangle]
+pub extern "C" fn nvim_redraw() -> c_int {
+    match crate::run_cmd("redraw") {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Redraws the status line of every window
+#[no_mangle]
+pub extern "C" fn nvim_redrawstatus() -> c_int {
+    match crate::run_cmd("redrawstatus!") {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Registers Neovim interop functions with the Lua state
+pub fn register_nvim_interop_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_nvim_set_option_bool(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let value = unsafe { lua_toboolean(l, 2) };
+
+        match CString::new(name) {
+            Ok(name) => nvim_set_option_bool(name.as_ptr(), value),
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_set_autocmd_callback(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let event = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let pattern = match lua.opt_string(2) {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => return 0,
+        };
+
+        let fn_name = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        // Return the autocmd id
+        match autocmd_callback_expr(&event, &pattern, &fn_name, lua.to_boolean(4))
+            .and_then(|expr| lua.eval(&expr))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_set_option_global_local(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let value = match lua.check_value(2) {
+            Ok(v) => v,
+            Err(_) => return 0,
+        };
+
+        match set_option_global_local(Some(&lua), &name, &value) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_options(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match set_options(&lua, 1) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_set_option_multi(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let pairs_json = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match set_option_multi(Some(&lua), &name, &pairs_json) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_create_keymap(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let mode = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let lhs = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let rhs = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let opts = match KeymapOpts::from_lua(&lua, 4) {
+            Ok(opts) => opts,
+            Err(_) => return 0,
+        };
+
+        let spec = KeymapSpec {
+            mode,
+            lhs,
+            rhs: Rhs::Command(rhs),
+            opts,
+            buffer: None,
+        };
+
+        match emit_keymap(&spec) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_augroup(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let c_name = match CString::new(name.as_str()) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        if nvim_create_augroup_lua(c_name.as_ptr(), 1) == 0 {
+            return 0;
+        }
+
+        let previous = CURRENT_AUGROUP.with(|group| group.replace(Some(name)));
+        let result = lua.call_function(2);
+        CURRENT_AUGROUP.with(|group| *group.borrow_mut() = previous);
+
+        match result {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_get_runtime_file(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let pattern = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let all = lua.to_boolean(2);
+
+        match lua.eval(&runtime_file_expr(&pattern, all)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_get_option_info(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match option_info_expr(&name).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_get_option_default(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match option_default_expr(&name).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_reset_option(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua
+            .check_string(1)
+            .and_then(|name| reset_option(Some(&lua), &name))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_option_append_unique(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let value = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match option_append_unique(Some(&lua), &name, &value) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_get_option_list(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match option_list_expr(&lua, &name).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_create_abbrev(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let mode = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let lhs = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let rhs = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match abbrev_cmd(&mode, &lhs, &rhs).and_then(|cmd| crate::run_cmd(&cmd)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_exec_autocmds(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let event = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let pattern = match lua.opt_string(2) {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => return 0,
+        };
+
+        match exec_autocmds_code(&event, &pattern).and_then(|code| crate::run_lua(&code)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_exec2_block(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        // Return whether the block ran without errors
+        match lua
+            .check_string(1)
+            .and_then(|script| lua.eval(&exec2_block_expr(&script)))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_set_var(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let scope = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let value = match lua.check_value(3) {
+            Ok(v) => v,
+            Err(_) => return 0,
+        };
+
+        match set_var(&scope, &name, &value.to_lua()) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_get_var_or_default(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let scope = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        if lua.ensure_stack(1).is_err() {
+            return 0;
+        }
+
+        if scoped_var(&scope, &name)
+            .and_then(|var| lua.eval(&var))
+            .is_err()
+        {
+            return 0;
+        }
+
+        // An unset variable reads as nil; hand back the caller's default,
+        // whatever its type, in its place
+        if lua.is_nil_on_top() {
+            lua.pop(1);
+            lua.push_value(3);
+        }
+
+        1
+    }
+
+    extern "C" fn lua_nvim_del_var(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let scope = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match del_var(&scope, &name) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_echo(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let msg = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let hl_group = match lua.opt_string(2) {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => return 0,
+        };
+
+        match echo(&msg, &hl_group, lua.to_boolean(3)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_confirm(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let msg = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let choices = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        // Enter picks the first choice unless told otherwise
+        let default = match lua.check_value(3) {
+            Ok(Value::Nil) => 1,
+            Ok(Value::Number(_)) => match lua.check_integer(3) {
+                Ok(n) => n,
+                Err(_) => return 0,
+            },
+            _ => return 0,
+        };
+
+        match confirm_expr(&msg, &choices, default).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_feedkeys_wait(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let keys = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let ms = match lua.check_integer(2) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match feedkeys_wait(&keys, ms) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_source_runtime(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let pattern = match lua.check_string(1) {
+            Ok(s) if !s.is_empty() => s,
+            _ => return 0,
+        };
+
+        match crate::run_lua(&crate::runtime_cmd(&[&pattern], lua.to_boolean(2))) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_get_hl(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let ns = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match get_hl_expr(ns, &name).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_get_all_hl(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        // Defaults to the global namespace
+        let ns = match lua.check_value(1) {
+            Ok(Value::Nil) => 0,
+            Ok(Value::Number(n)) if n >= 0.0 && n.fract() == 0.0 => n as i64,
+            _ => return 0,
+        };
+
+        // Return every group in the namespace as a table keyed by group name
+        match lua.eval(&format!("vim.api.nvim_get_hl({ns}, {{}})")) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_highlight_link(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let from = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let to = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match highlight_link(&from, &to) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_api_call(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let method = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let args_json = match lua.opt_string(2) {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => return 0,
+        };
+
+        match api_call_expr(&method, &args_json).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_eval(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.check_string(1).and_then(|expr| eval_vim(&lua, &expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_last_eval_error(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match LAST_EVAL_ERROR.with(|last| last.borrow().clone()) {
+            Some(error) => {
+                lua.push_string(&error);
+                1
+            }
+            None => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_list_runtime_paths(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        // Return the 'runtimepath' directories as an array, in search order
+        match lua.eval("vim.api.nvim_list_runtime_paths()") {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_get_mode(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let expr = "(function() \
+                    local m = vim.api.nvim_get_mode() \
+                    return { mode = m.mode, blocking = m.blocking } \
+                    end)()";
+        match lua.eval(expr) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_nvim_redraw(_l: *mut LuaState) -> c_int {
+        nvim_redraw()
+    }
+
+    extern "C" fn lua_nvim_redrawstatus(_l: *mut LuaState) -> c_int {
+        nvim_redrawstatus()
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_nvim_set_option_bool, 0);
+    lua.set_field(-2, "set_option_bool")?;
+
+    lua.push_cclosure(lua_nvim_set_autocmd_callback, 0);
+    lua.set_field(-2, "set_autocmd_callback")?;
+
+    lua.push_cclosure(lua_nvim_set_option_multi, 0);
+    lua.set_field(-2, "set_option_multi")?;
+
+    lua.push_cclosure(lua_nvim_set_option_global_local, 0);
+    lua.set_field(-2, "set_option_global_local")?;
+
+    lua.push_cclosure(lua_set_options, 0);
+    lua.set_field(-2, "set_options")?;
+
+    lua.push_cclosure(lua_nvim_create_keymap, 0);
+    lua.set_field(-2, "create_keymap")?;
+
+    lua.push_cclosure(lua_nvim_augroup, 0);
+    lua.set_field(-2, "augroup")?;
+
+    lua.push_cclosure(lua_nvim_get_runtime_file, 0);
+    lua.set_field(-2, "get_runtime_file")?;
+
+    lua.push_cclosure(lua_nvim_list_runtime_paths, 0);
+    lua.set_field(-2, "list_runtime_paths")?;
+
+    lua.push_cclosure(lua_nvim_create_abbrev, 0);
+    lua.set_field(-2, "abbrev")?;
+
+    lua.push_cclosure(lua_nvim_exec_autocmds, 0);
+    lua.set_field(-2, "exec_autocmds")?;
+
+    lua.push_cclosure(lua_nvim_exec2_block, 0);
+    lua.set_field(-2, "exec2_block")?;
+
+    lua.push_cclosure(lua_nvim_set_var, 0);
+    lua.set_field(-2, "set_var")?;
+
+    lua.push_cclosure(lua_nvim_get_var_or_default, 0);
+    lua.set_field(-2, "get_var_or_default")?;
+
+    lua.push_cclosure(lua_nvim_del_var, 0);
+    lua.set_field(-2, "del_var")?;
+
+    lua.push_cclosure(lua_nvim_get_option_info, 0);
+    lua.set_field(-2, "get_option_info")?;
+
+    lua.push_cclosure(lua_nvim_get_option_default, 0);
+    lua.set_field(-2, "get_option_default")?;
+
+    lua.push_cclosure(lua_nvim_reset_option, 0);
+    lua.set_field(-2, "reset_option")?;
+
+    lua.push_cclosure(lua_option_append_unique, 0);
+    lua.set_field(-2, "option_append_unique")?;
+
+    lua.push_cclosure(lua_nvim_get_option_list, 0);
+    lua.set_field(-2, "list_option_values")?;
+
+    lua.push_cclosure(lua_nvim_echo, 0);
+    lua.set_field(-2, "echo")?;
+
+    lua.push_cclosure(lua_confirm, 0);
+    lua.set_field(-2, "confirm")?;
+
+    lua.push_cclosure(lua_feedkeys_wait, 0);
+    lua.set_field(-2, "feedkeys_wait")?;
+
+    lua.push_cclosure(lua_nvim_source_runtime, 0);
+    lua.set_field(-2, "source_runtime")?;
+
+    lua.push_cclosure(lua_nvim_get_hl, 0);
+    lua.set_field(-2, "get_hl")?;
+
+    lua.push_cclosure(lua_nvim_get_all_hl, 0);
+    lua.set_field(-2, "get_hl_groups")?;
+
+    lua.push_cclosure(lua_nvim_highlight_link, 0);
+    lua.set_field(-2, "highlight_link")?;
+
+    lua.push_cclosure(lua_nvim_api_call, 0);
+    lua.set_field(-2, "api")?;
+
+    lua.push_cclosure(lua_nvim_eval, 0);
+    lua.set_field(-2, "eval")?;
+
+    lua.push_cclosure(lua_last_eval_error, 0);
+    lua.set_field(-2, "last_eval_error")?;
+
+    lua.push_cclosure(lua_nvim_get_mode, 0);
+    lua.set_field(-2, "get_mode")?;
+
+    lua.push_cclosure(lua_nvim_redraw, 0);
+    lua.set_field(-2, "redraw")?;
+
+    lua.push_cclosure(lua_nvim_redrawstatus, 0);
+    lua.set_field(-2, "redrawstatus")?;
+
+    Ok(())
+}
+
+// Lua API bindings used in the functions
+extern "C" {
+    /// Converts a Lua value at the given index to a boolean
+    ///
+    /// # Safety
+    ///
+    /// `l` must be a valid pointer to a properly initialized Lua state.
     /// The index must be valid (not beyond the stack size).
     fn lua_toboolean(l: *mut LuaState, idx: c_int) -> c_int;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockValue};
+
+    #[test]
+    fn augroup_applies_to_autocmds_created_in_its_callback() {
+        extern "C" fn create_autocmd(_l: *mut LuaState) -> c_int {
+            let event = CString::new("BufWritePre").unwrap();
+            let pattern = CString::new("*.rs").unwrap();
+            let command = CString::new("RustFmt").unwrap();
+            nvim_create_autocmd_lua(
+                event.as_ptr(),
+                pattern.as_ptr(),
+                command.as_ptr(),
+                std::ptr::null(),
+            );
+            0
+        }
+
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let augroup = mock::get(-1).field("augroup");
+
+        let callback = mock::MockValue::Function(create_autocmd, Default::default());
+        mock::call(&augroup, [mock::string("rust's"), callback]);
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.api.nvim_create_augroup('rust\\'s', { clear = true })",
+                "lua vim.api.nvim_create_autocmd('BufWritePre', { pattern = '*.rs', command = 'RustFmt' , group = 'rust\\'s' })",
+            ]
+        );
+        assert_eq!(current_augroup(), None);
+    }
+
+    #[test]
+    fn gets_runtime_files_as_an_array() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let get_runtime_file = mock::get(-1).field("get_runtime_file");
+
+        mock::set_result(
+            "return vim.api.nvim_get_runtime_file('colors/*.vim', true)",
+            mock::list([
+                mock::string("/usr/share/nvim/runtime/colors/blue.vim"),
+                mock::string("/usr/share/nvim/runtime/colors/desert.vim"),
+            ]),
+        );
+        let results = mock::call(
+            &get_runtime_file,
+            [mock::string("colors/*.vim"), true.into()],
+        );
+        assert!(
+            matches!(&results[..], [mock::MockValue::Table(paths)] if paths.borrow().len() == 2)
+        );
+    }
+
+    #[test]
+    fn runtime_file_export_rejects_an_empty_pattern() {
+        let empty = CString::new("").unwrap();
+        assert!(nvim_get_runtime_file(empty.as_ptr(), 1).is_null());
+        assert_eq!(
+            runtime_file_expr("it's/*.vim", false),
+            "vim.api.nvim_get_runtime_file('it\\'s/*.vim', false)"
+        );
+    }
+
+    #[test]
+    fn redraws_the_screen_and_status_lines() {
+        mock::lua();
+        assert_eq!(nvim_redraw(), 1);
+        assert_eq!(nvim_redrawstatus(), 1);
+        assert_eq!(mock::commands(), ["redraw", "redrawstatus!"]);
+    }
+
+    #[test]
+    fn builds_abbreviations() {
+        assert_eq!(abbrev_cmd("i", "teh", "the").unwrap(), "iabbrev teh the");
+        assert_eq!(abbrev_cmd("", "W", "w").unwrap(), "abbrev W w");
+        assert!(abbrev_cmd("n", "teh", "the").is_err());
+        assert!(abbrev_cmd("i", "t h", "the").is_err());
+    }
+
+    #[test]
+    fn builds_exec_autocmds_calls() {
+        assert_eq!(
+            exec_autocmds_code("User MyEvent", "").unwrap(),
+            "vim.api.nvim_exec_autocmds('User', { pattern = 'MyEvent' })"
+        );
+        assert_eq!(
+            exec_autocmds_code("BufRead", "").unwrap(),
+            "vim.api.nvim_exec_autocmds('BufRead', {})"
+        );
+        assert!(exec_autocmds_code("User MyEvent", "other").is_err());
+        assert!(exec_autocmds_code("", "").is_err());
+    }
+
+    #[test]
+    fn retriggers_filetype_autocmds() {
+        mock::lua();
+        let event = CString::new("FileType").unwrap();
+        let pattern = CString::new("rust").unwrap();
+        assert_eq!(nvim_exec_autocmds(event.as_ptr(), pattern.as_ptr()), 1);
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.api.nvim_exec_autocmds('FileType', { pattern = 'rust' })"]
+        );
+    }
+
+    #[test]
+    fn sets_typed_variables_in_any_scope() {
+        mock::lua();
+        let scope = CString::new("b").unwrap();
+        let name = CString::new("formatters").unwrap();
+        let value = CString::new(r#"["stylua", 2]"#).unwrap();
+        assert_eq!(
+            nvim_set_var(scope.as_ptr(), name.as_ptr(), value.as_ptr()),
+            1
+        );
+
+        let null = CString::new("null").unwrap();
+        assert_eq!(
+            nvim_set_var(scope.as_ptr(), name.as_ptr(), null.as_ptr()),
+            0
+        );
+        let vim_scope = CString::new("v").unwrap();
+        assert_eq!(
+            nvim_set_var(vim_scope.as_ptr(), name.as_ptr(), value.as_ptr()),
+            0
+        );
+
+        let leader = CString::new("mapleader").unwrap();
+        let space = CString::new(" ").unwrap();
+        assert_eq!(nvim_set_global(leader.as_ptr(), space.as_ptr()), 1);
+
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.b['formatters'] = { 'stylua', 2 }",
+                "lua vim.g['mapleader'] = ' '",
+            ]
+        );
+        assert_eq!(
+            scoped_var("b", "did_ftplugin").unwrap(),
+            "vim.b['did_ftplugin']"
+        );
+        assert!(scoped_var("g", "not valid").is_err());
+    }
+
+    #[test]
+    fn option_info_is_nil_for_unknown_options() {
+        let expr = option_info_expr("tabstop").unwrap();
+        assert!(expr.contains("pcall(vim.api.nvim_get_option_info2, 'tabstop', {})"));
+        assert!(expr.contains("if not ok then return nil end"));
+        assert!(expr.contains("default = info.default, scope = info.scope, type = info.type"));
+        assert!(option_info_expr("tab stop").is_err());
+    }
+
+    #[test]
+    fn splits_option_lists_on_unescaped_commas() {
+        assert_eq!(split_option_list(r"a,b\,c,,d,"), ["a", "b,c", "d"]);
+        assert!(split_option_list("").is_empty());
+    }
+
+    #[test]
+    fn deletes_variables_by_assigning_nil() {
+        mock::lua();
+        let scope = CString::new("g").unwrap();
+        let name = CString::new("x").unwrap();
+        assert_eq!(nvim_del_var(scope.as_ptr(), name.as_ptr()), 1);
+
+        let bad_scope = CString::new("v").unwrap();
+        assert_eq!(nvim_del_var(bad_scope.as_ptr(), name.as_ptr()), 0);
+        assert_eq!(mock::commands(), ["lua vim.g['x'] = nil"]);
+    }
+
+    #[test]
+    fn gets_the_mode_as_a_table() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let get_mode = mock::get(-1).field("get_mode");
+
+        mock::call(&get_mode, []);
+        let chunk = mock::chunks().remove(0);
+        assert!(chunk.starts_with("return (function() local m = vim.api.nvim_get_mode()"));
+
+        let mode = mock::table([("mode", "niI".into()), ("blocking", false.into())]);
+        mock::set_result(&chunk, mode);
+        let results = mock::call(&get_mode, []);
+        assert_eq!(results[0].field("mode").as_str(), Some("niI"));
+    }
+
+    #[test]
+    fn builds_highlight_lookups() {
+        assert!(get_hl_expr(0, "@markup.heading")
+            .unwrap()
+            .contains("pcall(vim.api.nvim_get_hl, 0, { name = '@markup.heading' })"));
+        assert!(get_hl_expr(-1, "Normal").is_err());
+        assert!(get_hl_expr(0, "Not Valid").is_err());
+    }
+
+    #[test]
+    fn evaluates_strings_numbers_booleans_and_nil() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let eval = mock::get(-1).field("eval");
+        let last_eval_error = mock::get(-1).field("last_eval_error");
+
+        let cases = [
+            ("&filetype", MockValue::from("lua")),
+            ("&shiftwidth", MockValue::Number(4.0)),
+            ("v:true", MockValue::Bool(true)),
+            ("v:null", MockValue::Nil),
+        ];
+        for (expr, value) in cases {
+            mock::call(&eval, [expr.into()]);
+            let chunk = mock::chunks().pop().unwrap();
+            assert!(chunk.contains(&format!("pcall(vim.api.nvim_eval, {})", lua_quote(expr))));
+            assert!(chunk.contains("if result == vim.NIL then result = nil end"));
+
+            mock::set_result(&chunk, value.clone());
+            let results = mock::call(&eval, [expr.into()]);
+            match (&results[0], &value) {
+                (MockValue::Str(_), MockValue::Str(_)) => {
+                    assert_eq!(results[0].as_str(), value.as_str())
+                }
+                (MockValue::Number(a), MockValue::Number(b)) => assert_eq!(a, b),
+                (MockValue::Bool(a), MockValue::Bool(b)) => assert_eq!(a, b),
+                (MockValue::Nil, MockValue::Nil) => {}
+                _ => panic!("unexpected result for {}", expr),
+            }
+            assert!(mock::call(&last_eval_error, []).is_empty());
+        }
+    }
+
+    #[test]
+    fn builds_api_calls_with_an_explicit_count() {
+        let expr =
+            api_call_expr("nvim_buf_set_lines", r#"[0, 0, -1, false, ["a", null]]"#).unwrap();
+        assert!(expr.contains("local fn = vim.api['nvim_buf_set_lines']"));
+        assert!(expr.contains("return fn(unpack({ 0, 0, -1, false, { 'a', nil } }, 1, 5))"));
+
+        assert!(api_call_expr("nvim_list_bufs", "")
+            .unwrap()
+            .contains("unpack({}, 1, 0)"));
+        assert!(api_call_expr("nvim_list_bufs", "{}").is_err());
+        assert!(api_call_expr("vim.cmd", "").is_err());
+    }
+
+    #[test]
+    fn vimscript_block_failures_are_notified() {
+        let script = "if 1\necho 'x'\nendif";
+        let expr = exec2_block_expr(script);
+        assert!(expr
+            .contains(r"pcall(vim.api.nvim_exec2, 'if 1\necho \'x\'\nendif', { output = false })"));
+        assert!(expr.contains(
+            "if not ok then vim.notify('rns: ' .. tostring(err), vim.log.levels.ERROR) end"
+        ));
+
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let exec2_block = mock::get(-1).field("exec2_block");
+
+        mock::set_result(&format!("return {expr}"), false.into());
+        let results = mock::call(&exec2_block, [script.into()]);
+        assert!(matches!(results[..], [MockValue::Bool(false)]));
+    }
+
+    #[test]
+    fn lists_runtime_paths_as_an_array() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let list_runtime_paths = mock::get(-1).field("list_runtime_paths");
+
+        let paths = mock::list([
+            mock::string("/home/u/.config/nvim"),
+            mock::string("/usr/share/nvim"),
+        ]);
+        mock::set_result("return vim.api.nvim_list_runtime_paths()", paths);
+        let results = mock::call(&list_runtime_paths, []);
+        assert!(matches!(&results[..], [MockValue::Table(t)] if t.borrow().len() == 2));
+    }
+
+    #[test]
+    fn links_highlight_groups() {
+        mock::lua();
+        let (from, to) = (
+            CString::new("MyTitle").unwrap(),
+            CString::new("Title").unwrap(),
+        );
+        let bad = CString::new("My Title").unwrap();
+
+        assert_eq!(nvim_highlight_link(from.as_ptr(), to.as_ptr()), 1);
+        assert_eq!(nvim_highlight_link(bad.as_ptr(), to.as_ptr()), 0);
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.api.nvim_set_hl(0, 'MyTitle', { link = 'Title' })"]
+        );
+    }
+
+    #[test]
+    fn renders_option_pairs() {
+        assert_eq!(
+            option_pairs_value(r#"{"tab": "> ", "trail": "-", "nbsp": 1}"#).unwrap(),
+            "tab:> ,trail:-,nbsp:1"
+        );
+        assert!(option_pairs_value("{}").is_err());
+        assert!(option_pairs_value(r#"{"tab": "a,b"}"#).is_err());
+        assert!(option_pairs_value(r#"{"tab": true}"#).is_err());
+    }
+
+    #[test]
+    fn builds_autocmd_callbacks() {
+        assert_eq!(
+            autocmd_callback_expr("BufEnter,BufWritePost", "*.rs", "vim.lsp.buf.format", false)
+                .unwrap(),
+            "vim.api.nvim_create_autocmd({ 'BufEnter', 'BufWritePost' }, \
+             { callback = function(...) return vim.lsp.buf.format(...) end, pattern = '*.rs' })"
+        );
+        assert_eq!(
+            autocmd_callback_expr("LspAttach", "", "on_attach", true).unwrap(),
+            "vim.api.nvim_create_autocmd({ 'LspAttach' }, \
+             { callback = vim.schedule_wrap(function(...) return on_attach(...) end) })"
+        );
+        assert!(autocmd_callback_expr("Buf Enter", "", "f", false).is_err());
+    }
+
+    #[test]
+    fn gets_option_defaults() {
+        let expr = option_default_expr("tabstop").unwrap();
+        assert!(expr.contains("pcall(vim.api.nvim_get_option_info2, 'tabstop', {})"));
+        assert!(expr.ends_with(" or {}).default"));
+        assert!(option_default_expr("tab stop").is_err());
+
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let get_option_default = mock::get(-1).field("get_option_default");
+
+        mock::set_result(&format!("return {expr}"), 8.0.into());
+        let results = mock::call(&get_option_default, ["tabstop".into()]);
+        assert!(matches!(results[..], [MockValue::Number(n)] if n == 8.0));
+    }
+
+    #[test]
+    fn resets_options_to_their_default() {
+        mock::lua();
+        let number = CString::new("number").unwrap();
+        let bad = CString::new("number&").unwrap();
+
+        assert_eq!(nvim_reset_option(number.as_ptr()), 1);
+        assert_eq!(nvim_reset_option(bad.as_ptr()), 0);
+        assert_eq!(mock::commands(), ["set number&"]);
+    }
+
+    #[test]
+    fn falls_back_to_the_default_for_unset_variables() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let get_var_or_default = mock::get(-1).field("get_var_or_default");
+
+        mock::set_result("return vim.g['rns_width']", 100.0.into());
+        let set = mock::call(
+            &get_var_or_default,
+            ["g".into(), "rns_width".into(), 80.0.into()],
+        );
+        assert!(matches!(set[..], [MockValue::Number(n)] if n == 100.0));
+
+        let default = mock::table([("style", "rounded".into())]);
+        let unset = mock::call(
+            &get_var_or_default,
+            ["b".into(), "rns_border".into(), default],
+        );
+        assert!(matches!(&unset[..], [value] if value.field("style").as_str() == Some("rounded")));
+
+        assert!(mock::call(&get_var_or_default, ["x".into(), "rns_border".into()]).is_empty());
+    }
+
+    #[test]
+    fn sets_both_global_and_local_values() {
+        mock::lua();
+        let name = CString::new("signcolumn").unwrap();
+        let value = CString::new(r#""yes:1""#).unwrap();
+        let null = CString::new("null").unwrap();
+
+        assert_eq!(
+            nvim_set_option_global_local(name.as_ptr(), value.as_ptr()),
+            1
+        );
+        assert_eq!(
+            nvim_set_option_global_local(name.as_ptr(), null.as_ptr()),
+            0
+        );
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.api.nvim_set_option_value('signcolumn', 'yes:1', { scope = 'global' })\n\
+              vim.api.nvim_set_option_value('signcolumn', 'yes:1', { scope = 'local' })"
+            ]
+        );
+    }
+
+    #[test]
+    fn gets_every_highlight_group_of_a_namespace() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_nvim_interop_functions(&lua).unwrap();
+        let get_hl_groups = mock::get(-1).field("get_hl_groups");
+
+        let groups = mock::table([("Normal", mock::table([("fg", 16777215.0.into())]))]);
+        mock::set_result("return vim.api.nvim_get_hl(0, {})", groups);
+        let results = mock::call(&get_hl_groups, []);
+        assert!(
+            matches!(results[0].field("Normal").field("fg"), MockValue::Number(n) if n == 16777215.0)
+        );
+
+        mock::call(&get_hl_groups, [5.0.into()]);
+        assert!(mock::call(&get_hl_groups, [(-1.0).into()]).is_empty());
+        assert!(mock::call(&get_hl_groups, ["Normal".into()]).is_empty());
+        assert_eq!(
+            mock::chunks(),
+            [
+                "return vim.api.nvim_get_hl(0, {})",
+                "return vim.api.nvim_get_hl(5, {})"
+            ]
+        );
+    }
+
+    #[test]
+    fn set_options_validates_every_pair_before_recording() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        crate::transaction::register_transaction_functions(&lua).unwrap();
+        let rns = mock::get(-1);
+        mock::set_result("return vim.inspect(vim.o['tabstop'])", mock::string("8"));
+        mock::set_result("return vim.inspect(vim.o['shiftwidth'])", mock::string("8"));
+
+        mock::call(&rns.field("begin_transaction"), []);
+        mock::push(mock::table([
+            ("tabstop", 4.0.into()),
+            ("not valid", true.into()),
+        ]));
+        assert!(set_options(&lua, -1).is_err());
+        lua.pop(1);
+        assert!(mock::commands().is_empty());
+
+        mock::push(mock::table([
+            ("tabstop", 4.0.into()),
+            ("shiftwidth", 2.0.into()),
+        ]));
+        set_options(&lua, -1).unwrap();
+        lua.pop(1);
+        mock::call(&rns.field("rollback"), []);
+
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.api.nvim_set_option_value('tabstop', 4, {})\n\
+                 vim.api.nvim_set_option_value('shiftwidth', 2, {})",
+                "lua vim.o['shiftwidth'] = 8",
+                "lua vim.o['tabstop'] = 8",
+            ]
+        );
+    }
+
+    #[test]
+    fn feeds_keys_and_executes_them_before_waiting() {
+        mock::lua();
+        let keys = CString::new("ihello<Esc>").unwrap();
+        assert_eq!(nvim_feedkeys_wait(keys.as_ptr(), 50), 1);
+        assert_eq!(nvim_feedkeys_wait(keys.as_ptr(), -1), 0);
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.api.nvim_feedkeys(vim.api.nvim_replace_termcodes('ihello<Esc>', true, false, true), 'tx', false)\n\
+              vim.wait(50)"]
+        );
+    }
+
+    #[test]
+    fn appends_only_missing_items() {
+        let lua = mock::lua();
+        mock::set_result("return tostring(vim.o['path'])", ".,,**".into());
+
+        option_append_unique(Some(&lua), "path", "**").unwrap();
+        option_append_unique(Some(&lua), "path", "/usr/include").unwrap();
+        assert!(option_append_unique(Some(&lua), "path", "").is_err());
+        assert!(option_append_unique(Some(&lua), "not valid", "x").is_err());
+
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.opt['path']:append('/usr/include')"]
+        );
+    }
+
+    #[test]
+    fn builds_confirm_dialogs() {
+        assert_eq!(
+            confirm_expr("Save?", "&Yes\n&No", 1).unwrap(),
+            r"vim.fn.confirm('Save?', '&Yes\n&No', 1)"
+        );
+        assert_eq!(
+            confirm_expr("Quit?", "&Yes", 0).unwrap(),
+            "vim.fn.confirm('Quit?', '&Yes', 0)"
+        );
+        assert!(confirm_expr("Save?", "&Yes", -1).is_err());
+    }
+}
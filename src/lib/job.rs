@@ -0,0 +1,291 @@
+use std::convert::TryFrom;
+use std::os::raw::{c_char, c_int};
+use std::sync::{Mutex, OnceLock};
+
+use crate::json::Json;
+use crate::{callback_ref, extract_c_string, lua_quote, Error, Lua, LuaState, Result};
+
+// Ids of the jobs started through `jobstart`, in start order
+static JOBS: OnceLock<Mutex<Vec<i64>>> = OnceLock::new();
+
+fn jobs() -> &'static Mutex<Vec<i64>> {
+    JOBS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Remembers a started job so that `stop_all_jobs` can stop it later
+fn track_job(id: i64) {
+    if let Ok(mut jobs) = jobs().lock() {
+        jobs.push(id);
+    }
+}
+
+/// Forgets every tracked job without stopping it, for `rns.reset`
+pub(crate) fn reset() {
+    if let Ok(mut jobs) = jobs().lock() {
+        jobs.clear();
+    }
+}
+
+/// Builds the `jobstop` calls for every tracked job and forgets them
+fn take_jobstop_cmds() -> Vec<String> {
+    let ids = jobs()
+        .lock()
+        .map(|mut jobs| std::mem::take(&mut *jobs))
+        .unwrap_or_default();
+
+    ids.iter()
+        .map(|id| format!("vim.fn.jobstop({id})"))
+        .collect()
+}
+
+/// Stops every job started through `jobstart`
+///
+/// Jobs that have already exited are skipped by `jobstop` itself.
+#[no_mangle]
+pub extern "C" fn stop_all_jobs() -> c_int {
+    let cmds = take_jobstop_cmds();
+    if cmds.is_empty() {
+        return 1;
+    }
+
+    match crate::run_lua(&cmds.join("\n")) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Builds the `jobstart` call for a command given as a JSON array of strings
+///
+/// The call evaluates to the job id. `on_exit_fn`, if given, names the Lua
+/// function called when the job exits, wrapped in `vim.schedule_wrap` if
+/// `schedule` is set.
+fn jobstart_expr(cmd_json: &str, on_exit_fn: Option<&str>, schedule: bool) -> Result<String> {
+    let cmd = match Json::parse(cmd_json)? {
+        Json::Array(args) if !args.is_empty() => args,
+        _ => return Err(Error::InvalidArgument),
+    };
+
+    if !cmd.iter().all(|arg| matches!(arg, Json::String(_))) {
+        return Err(Error::InvalidArgument);
+    }
+
+    let opts = match on_exit_fn {
+        Some(path) => format!("{{ on_exit = {} }}", callback_ref(path, schedule)?),
+        None => "{}".to_string(),
+    };
+
+    Ok(format!(
+        "vim.fn.jobstart({}, {opts})",
+        Json::Array(cmd).to_lua()
+    ))
+}
+
+/// Starts a job from a command given as a JSON array of strings
+///
+/// `on_exit_fn`, if not null, names the Lua function called when the job
+/// exits. Returns the job id, or 0 if the job could not be started.
+///
+/// # Safety
+///
+/// `cmd_list_json` must be a valid null-terminated C string.
+/// `on_exit_fn` must be either null or a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_jobstart(cmd_list_json: *const c_char, on_exit_fn: *const c_char) -> c_int {
+    let cmd_json = match extract_c_string(cmd_list_json) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let on_exit_fn = if on_exit_fn.is_null() {
+        None
+    } else {
+        match extract_c_string(on_exit_fn) {
+            Ok(s) => Some(s),
+            Err(_) => return 0,
+        }
+    };
+
+    // Ids of 0 and -1 report a job that failed to start
+    match jobstart_expr(&cmd_json, on_exit_fn.as_deref(), false)
+        .and_then(|expr| crate::eval_integer(&expr))
+    {
+        Ok(id) if id > 0 => match c_int::try_from(id) {
+            Ok(id) => {
+                track_job(id.into());
+                id
+            }
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Builds an expression that runs `cmd` in a terminal in a new buffer and evaluates to the buffer
+///
+/// `termopen` is deprecated from Neovim 0.11 in favour of `jobstart` with
+/// `term = true`, so the call is picked at runtime. Either one attaches the
+/// terminal to the current buffer, hence the `nvim_buf_call`.
+fn term_open_expr(cmd: &str) -> Result<String> {
+    if cmd.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+
+    Ok(format!(
+        "(function(cmd) \
+           local buf = vim.api.nvim_create_buf(true, false) \
+           vim.api.nvim_buf_call(buf, function() \
+             if vim.fn.has('nvim-0.11') == 1 then \
+               vim.fn.jobstart(cmd, {{ term = true }}) \
+             else \
+               vim.fn.termopen(cmd) \
+             end \
+           end) \
+           return buf \
+         end)({})",
+        lua_quote(cmd)
+    ))
+}
+
+/// Runs a shell command in a terminal in a new buffer
+///
+/// Returns the handle of the terminal buffer, or 0 on failure.
+///
+/// # Safety
+///
+/// `cmd` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_term_open(cmd: *const c_char) -> c_int {
+    extract_c_string(cmd)
+        .and_then(|cmd| term_open_expr(&cmd))
+        .and_then(|expr| crate::eval_integer(&expr))
+        .and_then(|buf| c_int::try_from(buf).map_err(|_| Error::InvalidArgument))
+        .unwrap_or(0)
+}
+
+/// Registers Lua bindings for job control
+pub fn register_job_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_jobstart(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let cmd_json = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let on_exit_fn = match lua.opt_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        if jobstart_expr(&cmd_json, on_exit_fn.as_deref(), lua.to_boolean(3))
+            .and_then(|expr| lua.eval(&expr))
+            .is_err()
+        {
+            return 0;
+        }
+
+        // Ids of 0 and -1 report a job that failed to start
+        if let Ok(id) = lua.check_integer(-1) {
+            if id > 0 {
+                track_job(id);
+            }
+        }
+
+        1
+    }
+
+    extern "C" fn lua_term_open(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let cmd = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match term_open_expr(&cmd).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_stop_all_jobs(_l: *mut LuaState) -> c_int {
+        stop_all_jobs()
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_jobstart, 0);
+    lua.set_field(-2, "jobstart")?;
+
+    lua.push_cclosure(lua_term_open, 0);
+    lua.set_field(-2, "term_open")?;
+
+    lua.push_cclosure(lua_stop_all_jobs, 0);
+    lua.set_field(-2, "stop_all_jobs")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn builds_jobstart_calls() {
+        assert_eq!(
+            jobstart_expr(r#"["make", "-j4"]"#, None, false).unwrap(),
+            "vim.fn.jobstart({ 'make', '-j4' }, {})"
+        );
+        assert_eq!(
+            jobstart_expr(r#"["ls"]"#, Some("on_done"), false).unwrap(),
+            "vim.fn.jobstart({ 'ls' }, { on_exit = function(...) return on_done(...) end })"
+        );
+
+        assert!(jobstart_expr("[]", None, false).is_err());
+        assert!(jobstart_expr(r#"["ls", 1]"#, None, false).is_err());
+        assert!(jobstart_expr(r#""ls""#, None, false).is_err());
+    }
+
+    #[test]
+    fn takes_every_tracked_job_once_in_start_order() {
+        let _serial = mock::serial();
+        reset();
+        track_job(101);
+        track_job(102);
+        track_job(7);
+
+        assert_eq!(
+            take_jobstop_cmds(),
+            [
+                "vim.fn.jobstop(101)",
+                "vim.fn.jobstop(102)",
+                "vim.fn.jobstop(7)"
+            ]
+        );
+        assert!(take_jobstop_cmds().is_empty());
+    }
+
+    #[test]
+    fn schedules_exit_callbacks_when_asked() {
+        assert_eq!(
+            jobstart_expr(r#"["ls"]"#, Some("on_done"), true).unwrap(),
+            "vim.fn.jobstart({ 'ls' }, { on_exit = \
+             vim.schedule_wrap(function(...) return on_done(...) end) })"
+        );
+    }
+
+    #[test]
+    fn opens_terminals_in_a_new_buffer() {
+        let expr = term_open_expr("htop").unwrap();
+        assert!(expr.contains("vim.api.nvim_create_buf(true, false)"));
+        assert!(expr.ends_with("end)('htop')"));
+        assert!(term_open_expr("").is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! Minimal JSON parsing for translating caller-supplied configuration into
+//! Lua table literals.
+//!
+//! Only what is needed to embed JSON in generated Lua is supported: the full
+//! JSON grammar is accepted, numbers are kept in their textual form, and
+//! `null` becomes `nil`.
+
+use std::convert::TryFrom;
+
+use crate::{lua_quote, Error, Result};
+
+// Deeply nested input is rejected rather than risking stack exhaustion
+const MAX_DEPTH: usize = 128;
+
+/// A parsed JSON value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// A number in its original textual form, already validated against the JSON grammar
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// Object members in document order
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parses a complete JSON document, rejecting trailing input
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut parser = Parser {
+            input: input.as_bytes(),
+            pos: 0,
+        };
+
+        let value = parser.parse_value(0)?;
+        parser.skip_whitespace();
+
+        if parser.pos == parser.input.len() {
+            Ok(value)
+        } else {
+            Err(Error::InvalidJson)
+        }
+    }
+
+    /// Renders the value as a Lua expression
+    pub fn to_lua(&self) -> String {
+        match self {
+            Self::Null => "nil".to_string(),
+            Self::Bool(b) => b.to_string(),
+            Self::Number(n) => n.clone(),
+            Self::String(s) => lua_quote(s),
+            Self::Array(items) => {
+                if items.is_empty() {
+                    return "{}".to_string();
+                }
+
+                let items = items.iter().map(Self::to_lua).collect::<Vec<_>>();
+                format!("{{ {} }}", items.join(", "))
+            }
+            Self::Object(members) => {
+                if members.is_empty() {
+                    return "{}".to_string();
+                }
+
+                let members = members
+                    .iter()
+                    .map(|(key, value)| format!("[{}] = {}", lua_quote(key), value.to_lua()))
+                    .collect::<Vec<_>>();
+                format!("{{ {} }}", members.join(", "))
+            }
+        }
+    }
+}
+
+/// Converts a JSON object or array into a Lua table literal
+///
+/// Scalars at the top level are rejected, since every caller expects a table.
+pub fn json_to_lua_table(json: &str) -> Result<String> {
+    match Json::parse(json)? {
+        value @ (Json::Array(_) | Json::Object(_)) => Ok(value.to_lua()),
+        _ => Err(Error::InvalidJson),
+    }
+}
+
+/// Recursive descent parser over the raw input bytes
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// Consumes `expected` or fails
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(Error::InvalidJson)
+        }
+    }
+
+    /// Consumes the literal keyword `word`
+    fn expect_word(&mut self, word: &str) -> Result<()> {
+        if self.input[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(())
+        } else {
+            Err(Error::InvalidJson)
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Json> {
+        if depth > MAX_DEPTH {
+            return Err(Error::InvalidJson);
+        }
+
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some(b'{') => self.parse_object(depth),
+            Some(b'[') => self.parse_array(depth),
+            Some(b'"') => self.parse_string().map(Json::String),
+            Some(b't') => self.expect_word("true").map(|()| Json::Bool(true)),
+            Some(b'f') => self.expect_word("false").map(|()| Json::Bool(false)),
+            Some(b'n') => self.expect_word("null").map(|()| Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            _ => Err(Error::InvalidJson),
+        }
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<Json> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+
+            self.skip_whitespace();
+            self.expect(b':')?;
+
+            let value = self.parse_value(depth + 1)?;
+            members.push((key, value));
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                _ => return Err(Error::InvalidJson),
+            }
+        }
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<Json> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.parse_value(depth + 1)?);
+
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(Error::InvalidJson),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+
+        loop {
+            let b = self.peek().ok_or(Error::InvalidJson)?;
+            self.pos += 1;
+
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.peek().ok_or(Error::InvalidJson)?;
+                    self.pos += 1;
+
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.parse_unicode_escape()?,
+                        _ => return Err(Error::InvalidJson),
+                    };
+
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                // Unescaped control characters are not allowed inside strings
+                0x00..=0x1f => return Err(Error::InvalidJson),
+                _ => bytes.push(b),
+            }
+        }
+
+        String::from_utf8(bytes).map_err(|_| Error::InvalidJson)
+    }
+
+    /// Parses the `XXXX` following `\u`, combining UTF-16 surrogate pairs
+    fn parse_unicode_escape(&mut self) -> Result<char> {
+        let high = self.parse_hex4()?;
+
+        let code = match high {
+            0xD800..=0xDBFF => {
+                self.expect_word("\\u")?;
+                let low = self.parse_hex4()?;
+                if !(0xDC00..=0xDFFF).contains(&low) {
+                    return Err(Error::InvalidJson);
+                }
+                0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+            }
+            0xDC00..=0xDFFF => return Err(Error::InvalidJson),
+            _ => high,
+        };
+
+        char::try_from(code).map_err(|_| Error::InvalidJson)
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .ok_or(Error::InvalidJson)?;
+        let digits = std::str::from_utf8(digits).map_err(|_| Error::InvalidJson)?;
+
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::InvalidJson);
+        }
+
+        self.pos += 4;
+        u32::from_str_radix(digits, 16).map_err(|_| Error::InvalidJson)
+    }
+
+    fn parse_number(&mut self) -> Result<Json> {
+        let start = self.pos;
+
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+
+        // Integer part: a single zero, or a non-zero digit followed by digits
+        match self.peek() {
+            Some(b'0') => self.pos += 1,
+            Some(b'1'..=b'9') => self.skip_digits(),
+            _ => return Err(Error::InvalidJson),
+        }
+
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !self.peek().is_some_and(|b| b.is_ascii_digit()) {
+                return Err(Error::InvalidJson);
+            }
+            self.skip_digits();
+        }
+
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if !self.peek().is_some_and(|b| b.is_ascii_digit()) {
+                return Err(Error::InvalidJson);
+            }
+            self.skip_digits();
+        }
+
+        let number =
+            std::str::from_utf8(&self.input[start..self.pos]).map_err(|_| Error::InvalidJson)?;
+        Ok(Json::Number(number.to_string()))
+    }
+
+    fn skip_digits(&mut self) {
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_documents() {
+        let value = Json::parse(r#" { "a": [1, -2.5e3, true, null], "b": "x" } "#).unwrap();
+        assert_eq!(
+            value,
+            Json::Object(vec![
+                (
+                    "a".to_string(),
+                    Json::Array(vec![
+                        Json::Number("1".to_string()),
+                        Json::Number("-2.5e3".to_string()),
+                        Json::Bool(true),
+                        Json::Null,
+                    ])
+                ),
+                ("b".to_string(), Json::String("x".to_string())),
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_string_escapes() {
+        assert_eq!(
+            Json::parse(r#""a\"b\n\u00e9\ud83d\ude00""#).unwrap(),
+            Json::String("a\"b\n\u{e9}\u{1f600}".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for input in [
+            "", "{", "[1,]", "01", "1.", "\"\\x\"", "tru", "{} {}", "{'a': 1}",
+        ] {
+            assert!(Json::parse(input).is_err(), "accepted {:?}", input);
+        }
+
+        let nested = |levels: usize| "[".repeat(levels) + &"]".repeat(levels);
+        assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_ok());
+        assert!(Json::parse(&nested(MAX_DEPTH + 2)).is_err());
+    }
+
+    #[test]
+    fn converts_tables_to_lua() {
+        assert_eq!(
+            json_to_lua_table(r#"{"it's": [1, "two"], "e": {}}"#).unwrap(),
+            r"{ ['it\'s'] = { 1, 'two' }, ['e'] = {} }"
+        );
+        assert!(json_to_lua_table("42").is_err());
+    }
+}
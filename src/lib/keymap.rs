@@ -0,0 +1,1375 @@
+use std::collections::HashMap;
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use crate::{extract_c_string, fn_ref, lua_quote, Error, Lua, LuaState, Result, Value};
+
+// Baseline options merged into every mapping created through `emit_keymap`
+static DEFAULT_OPTS: OnceLock<Mutex<KeymapOpts>> = OnceLock::new();
+
+// Whether emitted mappings warn before replacing an existing mapping
+static WARN_OVERWRITE: AtomicBool = AtomicBool::new(false);
+
+// Whether emitted mappings warn when a prefix of their lhs is already mapped
+static WARN_SHADOWED: AtomicBool = AtomicBool::new(false);
+
+// Whether mappings are created with `:map` commands instead of `vim.keymap.set`
+static LEGACY_MAPS: AtomicBool = AtomicBool::new(false);
+
+// `(mode, lhs)` of the buffer-local mappings created through `emit_keymap`, by buffer
+type BufferKeymaps = HashMap<i64, Vec<(String, String)>>;
+static BUFFER_KEYMAPS: OnceLock<Mutex<BufferKeymaps>> = OnceLock::new();
+
+fn buffer_keymaps() -> &'static Mutex<BufferKeymaps> {
+    BUFFER_KEYMAPS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A global mapping made through rns, as listed by `:RnsMaps`
+#[derive(Debug, Clone)]
+struct MapRecord {
+    mode: String,
+    lhs: String,
+    rhs: String,
+    desc: String,
+}
+
+// Global mappings made through rns, in the order they were first made
+static MAP_REGISTRY: OnceLock<Mutex<Vec<MapRecord>>> = OnceLock::new();
+
+fn map_registry() -> &'static Mutex<Vec<MapRecord>> {
+    MAP_REGISTRY.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Options understood by the keymap emitter
+///
+/// Every field is optional so that options supplied by the caller can be
+/// layered over the defaults set with `set_default_map_opts`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeymapOpts {
+    pub silent: Option<bool>,
+    pub noremap: Option<bool>,
+    pub nowait: Option<bool>,
+    pub expr: Option<bool>,
+    pub replace_keycodes: Option<bool>,
+    pub desc: Option<String>,
+}
+
+impl KeymapOpts {
+    /// Parses a packed options string such as `"silent,nowait=false,desc=Save"`
+    ///
+    /// Entries are separated by commas. A bare flag means `true`, and
+    /// `flag=false` explicitly disables it. `remap` asks for a recursive
+    /// mapping and cannot be combined with `noremap`. Since descriptions may
+    /// contain commas, `desc=` consumes the remainder of the string and must
+    /// come last.
+    pub fn parse(opts: &str) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut rest = opts.trim();
+
+        while !rest.is_empty() {
+            if let Some(desc) = rest.strip_prefix("desc=") {
+                parsed.desc = Some(desc.to_string());
+                break;
+            }
+
+            let (entry, tail) = rest.split_once(',').unwrap_or((rest, ""));
+            rest = tail.trim_start();
+
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) => (key.trim(), parse_flag(value.trim())?),
+                None => (entry.trim(), true),
+            };
+
+            match key {
+                "" => {}
+                "silent" => parsed.silent = Some(value),
+                "noremap" => set_noremap(&mut parsed.noremap, value)?,
+                "remap" => set_noremap(&mut parsed.noremap, !value)?,
+                "nowait" => parsed.nowait = Some(value),
+                "expr" => parsed.expr = Some(value),
+                "replace_keycodes" => parsed.replace_keycodes = Some(value),
+                _ => return Err(Error::InvalidArgument),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Parses a packed options string that may be null, meaning no options
+    pub(crate) fn from_ptr(opts: *const c_char) -> Result<Self> {
+        if opts.is_null() {
+            Ok(Self::default())
+        } else {
+            extract_c_string(opts).and_then(|s| Self::parse(&s))
+        }
+    }
+
+    /// Reads options from the Lua value at the given index
+    ///
+    /// Accepts nil for no options, a packed options string, or a table such as
+    /// `{ silent = true, desc = 'Find files' }`. As in a packed string, `remap`
+    /// is the inverse of `noremap` and the two cannot be combined.
+    pub(crate) fn from_lua(lua: &Lua<'_>, idx: c_int) -> Result<Self> {
+        if lua.check_table(idx).is_err() {
+            return Self::parse(&lua.opt_string(idx)?.unwrap_or_default());
+        }
+
+        let mut parsed = Self::default();
+        lua.for_each_pair(idx, |lua| {
+            if !lua.is_string(-2) {
+                return Err(Error::InvalidArgument);
+            }
+
+            let key = lua.check_string(-2)?;
+            match (key.as_str(), lua.check_value(-1)?) {
+                ("silent", Value::Bool(b)) => parsed.silent = Some(b),
+                ("noremap", Value::Bool(b)) => set_noremap(&mut parsed.noremap, b)?,
+                ("remap", Value::Bool(b)) => set_noremap(&mut parsed.noremap, !b)?,
+                ("nowait", Value::Bool(b)) => parsed.nowait = Some(b),
+                ("expr", Value::Bool(b)) => parsed.expr = Some(b),
+                ("replace_keycodes", Value::Bool(b)) => parsed.replace_keycodes = Some(b),
+                ("desc", Value::String(desc)) => parsed.desc = Some(desc),
+                _ => return Err(Error::InvalidArgument),
+            }
+
+            Ok(())
+        })?;
+
+        Ok(parsed)
+    }
+
+    /// Layers `other` over `self`, with fields set in `other` taking precedence
+    #[must_use]
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            silent: other.silent.or(self.silent),
+            noremap: other.noremap.or(self.noremap),
+            nowait: other.nowait.or(self.nowait),
+            expr: other.expr.or(self.expr),
+            replace_keycodes: other.replace_keycodes.or(self.replace_keycodes),
+            desc: other.desc.clone().or_else(|| self.desc.clone()),
+        }
+    }
+
+    /// Renders the options as a Lua table literal for `vim.keymap.set`
+    fn to_lua(&self, buffer: Option<c_int>) -> String {
+        let mut fields = Vec::new();
+
+        if let Some(silent) = self.silent {
+            fields.push(format!("silent = {silent}"));
+        }
+
+        // `vim.keymap.set` is non-recursive by default and only honours `remap`
+        if self.noremap == Some(false) {
+            fields.push("remap = true".to_string());
+        }
+
+        if let Some(nowait) = self.nowait {
+            fields.push(format!("nowait = {nowait}"));
+        }
+
+        if let Some(expr) = self.expr {
+            fields.push(format!("expr = {expr}"));
+        }
+
+        // Without `replace_keycodes`, termcodes returned by an expression
+        // mapping are inserted literally, so default it on unless disabled
+        if self.expr == Some(true) {
+            let replace_keycodes = self.replace_keycodes.unwrap_or(true);
+            fields.push(format!("replace_keycodes = {replace_keycodes}"));
+        }
+
+        if let Some(desc) = &self.desc {
+            fields.push(format!("desc = {}", lua_quote(desc)));
+        }
+
+        if let Some(buffer) = buffer {
+            fields.push(format!("buffer = {buffer}"));
+        }
+
+        if fields.is_empty() {
+            "{}".to_string()
+        } else {
+            format!("{{ {} }}", fields.join(", "))
+        }
+    }
+}
+
+/// Sets the `noremap` option, rejecting a second `noremap` or `remap` entry
+///
+/// Allowing both would leave it to entry order whether a mapping is recursive.
+fn set_noremap(slot: &mut Option<bool>, noremap: bool) -> Result<()> {
+    if slot.is_some() {
+        return Err(Error::InvalidArgument);
+    }
+
+    *slot = Some(noremap);
+    Ok(())
+}
+
+/// Checks that `mode` is a single mapping mode, as the `:map` family spells them
+///
+/// Valid modes are `n`, `i`, `v`, `x`, `s`, `o`, `c`, `t`, and `l`, the empty
+/// string for `:map`, and `!` for `:map!`. Unknown modes are reported through
+/// `vim.notify`, since they would otherwise surface as an obscure error about
+/// a nonexistent command such as `:zmap`.
+pub(crate) fn validate_mode(mode: &str) -> Result<()> {
+    if matches!(
+        mode,
+        "" | "n" | "i" | "v" | "x" | "s" | "o" | "c" | "t" | "l" | "!"
+    ) {
+        return Ok(());
+    }
+
+    let msg = format!("rns: invalid map mode '{mode}'");
+    let _ = crate::run_lua(&format!(
+        "vim.notify({}, vim.log.levels.ERROR)",
+        lua_quote(&msg)
+    ));
+    Err(Error::InvalidArgument)
+}
+
+/// Parses a boolean flag value from a packed options string
+pub(crate) fn parse_flag(value: &str) -> Result<bool> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+/// The right-hand side of a mapping
+#[derive(Debug, Clone)]
+pub enum Rhs {
+    /// Keys or a command string, as written in a `:map` command
+    Command(String),
+    /// Path to a Lua function called when the mapping is triggered
+    Function(String),
+}
+
+impl Rhs {
+    /// Renders the rhs as a Lua expression for `vim.keymap.set`
+    fn to_lua(&self) -> Result<String> {
+        match self {
+            Self::Command(rhs) => Ok(lua_quote(rhs)),
+            Self::Function(path) => fn_ref(path),
+        }
+    }
+}
+
+/// A single mapping to be created by `emit_keymap`
+#[derive(Debug, Clone)]
+pub struct KeymapSpec {
+    pub mode: String,
+    pub lhs: String,
+    pub rhs: Rhs,
+    pub opts: KeymapOpts,
+    /// Buffer the mapping is local to, or `None` for a global mapping
+    pub buffer: Option<c_int>,
+}
+
+impl KeymapSpec {
+    /// Renders the `maparg` checks reporting mappings this one conflicts with
+    ///
+    /// Empty unless overwrite or shadowing warnings are enabled. The checks
+    /// are plain Lua, so they run before the mapping in either emit mode.
+    fn conflict_checks(&self) -> Vec<String> {
+        let (mode, lhs) = (lua_quote(&self.mode), lua_quote(&self.lhs));
+
+        let mut code = Vec::new();
+
+        if WARN_OVERWRITE.load(Ordering::Relaxed) {
+            code.push(format!(
+                "if vim.fn.maparg({lhs}, {mode}) ~= '' then \
+                 vim.notify('rns: overwriting existing mapping for ' .. {lhs}, vim.log.levels.WARN) \
+                 end"
+            ));
+        }
+
+        let prefixes = if WARN_SHADOWED.load(Ordering::Relaxed) {
+            lhs_prefixes(&self.lhs)
+        } else {
+            Vec::new()
+        };
+
+        if !prefixes.is_empty() {
+            let prefixes = prefixes
+                .iter()
+                .map(|prefix| lua_quote(prefix))
+                .collect::<Vec<_>>();
+            code.push(format!(
+                "for _, prefix in ipairs({{ {} }}) do \
+                 if vim.fn.maparg(prefix, {mode}) ~= '' then \
+                 vim.notify('rns: ' .. {lhs} .. ' is shadowed by existing mapping ' .. prefix, vim.log.levels.WARN) \
+                 end \
+                 end",
+                prefixes.join(", ")
+            ));
+        }
+
+        code
+    }
+
+    /// Renders the `vim.keymap.set` call for this mapping
+    ///
+    /// The caller's options are merged over the current defaults. With
+    /// overwrite or shadowing warnings enabled, the call is preceded by
+    /// `maparg` checks that report conflicting mappings.
+    fn to_lua(&self) -> Result<String> {
+        validate_mode(&self.mode)?;
+
+        let opts = default_opts().merge(&self.opts);
+        let (mode, lhs) = (lua_quote(&self.mode), lua_quote(&self.lhs));
+
+        let mut code = self.conflict_checks();
+        code.push(format!(
+            "vim.keymap.set({mode}, {lhs}, {}, {})",
+            self.rhs.to_lua()?,
+            opts.to_lua(self.buffer)
+        ));
+
+        Ok(code.join(" "))
+    }
+
+    /// Renders the `:map` command for this mapping, for Neovim without `vim.keymap`
+    ///
+    /// Options become `<silent>`, `<nowait>`, `<expr>`, and `<buffer>`
+    /// modifiers. The command path cannot call Lua functions or map for a
+    /// buffer other than the current one, so such mappings are rejected, and
+    /// descriptions are dropped since old versions have nowhere to keep them.
+    fn to_legacy_cmd(&self) -> Result<String> {
+        validate_mode(&self.mode)?;
+
+        let opts = default_opts().merge(&self.opts);
+
+        let rhs = match &self.rhs {
+            Rhs::Command(rhs) if rhs.is_empty() => "<Nop>".to_string(),
+            Rhs::Command(rhs) => rhs.replace('|', "<Bar>"),
+            Rhs::Function(_) => return Err(Error::InvalidArgument),
+        };
+
+        if self.lhs.is_empty() {
+            return Err(Error::InvalidArgument);
+        }
+        let lhs = self.lhs.replace(' ', "<Space>").replace('|', "<Bar>");
+
+        let map = if opts.noremap == Some(false) {
+            "map"
+        } else {
+            "noremap"
+        };
+        let mut cmd = match self.mode.as_str() {
+            "!" => format!("{map}!"),
+            mode => format!("{mode}{map}"),
+        };
+
+        match self.buffer {
+            Some(0) => cmd.push_str(" <buffer>"),
+            Some(_) => return Err(Error::InvalidArgument),
+            None => {}
+        }
+
+        for (enabled, modifier) in [
+            (opts.silent, "<silent>"),
+            (opts.nowait, "<nowait>"),
+            (opts.expr, "<expr>"),
+        ] {
+            if enabled == Some(true) {
+                cmd.push(' ');
+                cmd.push_str(modifier);
+            }
+        }
+
+        Ok(format!("{cmd} {lhs} {rhs}"))
+    }
+}
+
+/// Returns every proper prefix of `lhs`, splitting it into keys
+///
+/// Special keys written in angle brackets, such as `<leader>` or `<C-x>`,
+/// count as a single key, so `<leader>ff` yields `<leader>` and `<leader>f`.
+fn lhs_prefixes(lhs: &str) -> Vec<String> {
+    let mut ends = Vec::new();
+    let mut pos = 0;
+
+    while pos < lhs.len() {
+        let rest = &lhs[pos..];
+        let special = rest
+            .strip_prefix('<')
+            .and_then(|inner| {
+                inner
+                    .find('>')
+                    .filter(|&end| end > 0 && !inner[..end].contains('<'))
+            })
+            .map(|end| end + 2);
+
+        pos += special.unwrap_or_else(|| rest.chars().next().map_or(1, char::len_utf8));
+        ends.push(pos);
+    }
+
+    ends.pop();
+    ends.into_iter().map(|end| lhs[..end].to_string()).collect()
+}
+
+/// Builds a Lua expression for `lhs` with `<leader>` and `<localleader>` expanded
+///
+/// Like Neovim, an unset or empty `g:mapleader` or `g:maplocalleader` falls
+/// back to a backslash. Key names are matched case-insensitively.
+fn resolve_leader_expr(lhs: &str) -> String {
+    format!(
+        "(function(lhs) \
+           local function leader(name) \
+             local value = vim.g[name] \
+             if value == nil or value == '' then return '\\\\' end \
+             return value \
+           end \
+           return (lhs:gsub('<[^<>]+>', function(key) \
+             local lower = key:lower() \
+             if lower == '<leader>' then return leader('mapleader') end \
+             if lower == '<localleader>' then return leader('maplocalleader') end \
+           end)) \
+         end)({})",
+        lua_quote(lhs)
+    )
+}
+
+/// Returns a copy of the current default keymap options
+pub(crate) fn default_opts() -> KeymapOpts {
+    DEFAULT_OPTS
+        .get_or_init(|| Mutex::new(KeymapOpts::default()))
+        .lock()
+        .map(|opts| opts.clone())
+        .unwrap_or_default()
+}
+
+/// Replaces the default options merged into every subsequent mapping
+pub fn set_default_opts(opts: KeymapOpts) {
+    let lock = DEFAULT_OPTS.get_or_init(|| Mutex::new(KeymapOpts::default()));
+    if let Ok(mut defaults) = lock.lock() {
+        *defaults = opts;
+    }
+}
+
+/// Clears the default keymap options and forgets tracked and recorded mappings, for `rns.reset`
+pub(crate) fn reset() {
+    set_default_opts(KeymapOpts::default());
+
+    if let Ok(mut tracked) = buffer_keymaps().lock() {
+        tracked.clear();
+    }
+
+    if let Ok(mut registry) = map_registry().lock() {
+        registry.clear();
+    }
+}
+
+/// Records a global mapping for `:RnsMaps`, replacing an earlier one of the same mode and lhs
+fn record_map(mode: &str, lhs: &str, rhs: &str, desc: &str) {
+    let record = MapRecord {
+        mode: mode.to_string(),
+        lhs: lhs.to_string(),
+        rhs: rhs.to_string(),
+        desc: desc.to_string(),
+    };
+
+    if let Ok(mut registry) = map_registry().lock() {
+        match registry
+            .iter_mut()
+            .find(|existing| existing.mode == mode && existing.lhs == lhs)
+        {
+            Some(existing) => *existing = record,
+            None => registry.push(record),
+        }
+    }
+}
+
+/// Renders the recorded mappings as aligned `mode lhs rhs desc` lines
+fn map_registry_lines() -> Vec<String> {
+    let registry = match map_registry().lock() {
+        Ok(registry) => registry.clone(),
+        Err(_) => Vec::new(),
+    };
+
+    if registry.is_empty() {
+        return vec!["No mappings recorded".to_string()];
+    }
+
+    let width = |field: fn(&MapRecord) -> &str| {
+        registry
+            .iter()
+            .map(|record| field(record).chars().count())
+            .max()
+            .unwrap_or(0)
+    };
+    let (mode_width, lhs_width, rhs_width) =
+        (width(|r| &r.mode), width(|r| &r.lhs), width(|r| &r.rhs));
+
+    registry
+        .iter()
+        .map(|r| {
+            format!(
+                "{:mode_width$}  {:lhs_width$}  {:rhs_width$}  {}",
+                r.mode, r.lhs, r.rhs, r.desc
+            )
+            .trim_end()
+            .to_string()
+        })
+        .collect()
+}
+
+/// Opens a scratch buffer in a new split listing the recorded mappings
+fn show_map_registry() -> Result<()> {
+    let lines = map_registry_lines()
+        .iter()
+        .map(|line| lua_quote(line))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    crate::run_lua(&format!(
+        "local buf = vim.api.nvim_create_buf(false, true) \
+         vim.api.nvim_buf_set_lines(buf, 0, -1, false, {{ {lines} }}) \
+         vim.bo[buf].modifiable = false \
+         vim.bo[buf].bufhidden = 'wipe' \
+         vim.cmd('botright split') \
+         vim.api.nvim_win_set_buf(0, buf)"
+    ))
+}
+
+/// Creates the `:RnsMaps` user command listing the mappings made through rns
+///
+/// The command calls back into the module directly, so it always shows the
+/// registry as it is when run. Nothing is created outside of Neovim.
+fn create_maps_command(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_show_map_registry(_l: *mut LuaState) -> c_int {
+        let _ = show_map_registry();
+        0
+    }
+
+    if !lua.is_neovim_host() {
+        return Ok(());
+    }
+
+    lua.ensure_stack(2)?;
+    lua.eval(
+        "function(show) \
+           vim.api.nvim_create_user_command('RnsMaps', show, \
+             { desc = 'List the mappings made through rns' }) \
+         end",
+    )?;
+    lua.push_cclosure(lua_show_map_registry, 0);
+    lua.call(1)
+}
+
+/// Remembers a buffer-local mapping so that `clear_buffer_keymaps` can remove it
+///
+/// Buffer 0 is resolved to the current buffer, since that is the one the
+/// mapping was created in.
+fn track_buffer_keymap(buffer: c_int, mode: &str, lhs: &str) {
+    let buffer = match buffer {
+        0 => crate::eval_integer("vim.api.nvim_get_current_buf()").unwrap_or(0),
+        n => i64::from(n),
+    };
+
+    if let Ok(mut tracked) = buffer_keymaps().lock() {
+        let maps = tracked.entry(buffer).or_default();
+        let key = (mode.to_string(), lhs.to_string());
+        if !maps.contains(&key) {
+            maps.push(key);
+        }
+    }
+}
+
+/// Removes every mapping created through `emit_keymap` for `buffer`
+///
+/// Mappings that were already removed, or whose buffer has been wiped, are
+/// skipped. A `buffer` of 0 refers to the current buffer.
+fn clear_buffer_keymaps(buffer: i64) -> Result<()> {
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    let buffer = if buffer == 0 {
+        crate::eval_integer("vim.api.nvim_get_current_buf()")?
+    } else {
+        buffer
+    };
+
+    let maps = buffer_keymaps()
+        .lock()
+        .map(|mut tracked| tracked.remove(&buffer).unwrap_or_default())
+        .unwrap_or_default();
+    if maps.is_empty() {
+        return Ok(());
+    }
+
+    let code = maps
+        .iter()
+        .map(|(mode, lhs)| {
+            format!(
+                "pcall(vim.api.nvim_buf_del_keymap, {buffer}, {}, {})",
+                lua_quote(mode),
+                lua_quote(lhs)
+            )
+        })
+        .collect::<Vec<_>>();
+    crate::run_lua(&code.join("\n"))
+}
+
+/// Creates a mapping through `vim.keymap.set`, or a `:map` command in legacy mode
+///
+/// Buffer-local mappings are tracked so they can be removed again with
+/// `clear_buffer_keymaps`, and global ones are recorded for `:RnsMaps`.
+pub fn emit_keymap(spec: &KeymapSpec) -> Result<()> {
+    if LEGACY_MAPS.load(Ordering::Relaxed) {
+        let cmd = spec.to_legacy_cmd()?;
+        let checks = spec.conflict_checks();
+        if !checks.is_empty() {
+            crate::run_lua(&checks.join(" "))?;
+        }
+        crate::run_cmd(&cmd)?;
+    } else {
+        crate::run_lua(&spec.to_lua()?)?;
+    }
+
+    match spec.buffer {
+        Some(buffer) => track_buffer_keymap(buffer, &spec.mode, &spec.lhs),
+        None => {
+            let rhs = match &spec.rhs {
+                Rhs::Command(rhs) | Rhs::Function(rhs) => rhs,
+            };
+            // Record the description the mapping actually got, which may
+            // come from the defaults
+            let opts = default_opts().merge(&spec.opts);
+            let desc = opts.desc.as_deref().unwrap_or_default();
+            record_map(&spec.mode, &spec.lhs, rhs, desc);
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates a mapping that always carries a description
+///
+/// Descriptions are what which-key and `:map` display, so this is the
+/// preferred entry point for user-facing mappings.
+fn map_desc(mode: String, lhs: String, rhs: String, desc: String) -> Result<()> {
+    let spec = KeymapSpec {
+        mode,
+        lhs,
+        rhs: Rhs::Command(rhs),
+        opts: KeymapOpts {
+            desc: Some(desc),
+            ..KeymapOpts::default()
+        },
+        buffer: None,
+    };
+
+    emit_keymap(&spec)
+}
+
+/// Creates a keymap with a description
+///
+/// # Safety
+///
+/// `mode`, `lhs`, `rhs`, and `desc` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_map_desc(
+    mode: *const c_char,
+    lhs: *const c_char,
+    rhs: *const c_char,
+    desc: *const c_char,
+) -> c_int {
+    match (
+        extract_c_string(mode),
+        extract_c_string(lhs),
+        extract_c_string(rhs),
+        extract_c_string(desc),
+    ) {
+        (Ok(mode), Ok(lhs), Ok(rhs), Ok(desc)) => match map_desc(mode, lhs, rhs, desc) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Creates an expression mapping
+///
+/// # Safety
+///
+/// `mode`, `lhs`, `rhs` must be valid null-terminated C strings.
+/// `opts` must be either null or a valid C string in the packed options format.
+#[no_mangle]
+pub extern "C" fn nvim_set_keymap_expr(
+    mode: *const c_char,
+    lhs: *const c_char,
+    rhs: *const c_char,
+    opts: *const c_char,
+) -> c_int {
+    match (
+        extract_c_string(mode),
+        extract_c_string(lhs),
+        extract_c_string(rhs),
+        KeymapOpts::from_ptr(opts),
+    ) {
+        (Ok(mode), Ok(lhs), Ok(rhs), Ok(opts)) => match map_expr(mode, lhs, rhs, opts) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Creates a mapping whose rhs is evaluated as an expression
+fn map_expr(mode: String, lhs: String, rhs: String, opts: KeymapOpts) -> Result<()> {
+    let spec = KeymapSpec {
+        mode,
+        lhs,
+        rhs: Rhs::Command(rhs),
+        opts: KeymapOpts {
+            expr: Some(true),
+            ..opts
+        },
+        buffer: None,
+    };
+
+    emit_keymap(&spec)
+}
+
+/// Creates a mapping that calls a Lua function
+///
+/// `fn_path` is a function path such as `require('telescope.builtin').find_files`,
+/// resolved when the mapping is triggered.
+fn map_fn(mode: String, lhs: String, fn_path: String, opts: KeymapOpts) -> Result<()> {
+    let spec = KeymapSpec {
+        mode,
+        lhs,
+        rhs: Rhs::Function(fn_path),
+        opts,
+        buffer: None,
+    };
+
+    emit_keymap(&spec)
+}
+
+/// Creates a keymap that calls a Lua function
+///
+/// # Safety
+///
+/// `mode`, `lhs`, and `fn_path` must be valid null-terminated C strings.
+/// `opts` must be either null or a valid C string in the packed options format.
+#[no_mangle]
+pub extern "C" fn nvim_map_fn(
+    mode: *const c_char,
+    lhs: *const c_char,
+    fn_path: *const c_char,
+    opts: *const c_char,
+) -> c_int {
+    match (
+        extract_c_string(mode),
+        extract_c_string(lhs),
+        extract_c_string(fn_path),
+        KeymapOpts::from_ptr(opts),
+    ) {
+        (Ok(mode), Ok(lhs), Ok(fn_path), Ok(opts)) => match map_fn(mode, lhs, fn_path, opts) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Sets the default options merged into every subsequent mapping
+///
+/// Options passed to an individual mapping take precedence over these, so
+/// a default of `silent` can still be overridden with `silent=false`.
+///
+/// # Safety
+///
+/// `opts` must be a valid null-terminated C string in the packed options format.
+#[no_mangle]
+pub extern "C" fn nvim_set_default_map_opts(opts: *const c_char) -> c_int {
+    match extract_c_string(opts).and_then(|s| KeymapOpts::parse(&s)) {
+        Ok(parsed) => {
+            set_default_opts(parsed);
+            1
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Enables or disables warnings when a mapping replaces an existing one
+#[no_mangle]
+pub extern "C" fn nvim_set_warn_overwrite(enabled: c_int) -> c_int {
+    WARN_OVERWRITE.store(enabled != 0, Ordering::Relaxed);
+    1
+}
+
+/// Enables or disables warnings when a prefix of a new mapping is already mapped
+///
+/// Such a prefix makes Neovim wait for `timeoutlen` or fire the shorter
+/// mapping instead, which is easy to miss.
+#[no_mangle]
+pub extern "C" fn nvim_set_warn_shadowed(enabled: c_int) -> c_int {
+    WARN_SHADOWED.store(enabled != 0, Ordering::Relaxed);
+    1
+}
+
+/// Enables or disables creating mappings with `:map` commands
+///
+/// This is for Neovim versions older than 0.7, which lack `vim.keymap.set`.
+#[no_mangle]
+pub extern "C" fn nvim_set_legacy_maps(enabled: c_int) -> c_int {
+    LEGACY_MAPS.store(enabled != 0, Ordering::Relaxed);
+    1
+}
+
+/// Removes every buffer-local mapping created through rns for the given buffer
+#[no_mangle]
+pub extern "C" fn nvim_clear_buffer_keymaps(buffer: c_int) -> c_int {
+    match clear_buffer_keymaps(buffer.into()) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Registers Lua bindings for keymap functions
+pub fn register_keymap_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_set_default_map_opts(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match KeymapOpts::from_lua(&lua, 1) {
+            Ok(parsed) => {
+                set_default_opts(parsed);
+                1
+            }
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_warn_overwrite(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        nvim_set_warn_overwrite(c_int::from(lua.to_boolean(1)))
+    }
+
+    extern "C" fn lua_clear_buffer_keymaps(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.check_integer(1).and_then(clear_buffer_keymaps) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_resolve_leader(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let lhs = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match lua.eval(&resolve_leader_expr(&lhs)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_legacy_maps(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        nvim_set_legacy_maps(c_int::from(lua.to_boolean(1)))
+    }
+
+    extern "C" fn lua_set_warn_shadowed(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        nvim_set_warn_shadowed(c_int::from(lua.to_boolean(1)))
+    }
+
+    extern "C" fn lua_map_desc(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let mode = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let lhs = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let rhs = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let desc = match lua.check_string(4) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match map_desc(mode, lhs, rhs, desc) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_keymap_expr(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let mode = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let lhs = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let rhs = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let opts = match KeymapOpts::from_lua(&lua, 4) {
+            Ok(opts) => opts,
+            Err(_) => return 0,
+        };
+
+        match map_expr(mode, lhs, rhs, opts) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_map_fn(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let mode = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let lhs = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let fn_path = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let opts = match KeymapOpts::from_lua(&lua, 4) {
+            Ok(opts) => opts,
+            Err(_) => return 0,
+        };
+
+        match map_fn(mode, lhs, fn_path, opts) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    create_maps_command(lua)?;
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_set_default_map_opts, 0);
+    lua.set_field(-2, "set_default_map_opts")?;
+
+    lua.push_cclosure(lua_set_warn_overwrite, 0);
+    lua.set_field(-2, "set_warn_overwrite")?;
+
+    lua.push_cclosure(lua_set_warn_shadowed, 0);
+    lua.set_field(-2, "set_warn_shadowed")?;
+
+    lua.push_cclosure(lua_set_legacy_maps, 0);
+    lua.set_field(-2, "set_legacy_maps")?;
+
+    lua.push_cclosure(lua_clear_buffer_keymaps, 0);
+    lua.set_field(-2, "clear_buffer_keymaps")?;
+
+    lua.push_cclosure(lua_resolve_leader, 0);
+    lua.set_field(-2, "resolve_leader")?;
+
+    lua.push_cclosure(lua_map_desc, 0);
+    lua.set_field(-2, "map_desc")?;
+
+    lua.push_cclosure(lua_set_keymap_expr, 0);
+    lua.set_field(-2, "set_keymap_expr")?;
+
+    lua.push_cclosure(lua_map_fn, 0);
+    lua.set_field(-2, "map_fn")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn parses_packed_options() {
+        let opts = KeymapOpts::parse("silent, nowait=false, remap, desc=Save, all").unwrap();
+        assert_eq!(
+            opts,
+            KeymapOpts {
+                silent: Some(true),
+                noremap: Some(false),
+                nowait: Some(false),
+                desc: Some("Save, all".to_string()),
+                ..KeymapOpts::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_or_unknown_options() {
+        assert!(KeymapOpts::parse("noremap,remap").is_err());
+        assert!(KeymapOpts::parse("silent=yes").is_err());
+        assert!(KeymapOpts::parse("loud").is_err());
+    }
+
+    #[test]
+    fn merge_prefers_the_override() {
+        let base = KeymapOpts::parse("silent,desc=Base").unwrap();
+        let merged = base.merge(&KeymapOpts::parse("silent=false,nowait").unwrap());
+        assert_eq!(merged.silent, Some(false));
+        assert_eq!(merged.nowait, Some(true));
+        assert_eq!(merged.desc.as_deref(), Some("Base"));
+    }
+
+    #[test]
+    fn expr_mappings_replace_keycodes_by_default() {
+        let opts = KeymapOpts::parse("expr,noremap=false").unwrap();
+        assert_eq!(
+            opts.to_lua(Some(3)),
+            "{ remap = true, expr = true, replace_keycodes = true, buffer = 3 }"
+        );
+        assert_eq!(KeymapOpts::default().to_lua(None), "{}");
+    }
+
+    fn spec(mode: &str, lhs: &str, rhs: Rhs, opts: KeymapOpts) -> KeymapSpec {
+        KeymapSpec {
+            mode: mode.to_string(),
+            lhs: lhs.to_string(),
+            rhs,
+            opts,
+            buffer: None,
+        }
+    }
+
+    #[test]
+    fn function_rhs_is_emitted_as_a_function_ref() {
+        let _serial = mock::serial();
+        let find_files = Rhs::Function("require('telescope.builtin').find_files".to_string());
+        assert_eq!(
+            spec("n", "<leader>ff", find_files, KeymapOpts::default())
+                .to_lua()
+                .unwrap(),
+            "vim.keymap.set('n', '<leader>ff', \
+             function(...) return require('telescope.builtin').find_files(...) end, {})"
+        );
+
+        let bad = Rhs::Function("os.execute('rm')".to_string());
+        assert!(spec("n", "x", bad, KeymapOpts::default()).to_lua().is_err());
+    }
+
+    #[test]
+    fn warns_about_overwrites_only_when_enabled() {
+        let _serial = mock::serial();
+        let save = spec(
+            "n",
+            "<C-s>",
+            Rhs::Command(":w<CR>".to_string()),
+            KeymapOpts::default(),
+        );
+        let quiet = save.to_lua().unwrap();
+        nvim_set_warn_overwrite(1);
+        let warned = save.to_lua().unwrap();
+        nvim_set_warn_overwrite(0);
+
+        assert_eq!(quiet, "vim.keymap.set('n', '<C-s>', ':w<CR>', {})");
+        assert_eq!(
+            warned,
+            "if vim.fn.maparg('<C-s>', 'n') ~= '' then \
+             vim.notify('rns: overwriting existing mapping for ' .. '<C-s>', vim.log.levels.WARN) \
+             end vim.keymap.set('n', '<C-s>', ':w<CR>', {})"
+        );
+    }
+
+    #[test]
+    fn splits_lhs_into_prefixes() {
+        assert_eq!(lhs_prefixes("<leader>ff"), ["<leader>", "<leader>f"]);
+        assert_eq!(lhs_prefixes("gcc"), ["g", "gc"]);
+        assert_eq!(lhs_prefixes("<a"), ["<"]);
+        assert!(lhs_prefixes("x").is_empty());
+    }
+
+    #[test]
+    fn warns_when_a_prefix_shadows_the_mapping() {
+        let _serial = mock::serial();
+        let find = spec(
+            "n",
+            "<leader>ff",
+            Rhs::Command(":Telescope find_files<CR>".to_string()),
+            KeymapOpts::default(),
+        );
+        let quiet = find.to_lua().unwrap();
+        nvim_set_warn_shadowed(1);
+        let warned = find.to_lua().unwrap();
+        nvim_set_warn_shadowed(0);
+
+        assert!(!quiet.contains("shadowed"));
+        assert_eq!(
+            warned,
+            "for _, prefix in ipairs({ '<leader>', '<leader>f' }) do \
+             if vim.fn.maparg(prefix, 'n') ~= '' then \
+             vim.notify('rns: ' .. '<leader>ff' .. ' is shadowed by existing mapping ' .. prefix, vim.log.levels.WARN) \
+             end \
+             end \
+             vim.keymap.set('n', '<leader>ff', ':Telescope find_files<CR>', {})"
+        );
+    }
+
+    #[test]
+    fn reads_an_opts_table_into_a_spec() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        mock::push(mock::table([
+            ("noremap", true.into()),
+            ("silent", true.into()),
+            ("desc", "Find files".into()),
+        ]));
+
+        let opts = KeymapOpts::from_lua(&lua, 1).unwrap();
+        assert_eq!(
+            opts,
+            KeymapOpts {
+                silent: Some(true),
+                noremap: Some(true),
+                desc: Some("Find files".to_string()),
+                ..KeymapOpts::default()
+            }
+        );
+
+        let spec = spec(
+            "n",
+            "<leader>ff",
+            Rhs::Command(":Files<CR>".to_string()),
+            opts,
+        );
+        assert_eq!(
+            spec.to_lua().unwrap(),
+            "vim.keymap.set('n', '<leader>ff', ':Files<CR>', { silent = true, desc = 'Find files' })"
+        );
+    }
+
+    #[test]
+    fn from_lua_accepts_nil_and_strings_but_not_unknown_keys() {
+        let lua = mock::lua();
+        assert_eq!(
+            KeymapOpts::from_lua(&lua, 1).unwrap(),
+            KeymapOpts::default()
+        );
+
+        mock::push(mock::string("silent"));
+        assert_eq!(KeymapOpts::from_lua(&lua, 1).unwrap().silent, Some(true));
+
+        mock::push(mock::table([("loud", true.into())]));
+        assert!(KeymapOpts::from_lua(&lua, 2).is_err());
+
+        mock::push(mock::table([("silent", "yes".into())]));
+        assert!(KeymapOpts::from_lua(&lua, 3).is_err());
+    }
+
+    #[test]
+    fn builds_legacy_map_commands() {
+        let _serial = mock::serial();
+        let opts = KeymapOpts::parse("silent").unwrap();
+        let cmd = spec("n", "a b", Rhs::Command(":echo 1|echo 2".to_string()), opts);
+        assert_eq!(
+            cmd.to_legacy_cmd().unwrap(),
+            "nnoremap <silent> a<Space>b :echo 1<Bar>echo 2"
+        );
+
+        let nop = spec("!", "x", Rhs::Command(String::new()), KeymapOpts::default());
+        assert_eq!(nop.to_legacy_cmd().unwrap(), "noremap! x <Nop>");
+
+        let func = spec(
+            "n",
+            "x",
+            Rhs::Function("vim.cmd.w".to_string()),
+            KeymapOpts::default(),
+        );
+        assert!(func.to_legacy_cmd().is_err());
+    }
+
+    #[test]
+    fn clears_only_the_given_buffers_keymaps() {
+        let _serial = mock::serial();
+        mock::lua();
+        for (buffer, lhs) in [(2, "gd"), (2, "K"), (3, "gd")] {
+            emit_keymap(&KeymapSpec {
+                buffer: Some(buffer),
+                ..spec(
+                    "n",
+                    lhs,
+                    Rhs::Command(":help<CR>".to_string()),
+                    KeymapOpts::default(),
+                )
+            })
+            .unwrap();
+        }
+
+        let before = mock::commands().len();
+        assert_eq!(nvim_clear_buffer_keymaps(2), 1);
+        assert_eq!(nvim_clear_buffer_keymaps(2), 1);
+        assert_eq!(nvim_clear_buffer_keymaps(3), 1);
+        assert_eq!(nvim_clear_buffer_keymaps(-1), 0);
+
+        assert_eq!(
+            mock::commands().split_off(before),
+            [
+                "lua pcall(vim.api.nvim_buf_del_keymap, 2, 'n', 'gd')\n\
+                 pcall(vim.api.nvim_buf_del_keymap, 2, 'n', 'K')",
+                "lua pcall(vim.api.nvim_buf_del_keymap, 3, 'n', 'gd')",
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_only_known_map_modes() {
+        mock::lua();
+        for mode in ["", "n", "i", "v", "x", "s", "o", "c", "t", "l", "!"] {
+            assert!(validate_mode(mode).is_ok(), "rejected {:?}", mode);
+        }
+        assert!(mock::commands().is_empty());
+
+        assert!(validate_mode("z").is_err());
+        assert!(validate_mode("xyz").is_err());
+        assert_eq!(
+            mock::commands(),
+            [
+                format!(
+                    "lua vim.notify({}, vim.log.levels.ERROR)",
+                    lua_quote("rns: invalid map mode 'z'")
+                ),
+                format!(
+                    "lua vim.notify({}, vim.log.levels.ERROR)",
+                    lua_quote("rns: invalid map mode 'xyz'")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_leaders_at_runtime() {
+        let expr = resolve_leader_expr("<leader>f");
+        assert!(expr.ends_with("end)('<leader>f')"));
+        assert!(expr.contains("if lower == '<leader>' then return leader('mapleader') end"));
+        assert!(expr.contains("if value == nil or value == '' then return '\\\\' end"));
+
+        // With `mapleader = ' '`, `<leader>f` becomes a space followed by `f`
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_keymap_functions(&lua).unwrap();
+        let resolve_leader = mock::get(-1).field("resolve_leader");
+
+        mock::set_result(&format!("return {expr}"), " f".into());
+        let results = mock::call(&resolve_leader, ["<leader>f".into()]);
+        assert_eq!(results[0].as_str(), Some(" f"));
+    }
+
+    #[test]
+    fn records_global_mappings_with_their_merged_description() {
+        let _serial = mock::serial();
+        mock::lua();
+        reset();
+        set_default_opts(KeymapOpts::parse("silent,desc=rns").unwrap());
+
+        let save = KeymapOpts::parse("desc=Save").unwrap();
+        emit_keymap(&spec(
+            "n",
+            "<C-s>",
+            Rhs::Command(":w<CR>".to_string()),
+            save,
+        ))
+        .unwrap();
+        let quit = Rhs::Command(":q<CR>".to_string());
+        emit_keymap(&spec("n", "<leader>q", quit, KeymapOpts::default())).unwrap();
+
+        assert_eq!(
+            map_registry_lines(),
+            ["n  <C-s>      :w<CR>  Save", "n  <leader>q  :q<CR>  rns"]
+        );
+        reset();
+    }
+
+    #[test]
+    fn maps_command_lists_the_registry() {
+        extern "C" fn create_user_command(_l: *mut LuaState) -> c_int {
+            // Run the command right away, as if `:RnsMaps` had been typed
+            let show = mock::get(1);
+            mock::call(&show, []);
+            0
+        }
+
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        reset();
+        record_map("n", "<C-s>", ":w<CR>", "Save");
+
+        mock::set_global("vim", mock::table([]));
+        mock::set_result(
+            "return function(show) \
+               vim.api.nvim_create_user_command('RnsMaps', show, \
+                 { desc = 'List the mappings made through rns' }) \
+             end",
+            mock::MockValue::Function(create_user_command, Default::default()),
+        );
+        create_maps_command(&lua).unwrap();
+
+        let commands = mock::commands();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].contains(
+            "vim.api.nvim_buf_set_lines(buf, 0, -1, false, { 'n  <C-s>  :w<CR>  Save' })"
+        ));
+        assert!(commands[0].contains("vim.cmd('botright split')"));
+        reset();
+    }
+}
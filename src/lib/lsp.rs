@@ -0,0 +1,132 @@
+use std::os::raw::c_int;
+
+use crate::{Error, Lua, LuaState, Result, Value};
+
+/// Builds an expression evaluating to an array of `{ id, name }` tables for
+/// the LSP clients attached to `buffer`, or to every client if `None`
+///
+/// A `buffer` of 0 refers to the current buffer. Versions of Neovim before
+/// `vim.lsp.get_clients` fall back to `vim.lsp.get_active_clients`.
+fn get_clients_expr(buffer: Option<i64>) -> Result<String> {
+    let filter = match buffer {
+        Some(n) if n < 0 => return Err(Error::InvalidArgument),
+        Some(n) => format!("{{ bufnr = {n} }}"),
+        None => "{}".to_string(),
+    };
+
+    Ok(format!(
+        "(function() \
+         local get = vim.lsp.get_clients or vim.lsp.get_active_clients \
+         local clients = {{}} \
+         for _, client in ipairs(get({filter})) do \
+         table.insert(clients, {{ id = client.id, name = client.name }}) \
+         end \
+         return clients \
+         end)()"
+    ))
+}
+
+/// Formats `buffer` through its attached LSP clients
+///
+/// A `buffer` of 0 refers to the current buffer. Unless `async` is set the
+/// call blocks until the edits have been applied.
+fn lsp_format(buffer: i64, async_format: bool) -> Result<()> {
+    if buffer < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!(
+        "vim.lsp.buf.format({{ bufnr = {buffer}, async = {async_format} }})"
+    ))
+}
+
+/// Formats a buffer with LSP, asynchronously if `async_format` is non-zero
+#[no_mangle]
+pub extern "C" fn nvim_lsp_format(buffer: c_int, async_format: c_int) -> c_int {
+    match lsp_format(buffer.into(), async_format != 0) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Registers Lua bindings for LSP functions
+pub fn register_lsp_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_lsp_get_clients(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_value(1) {
+            Ok(Value::Nil) => None,
+            Ok(Value::Number(n)) if n.fract() == 0.0 => Some(n as i64),
+            _ => return 0,
+        };
+
+        match get_clients_expr(buffer).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_lsp_format(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match lsp_format(buffer, lua.to_boolean(2)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_lsp_get_clients, 0);
+    lua.set_field(-2, "get_clients")?;
+
+    lua.push_cclosure(lua_lsp_format, 0);
+    lua.set_field(-2, "lsp_format")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn filters_clients_by_buffer() {
+        assert!(get_clients_expr(Some(0))
+            .unwrap()
+            .contains("ipairs(get({ bufnr = 0 }))"));
+        assert!(get_clients_expr(None).unwrap().contains("ipairs(get({}))"));
+        assert!(get_clients_expr(Some(-1)).is_err());
+    }
+
+    #[test]
+    fn formats_asynchronously_when_asked() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_lsp_functions(&lua).unwrap();
+        let format = mock::get(-1).field("lsp_format");
+
+        mock::call(&format, [0.0.into(), true.into()]);
+        mock::call(&format, [3.0.into()]);
+        assert_eq!(nvim_lsp_format(-1, 1), 0);
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.lsp.buf.format({ bufnr = 0, async = true })",
+                "lua vim.lsp.buf.format({ bufnr = 3, async = false })",
+            ]
+        );
+    }
+}
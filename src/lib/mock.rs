@@ -0,0 +1,595 @@
+//! In-process stand-in for the Lua C API and the Neovim symbols, for tests
+//!
+//! Every test thread gets its own state, so tests can run in parallel. Chunks
+//! passed to `luaL_loadstring` are recorded rather than run and evaluate to
+//! nil unless given a result with `set_result`, and commands passed to
+//! `do_cmdline_cmd` are recorded and succeed. C functions pushed with
+//! `lua_pushcclosure` are real and can be called through `call`.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{CVoid, Lua, LuaState, LUA_GLOBALSINDEX};
+
+type CFunction = extern "C" fn(*mut LuaState) -> c_int;
+
+/// A Lua value held by the mock state
+#[derive(Clone)]
+pub(crate) enum MockValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    /// String bytes, always followed by a NUL that is not part of the string
+    Str(Rc<Vec<u8>>),
+    Table(Rc<RefCell<Vec<(MockValue, MockValue)>>>),
+    Function(CFunction, Rc<Vec<MockValue>>),
+    /// A chunk loaded by `luaL_loadstring`
+    Chunk(Rc<String>),
+}
+
+impl MockValue {
+    /// Compares two values the way `rawequal` does
+    fn raw_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Number(a), Self::Number(b)) => a == b,
+            (Self::Str(a), Self::Str(b)) => a == b,
+            (Self::Table(a), Self::Table(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    /// Sets the string key `key` of this table, returning the table
+    pub(crate) fn with(self, key: &str, value: Self) -> Self {
+        match &self {
+            Self::Table(t) => store(t, string(key), value),
+            _ => panic!("with on a non-table"),
+        }
+        self
+    }
+
+    /// Returns the contents of this value if it is a string
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(bytes) => std::str::from_utf8(&bytes[..bytes.len() - 1]).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value stored under the string key `key`, if this is a table
+    pub(crate) fn field(&self, key: &str) -> Self {
+        match self {
+            Self::Table(t) => lookup(&t.borrow(), &string(key)),
+            _ => Self::Nil,
+        }
+    }
+}
+
+impl From<&str> for MockValue {
+    fn from(s: &str) -> Self {
+        string(s)
+    }
+}
+
+impl From<bool> for MockValue {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<f64> for MockValue {
+    fn from(n: f64) -> Self {
+        Self::Number(n)
+    }
+}
+
+/// Builds a string value
+pub(crate) fn string(s: &str) -> MockValue {
+    let mut bytes = s.as_bytes().to_vec();
+    bytes.push(0);
+    MockValue::Str(Rc::new(bytes))
+}
+
+/// Builds a table from string keys and values
+pub(crate) fn table<'a>(pairs: impl IntoIterator<Item = (&'a str, MockValue)>) -> MockValue {
+    let pairs = pairs.into_iter().map(|(k, v)| (string(k), v)).collect();
+    MockValue::Table(Rc::new(RefCell::new(pairs)))
+}
+
+/// Builds a table whose array part holds `items`
+pub(crate) fn list(items: impl IntoIterator<Item = MockValue>) -> MockValue {
+    let pairs = items
+        .into_iter()
+        .enumerate()
+        .map(|(i, v)| (MockValue::Number((i + 1) as f64), v))
+        .collect();
+    MockValue::Table(Rc::new(RefCell::new(pairs)))
+}
+
+fn lookup(table: &[(MockValue, MockValue)], key: &MockValue) -> MockValue {
+    table
+        .iter()
+        .find(|(k, _)| k.raw_eq(key))
+        .map_or(MockValue::Nil, |(_, v)| v.clone())
+}
+
+fn store(table: &RefCell<Vec<(MockValue, MockValue)>>, key: MockValue, value: MockValue) {
+    let mut table = table.borrow_mut();
+    let pos = table.iter().position(|(k, _)| k.raw_eq(&key));
+    match (pos, value) {
+        (Some(pos), MockValue::Nil) => {
+            table.remove(pos);
+        }
+        (Some(pos), value) => table[pos].1 = value,
+        (None, MockValue::Nil) => {}
+        (None, value) => table.push((key, value)),
+    }
+}
+
+struct State {
+    stack: Vec<MockValue>,
+    /// Stack position of index 1 in the running C function
+    base: usize,
+    upvalues: Rc<Vec<MockValue>>,
+    globals: MockValue,
+    /// Names of the API functions called, in order
+    calls: Vec<&'static str>,
+    /// Chunks run through `lua_pcall`, in order
+    chunks: Vec<String>,
+    /// Commands run through `do_cmdline_cmd`, in order
+    commands: Vec<String>,
+    /// Values that chunks evaluate to instead of nil, by chunk
+    results: Vec<(String, MockValue)>,
+    /// Whether `lua_checkstack` refuses to grow the stack
+    stack_full: bool,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            stack: Vec::new(),
+            base: 0,
+            upvalues: Rc::new(Vec::new()),
+            globals: table([]),
+            calls: Vec::new(),
+            chunks: Vec::new(),
+            commands: Vec::new(),
+            results: Vec::new(),
+            stack_full: false,
+        }
+    }
+
+    fn get(&self, idx: c_int) -> Option<MockValue> {
+        if idx == LUA_GLOBALSINDEX {
+            return Some(self.globals.clone());
+        }
+
+        if idx < LUA_GLOBALSINDEX {
+            let n = (LUA_GLOBALSINDEX - idx) as usize;
+            return self.upvalues.get(n - 1).cloned();
+        }
+
+        self.position(idx).map(|pos| self.stack[pos].clone())
+    }
+
+    fn position(&self, idx: c_int) -> Option<usize> {
+        let pos = if idx > 0 {
+            self.base + idx as usize - 1
+        } else {
+            self.stack.len().checked_sub(idx.unsigned_abs() as usize)?
+        };
+
+        (pos >= self.base && pos < self.stack.len()).then_some(pos)
+    }
+
+    fn pop(&mut self) -> MockValue {
+        self.stack.pop().expect("mock Lua stack underflow")
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::new());
+}
+
+fn with<T>(f: impl FnOnce(&mut State) -> T) -> T {
+    STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+fn logged<T>(name: &'static str, f: impl FnOnce(&mut State) -> T) -> T {
+    with(|state| {
+        state.calls.push(name);
+        f(state)
+    })
+}
+
+/// Resets this thread's mock state and returns a handle to it
+pub(crate) fn lua() -> Lua<'static> {
+    with(|state| *state = State::new());
+    unsafe { Lua::new(std::ptr::NonNull::dangling().as_ptr()) }.expect("non-null state")
+}
+
+/// Makes the chunk `chunk` evaluate to `value` from now on
+pub(crate) fn set_result(chunk: &str, value: MockValue) {
+    with(|state| state.results.push((chunk.to_string(), value)));
+}
+
+/// Serializes tests that touch process-wide state, such as an open transaction
+pub(crate) fn serial() -> MutexGuard<'static, ()> {
+    static SERIAL: Mutex<()> = Mutex::new(());
+    SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Makes `lua_checkstack` fail from now on
+pub(crate) fn fill_stack() {
+    with(|state| state.stack_full = true);
+}
+
+/// Pushes `value` onto the stack
+pub(crate) fn push(value: MockValue) {
+    with(|state| state.stack.push(value));
+}
+
+/// Returns the value at the given index
+pub(crate) fn get(idx: c_int) -> MockValue {
+    with(|state| state.get(idx)).unwrap_or(MockValue::Nil)
+}
+
+/// Sets the Lua global `name`
+pub(crate) fn set_global(name: &str, value: MockValue) {
+    with(|state| match &state.globals {
+        MockValue::Table(t) => store(t, string(name), value),
+        _ => unreachable!(),
+    });
+}
+
+/// Calls the function `f` with `args`, returning its results
+pub(crate) fn call(f: &MockValue, args: impl IntoIterator<Item = MockValue>) -> Vec<MockValue> {
+    let ptr = std::ptr::NonNull::dangling().as_ptr();
+    let (func_pos, nargs) = with(|state| {
+        let func_pos = state.stack.len();
+        state.stack.push(f.clone());
+        state.stack.extend(args);
+        (func_pos, (state.stack.len() - func_pos - 1) as c_int)
+    });
+
+    assert_eq!(lua_pcall(ptr, nargs, -1, 0), 0, "mock call failed");
+    with(|state| state.stack.split_off(func_pos))
+}
+
+/// Returns the names of the API functions called so far
+pub(crate) fn calls() -> Vec<&'static str> {
+    with(|state| state.calls.clone())
+}
+
+/// Returns the chunks run so far
+pub(crate) fn chunks() -> Vec<String> {
+    with(|state| state.chunks.clone())
+}
+
+/// Returns the commands run so far
+pub(crate) fn commands() -> Vec<String> {
+    with(|state| state.commands.clone())
+}
+
+/// Formats a number the way `lua_tolstring` does
+fn number_to_string(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        n.to_string()
+    }
+}
+
+unsafe fn to_lstring(idx: c_int, len: *mut usize) -> *const c_char {
+    with(|state| {
+        let pos = state.position(idx)?;
+        if let MockValue::Number(n) = state.stack[pos] {
+            state.stack[pos] = string(&number_to_string(n));
+        }
+
+        match &state.stack[pos] {
+            MockValue::Str(bytes) => {
+                if !len.is_null() {
+                    *len = bytes.len() - 1;
+                }
+                Some(bytes.as_ptr().cast::<c_char>())
+            }
+            _ => None,
+        }
+    })
+    .unwrap_or(std::ptr::null())
+}
+
+#[no_mangle]
+extern "C" fn lua_checkstack(_l: *mut LuaState, _extra: c_int) -> c_int {
+    logged("lua_checkstack", |state| c_int::from(!state.stack_full))
+}
+
+#[no_mangle]
+extern "C" fn lua_createtable(_l: *mut LuaState, _narr: c_int, _nrec: c_int) {
+    logged("lua_createtable", |state| state.stack.push(table([])));
+}
+
+#[no_mangle]
+extern "C" fn lua_pushcclosure(_l: *mut LuaState, f: CFunction, n: c_int) {
+    logged("lua_pushcclosure", |state| {
+        let upvalues = state.stack.split_off(state.stack.len() - n as usize);
+        state.stack.push(MockValue::Function(f, Rc::new(upvalues)));
+    });
+}
+
+#[no_mangle]
+unsafe extern "C" fn lua_setfield(_l: *mut LuaState, idx: c_int, k: *const c_char) {
+    let key = CStr::from_ptr(k).to_str().expect("UTF-8 key");
+    logged("lua_setfield", |state| {
+        let target = state.get(idx);
+        let value = state.pop();
+        match target {
+            Some(MockValue::Table(t)) => store(&t, string(key), value),
+            _ => panic!("lua_setfield on a non-table"),
+        }
+    });
+}
+
+#[no_mangle]
+extern "C" fn lua_type(_l: *mut LuaState, idx: c_int) -> c_int {
+    logged("lua_type", |state| match state.get(idx) {
+        None => crate::LUA_TNONE,
+        Some(MockValue::Nil) => crate::LUA_TNIL,
+        Some(MockValue::Bool(_)) => crate::LUA_TBOOLEAN,
+        Some(MockValue::Number(_)) => crate::LUA_TNUMBER,
+        Some(MockValue::Str(_)) => crate::LUA_TSTRING,
+        Some(MockValue::Table(_)) => crate::LUA_TTABLE,
+        Some(MockValue::Function(..) | MockValue::Chunk(_)) => crate::LUA_TFUNCTION,
+    })
+}
+
+#[no_mangle]
+extern "C" fn lua_pushvalue(_l: *mut LuaState, idx: c_int) {
+    logged("lua_pushvalue", |state| {
+        let value = state.get(idx).unwrap_or(MockValue::Nil);
+        state.stack.push(value);
+    });
+}
+
+#[no_mangle]
+extern "C" fn lua_pushnil(_l: *mut LuaState) {
+    logged("lua_pushnil", |state| state.stack.push(MockValue::Nil));
+}
+
+#[no_mangle]
+extern "C" fn lua_pushinteger(_l: *mut LuaState, n: isize) {
+    logged("lua_pushinteger", |state| {
+        state.stack.push(MockValue::Number(n as f64));
+    });
+}
+
+#[no_mangle]
+unsafe extern "C" fn lua_pushlstring(_l: *mut LuaState, s: *const c_char, len: usize) {
+    let mut bytes = std::slice::from_raw_parts(s.cast::<u8>(), len).to_vec();
+    bytes.push(0);
+    logged("lua_pushlstring", |state| {
+        state.stack.push(MockValue::Str(Rc::new(bytes)));
+    });
+}
+
+#[no_mangle]
+extern "C" fn lua_settop(_l: *mut LuaState, idx: c_int) {
+    logged("lua_settop", |state| {
+        let len = if idx >= 0 {
+            state.base + idx as usize
+        } else {
+            state.stack.len() + 1 - idx.unsigned_abs() as usize
+        };
+        state.stack.resize(len, MockValue::Nil);
+    });
+}
+
+#[no_mangle]
+extern "C" fn lua_pcall(l: *mut LuaState, nargs: c_int, nresults: c_int, _errfunc: c_int) -> c_int {
+    let (func_pos, func) = logged("lua_pcall", |state| {
+        let func_pos = state.stack.len() - nargs as usize - 1;
+        (func_pos, state.stack[func_pos].clone())
+    });
+
+    let mut results = match func {
+        MockValue::Function(f, upvalues) => {
+            let saved = with(|state| {
+                let base = std::mem::replace(&mut state.base, func_pos + 1);
+                (base, std::mem::replace(&mut state.upvalues, upvalues))
+            });
+
+            let n = f(l) as usize;
+
+            with(|state| {
+                (state.base, state.upvalues) = saved;
+                let results = state.stack.split_off(state.stack.len() - n);
+                state.stack.truncate(func_pos);
+                results
+            })
+        }
+        MockValue::Chunk(code) => with(|state| {
+            state.chunks.push(code.to_string());
+            state.stack.truncate(func_pos);
+            state
+                .results
+                .iter()
+                .find(|(chunk, _)| *chunk == *code)
+                .map(|(_, value)| vec![value.clone()])
+                .unwrap_or_default()
+        }),
+        _ => {
+            with(|state| {
+                state.stack.truncate(func_pos);
+                state.stack.push(string("attempt to call a non-function"));
+            });
+            return 2;
+        }
+    };
+
+    if nresults >= 0 {
+        results.resize(nresults as usize, MockValue::Nil);
+    }
+    with(|state| state.stack.extend(results));
+    0
+}
+
+#[no_mangle]
+extern "C" fn lua_objlen(_l: *mut LuaState, idx: c_int) -> usize {
+    logged("lua_objlen", |state| match state.get(idx) {
+        Some(MockValue::Str(bytes)) => bytes.len() - 1,
+        Some(MockValue::Table(t)) => {
+            let t = t.borrow();
+            (1..)
+                .take_while(|&i| {
+                    !matches!(lookup(&t, &MockValue::Number(i as f64)), MockValue::Nil)
+                })
+                .count()
+        }
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+extern "C" fn lua_rawgeti(_l: *mut LuaState, idx: c_int, n: c_int) {
+    logged("lua_rawgeti", |state| {
+        let value = match state.get(idx) {
+            Some(MockValue::Table(t)) => lookup(&t.borrow(), &MockValue::Number(f64::from(n))),
+            _ => panic!("lua_rawgeti on a non-table"),
+        };
+        state.stack.push(value);
+    });
+}
+
+#[no_mangle]
+unsafe extern "C" fn lua_getfield(_l: *mut LuaState, idx: c_int, k: *const c_char) {
+    let key = CStr::from_ptr(k).to_str().expect("UTF-8 key");
+    logged("lua_getfield", |state| {
+        let value = match state.get(idx) {
+            Some(MockValue::Table(t)) => lookup(&t.borrow(), &string(key)),
+            _ => panic!("lua_getfield on a non-table"),
+        };
+        state.stack.push(value);
+    });
+}
+
+#[no_mangle]
+extern "C" fn lua_gettop(_l: *mut LuaState) -> c_int {
+    logged("lua_gettop", |state| {
+        (state.stack.len() - state.base) as c_int
+    })
+}
+
+#[no_mangle]
+extern "C" fn lua_next(_l: *mut LuaState, idx: c_int) -> c_int {
+    logged("lua_next", |state| {
+        let target = state.get(idx);
+        let key = state.pop();
+        let t = match target {
+            Some(MockValue::Table(t)) => t,
+            _ => panic!("lua_next on a non-table"),
+        };
+
+        let t = t.borrow();
+        let next = match key {
+            MockValue::Nil => 0,
+            key => t
+                .iter()
+                .position(|(k, _)| k.raw_eq(&key))
+                .map_or(t.len(), |i| i + 1),
+        };
+
+        match t.get(next) {
+            Some((k, v)) => {
+                state.stack.push(k.clone());
+                state.stack.push(v.clone());
+                1
+            }
+            None => 0,
+        }
+    })
+}
+
+#[no_mangle]
+unsafe extern "C" fn lua_tolstring(
+    _l: *mut LuaState,
+    idx: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    with(|state| state.calls.push("lua_tolstring"));
+    to_lstring(idx, len)
+}
+
+#[no_mangle]
+extern "C" fn lua_toboolean(_l: *mut LuaState, idx: c_int) -> c_int {
+    logged("lua_toboolean", |state| {
+        c_int::from(!matches!(
+            state.get(idx),
+            None | Some(MockValue::Nil | MockValue::Bool(false))
+        ))
+    })
+}
+
+#[no_mangle]
+extern "C" fn lua_tointeger(_l: *mut LuaState, idx: c_int) -> isize {
+    logged("lua_tointeger", |state| match state.get(idx) {
+        Some(MockValue::Number(n)) => n as isize,
+        _ => 0,
+    })
+}
+
+#[no_mangle]
+extern "C" fn lua_tonumber(_l: *mut LuaState, idx: c_int) -> f64 {
+    logged("lua_tonumber", |state| match state.get(idx) {
+        Some(MockValue::Number(n)) => n,
+        _ => 0.0,
+    })
+}
+
+/// Unlike the real function, a non-string argument returns NULL instead of
+/// raising an error
+#[no_mangle]
+unsafe extern "C" fn luaL_checklstring(
+    _l: *mut LuaState,
+    arg: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    with(|state| state.calls.push("luaL_checklstring"));
+    to_lstring(arg, len)
+}
+
+#[no_mangle]
+unsafe extern "C" fn luaL_loadstring(_l: *mut LuaState, s: *const c_char) -> c_int {
+    let code = CStr::from_ptr(s).to_string_lossy().into_owned();
+    logged("luaL_loadstring", |state| {
+        state.stack.push(MockValue::Chunk(Rc::new(code)));
+    });
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn do_cmdline_cmd(cmd: *const c_char) -> c_int {
+    let cmd = CStr::from_ptr(cmd).to_string_lossy().into_owned();
+    logged("do_cmdline_cmd", |state| state.commands.push(cmd));
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn concat_str(s1: *const c_char, s2: *const c_char) -> *mut c_char {
+    let mut bytes = CStr::from_ptr(s1).to_bytes().to_vec();
+    bytes.extend_from_slice(CStr::from_ptr(s2).to_bytes());
+    CString::new(bytes).expect("no interior NUL").into_raw()
+}
+
+#[no_mangle]
+unsafe extern "C" fn xfree(ptr: *mut CVoid) {
+    if !ptr.is_null() {
+        drop(CString::from_raw(ptr.cast::<c_char>()));
+    }
+}
@@ -4,14 +4,46 @@
 //! the creation of Neovim plugins in Rust. This library handles the interaction
 //! between Rust, Lua, and Neovim's C API.
 
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::ffi::{CStr, CString};
+use std::fmt::Write;
 use std::marker::PhantomData;
 use std::os::raw::{c_char, c_int};
-
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, ThreadId};
+
+mod action;
+mod buffer;
+mod command;
+mod extmark;
 mod interop;
+mod job;
+mod json;
+mod keymap;
+mod lsp;
+#[cfg(test)]
+mod mock;
 mod pman;
+mod setup;
+mod sign;
+mod transaction;
+mod window;
+use action::register_action_functions;
+use buffer::register_buffer_functions;
+use command::register_command_functions;
+use extmark::register_extmark_functions;
 use interop::register_nvim_interop_functions;
+use job::register_job_functions;
+use json::json_to_lua_table;
+use keymap::register_keymap_functions;
+use lsp::register_lsp_functions;
 use pman::register_plugin_functions;
+use setup::register_setup_functions;
+use sign::register_sign_functions;
+use transaction::register_transaction_functions;
+use window::register_window_functions;
 
 /// Opaque representation of Lua state
 #[repr(C)]
@@ -28,16 +60,42 @@ pub enum Error {
     StringConversion,
     /// Failed to execute a Neovim command
     CommandExecution,
+    /// Received an argument that failed validation
+    InvalidArgument,
+    /// Lua code raised an error while running
+    LuaExecution,
+    /// Failed to parse a JSON document
+    InvalidJson,
+    /// The Lua stack could not grow to hold the requested values
+    StackOverflow,
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
 // FFI bindings to the Lua C API
 extern "C" {
+    fn lua_checkstack(l: *mut LuaState, extra: c_int) -> c_int;
     fn lua_createtable(l: *mut LuaState, narr: c_int, nrec: c_int);
     fn lua_pushcclosure(l: *mut LuaState, f: extern "C" fn(*mut LuaState) -> c_int, n: c_int);
     fn lua_setfield(l: *mut LuaState, idx: c_int, k: *const c_char);
+    fn lua_type(l: *mut LuaState, idx: c_int) -> c_int;
+    fn lua_pushvalue(l: *mut LuaState, idx: c_int);
+    fn lua_pushnil(l: *mut LuaState);
+    fn lua_pushinteger(l: *mut LuaState, n: isize);
+    fn lua_pushlstring(l: *mut LuaState, s: *const c_char, len: usize);
+    fn lua_settop(l: *mut LuaState, idx: c_int);
+    fn lua_pcall(l: *mut LuaState, nargs: c_int, nresults: c_int, errfunc: c_int) -> c_int;
+    fn lua_objlen(l: *mut LuaState, idx: c_int) -> usize;
+    fn lua_rawgeti(l: *mut LuaState, idx: c_int, n: c_int);
+    fn lua_getfield(l: *mut LuaState, idx: c_int, k: *const c_char);
+    fn lua_gettop(l: *mut LuaState) -> c_int;
+    fn lua_next(l: *mut LuaState, idx: c_int) -> c_int;
+    fn lua_tolstring(l: *mut LuaState, idx: c_int, len: *mut usize) -> *const c_char;
+    fn lua_toboolean(l: *mut LuaState, idx: c_int) -> c_int;
+    fn lua_tointeger(l: *mut LuaState, idx: c_int) -> isize;
+    fn lua_tonumber(l: *mut LuaState, idx: c_int) -> f64;
     fn luaL_checklstring(l: *mut LuaState, arg: c_int, len: *mut usize) -> *const c_char;
+    fn luaL_loadstring(l: *mut LuaState, s: *const c_char) -> c_int;
 }
 
 // FFI bindings to the external Neovim API
@@ -104,7 +162,55 @@ fn lua_check_string(l: *mut LuaState, idx: c_int) -> Result<String> {
     }
 }
 
+// Whether generated Lua is wrapped in `pcall` before it is run
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+
+// Whether generated Lua is deferred with `vim.schedule` instead of run immediately
+static DEFER_COMMANDS: AtomicBool = AtomicBool::new(false);
+
+// Thread that loaded the module, which is the only one that runs commands
+static MAIN_THREAD: OnceLock<ThreadId> = OnceLock::new();
+
+// Commands issued off the main thread, run once the main thread gets to them
+static QUEUED_CMDS: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+
+// Lua state that loaded the module, for C exports that need to inspect the host
+static HOST_STATE: AtomicPtr<LuaState> = AtomicPtr::new(std::ptr::null_mut());
+
+// Whether options are checked for existence before they are set
+static STRICT_OPTIONS: AtomicBool = AtomicBool::new(false);
+
+// Whether setting an option to its current value is skipped
+static SKIP_NOOP: AtomicBool = AtomicBool::new(false);
+
+// Keys of the notifications already shown this session
+static NOTIFIED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+// Upper bound on the number of times `defer_until` checks its condition
+const MAX_DEFER_POLLS: i64 = 10_000;
+
+/// Pseudo-index of the table holding Lua globals
+const LUA_GLOBALSINDEX: c_int = -10002;
+
+/// Pseudo-index of the `n`th upvalue of the running C closure, as `lua_upvalueindex`
+pub(crate) const fn upvalue_index(n: c_int) -> c_int {
+    LUA_GLOBALSINDEX - n
+}
+
+/// Lua type tags as returned by `lua_type`
+const LUA_TNONE: c_int = -1;
+const LUA_TNIL: c_int = 0;
+const LUA_TBOOLEAN: c_int = 1;
+const LUA_TNUMBER: c_int = 3;
+const LUA_TSTRING: c_int = 4;
+const LUA_TTABLE: c_int = 5;
+const LUA_TFUNCTION: c_int = 6;
+
 /// Extracts a Rust String from a C string pointer
+///
+/// Invalid UTF-8 is replaced with `U+FFFD`, which is fine for display text but
+/// silently corrupts values such as paths and URLs. Use
+/// `extract_c_string_strict` where the exact bytes matter.
 pub(crate) fn extract_c_string(ptr: *const c_char) -> Result<String> {
     if ptr.is_null() {
         return Err(Error::NullPointer);
@@ -113,8 +219,406 @@ pub(crate) fn extract_c_string(ptr: *const c_char) -> Result<String> {
     unsafe { Ok(CStr::from_ptr(ptr).to_string_lossy().into_owned()) }
 }
 
+/// Extracts a Rust String from a C string pointer, rejecting invalid UTF-8
+pub(crate) fn extract_c_string_strict(ptr: *const c_char) -> Result<String> {
+    if ptr.is_null() {
+        return Err(Error::NullPointer);
+    }
+
+    unsafe {
+        CStr::from_ptr(ptr)
+            .to_str()
+            .map(str::to_owned)
+            .map_err(|_| Error::StringConversion)
+    }
+}
+
+/// Quotes a string as a single-quoted Lua string literal
+pub(crate) fn lua_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('\'');
+
+    for c in s.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '\'' => quoted.push_str("\\'"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_ascii_control() => {
+                let _ = write!(quoted, "\\{:03}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('\'');
+    quoted
+}
+
+/// Wraps a string in a Lua long bracket that cannot be terminated by its contents
+///
+/// The bracket level is the lowest one whose closing sequence does not occur
+/// in the string, including a trailing `]` or `]=...` that would combine with
+/// the closing bracket.
+pub(crate) fn lua_long_string(s: &str) -> String {
+    let mut level = 0;
+
+    let (open, close) = loop {
+        let equals = "=".repeat(level);
+        let close = format!("]{equals}]");
+
+        // The first occurrence of the closing sequence must be the one we append
+        if format!("{s}{close}").find(&close) == Some(s.len()) {
+            break (format!("[{equals}["), close);
+        }
+
+        level += 1;
+    };
+
+    // Lua drops a newline directly after the opening bracket
+    let leading = if s.starts_with(['\n', '\r']) {
+        "\n"
+    } else {
+        ""
+    };
+
+    format!("{open}{leading}{s}{close}")
+}
+
+/// A scalar Lua value passed through to generated code with its type intact
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl Value {
+    /// Renders the value as a Lua expression
+    pub fn to_lua(&self) -> String {
+        match self {
+            Self::Nil => "nil".to_string(),
+            Self::Bool(b) => b.to_string(),
+            Self::Number(n) if n.is_nan() => "0/0".to_string(),
+            Self::Number(n) if n.is_infinite() => {
+                if *n > 0.0 {
+                    "math.huge".to_string()
+                } else {
+                    "-math.huge".to_string()
+                }
+            }
+            Self::Number(n) => n.to_string(),
+            Self::String(s) => lua_quote(s),
+        }
+    }
+}
+
+/// Checks that `name` is a plain identifier such as an option or group name
+pub(crate) fn validate_identifier(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return Err(Error::InvalidArgument),
+    }
+
+    if chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgument)
+    }
+}
+
+/// Checks that `name` is a usable highlight group name
+///
+/// Besides identifiers this allows the `.` and `-` found in groups such as
+/// `DiagnosticVirtualText.Error`, and a leading `@` for treesitter captures
+/// such as `@comment.lua`.
+pub(crate) fn validate_hl_group(name: &str) -> Result<()> {
+    let mut chars = name.strip_prefix('@').unwrap_or(name).chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return Err(Error::InvalidArgument),
+    }
+
+    if chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')) {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgument)
+    }
+}
+
+/// Checks that `path` names a Lua function, such as `vim.lsp.buf.format` or
+/// `require('telescope.builtin').find_files`
+///
+/// Only dotted identifiers, optionally rooted at a `require` of a module name,
+/// are accepted so that the path can be embedded in generated code safely.
+pub(crate) fn validate_fn_path(path: &str) -> Result<()> {
+    let rest = match path.strip_prefix("require(") {
+        Some(rest) => {
+            let quote = rest.chars().next().ok_or(Error::InvalidArgument)?;
+            if quote != '\'' && quote != '"' {
+                return Err(Error::InvalidArgument);
+            }
+
+            let (module, rest) = rest[1..].split_once(quote).ok_or(Error::InvalidArgument)?;
+            let module_ok = !module.is_empty()
+                && module
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '/'));
+            if !module_ok {
+                return Err(Error::InvalidArgument);
+            }
+
+            let rest = rest.strip_prefix(')').ok_or(Error::InvalidArgument)?;
+            if rest.is_empty() {
+                return Ok(());
+            }
+            rest.strip_prefix('.').ok_or(Error::InvalidArgument)?
+        }
+        None => path,
+    };
+
+    rest.split('.').try_for_each(validate_identifier)
+}
+
+/// Builds a Lua function that forwards its arguments to the function at `path`
+///
+/// The path is resolved each time the function is called rather than when it
+/// is created, so it may refer to modules that are not loaded yet.
+pub(crate) fn fn_ref(path: &str) -> Result<String> {
+    validate_fn_path(path)?;
+    Ok(format!("function(...) return {path}(...) end"))
+}
+
+/// Builds a callback forwarding to the function at `path`, deferred with
+/// `vim.schedule_wrap` if `schedule` is set
+///
+/// Scheduled callbacks run on the next event loop iteration, where the full
+/// API is available even if they were triggered from a fast context. Their
+/// return value is lost, so they cannot, for example, delete an autocmd by
+/// returning `true`.
+pub(crate) fn callback_ref(path: &str, schedule: bool) -> Result<String> {
+    let callback = fn_ref(path)?;
+    if schedule {
+        Ok(format!("vim.schedule_wrap({callback})"))
+    } else {
+        Ok(callback)
+    }
+}
+
+/// Builds Lua code running an Ex command with an optional file argument
+///
+/// The file is passed through `fnameescape` at runtime so that paths with
+/// spaces or other special characters reach the command intact.
+pub(crate) fn fnameescape_cmd(cmd: &str, file: Option<&str>) -> String {
+    match file {
+        Some(file) if !file.is_empty() => format!(
+            "vim.cmd({} .. vim.fn.fnameescape({}))",
+            lua_quote(&format!("{cmd} ")),
+            lua_quote(file)
+        ),
+        _ => format!("vim.cmd({})", lua_quote(cmd)),
+    }
+}
+
+/// Evaluates `expr` against `lua`, or against the host state recorded when the
+/// module was loaded if `None`, and returns whether the result is truthy
+///
+/// Returns `None` if there is no state to evaluate against yet, or if the
+/// host state would be used from a thread other than the main one.
+fn eval_truthy(lua: Option<&Lua<'_>>, expr: &str) -> Result<Option<bool>> {
+    let host;
+    let lua = match lua {
+        Some(lua) => lua,
+        None if !on_main_thread() => return Ok(None),
+        None => match unsafe { Lua::new(HOST_STATE.load(Ordering::Relaxed)) } {
+            Ok(state) => {
+                host = state;
+                &host
+            }
+            Err(_) => return Ok(None),
+        },
+    };
+
+    lua.ensure_stack(1)?;
+    lua.eval(expr)?;
+    let truthy = lua.to_boolean(-1);
+    lua.pop(1);
+
+    Ok(Some(truthy))
+}
+
+/// Checks whether option `name` already holds `value`, a Lua expression, when
+/// no-op sets are being skipped
+///
+/// Values are compared in their string form, so `4` matches `'4'`. Whenever
+/// the comparison cannot be made the option is treated as changed.
+pub(crate) fn option_unchanged(lua: Option<&Lua<'_>>, name: &str, value: &str) -> bool {
+    if !SKIP_NOOP.load(Ordering::Relaxed) || validate_identifier(name).is_err() {
+        return false;
+    }
+
+    let expr = format!(
+        "select(2, pcall(function() return tostring(vim.o[{}]) == tostring({value}) end)) == true",
+        lua_quote(name)
+    );
+    matches!(eval_truthy(lua, &expr), Ok(Some(true)))
+}
+
+/// Checks that `name` is a known option when strict options are enabled
+///
+/// The check runs against `lua`, or against the host state recorded when the
+/// module was loaded if `None`; before that nothing is checked. Unknown
+/// options are reported through `vim.notify` rather than left to surface as
+/// a bare `E518` from `:set`.
+pub(crate) fn check_option(lua: Option<&Lua<'_>>, name: &str) -> Result<()> {
+    if !STRICT_OPTIONS.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let known = eval_truthy(
+        lua,
+        &format!(
+            "(pcall(vim.api.nvim_get_option_info2, {}, {{}}))",
+            lua_quote(name)
+        ),
+    )?;
+
+    if known.unwrap_or(true) {
+        return Ok(());
+    }
+
+    let msg = format!("rns: unknown option '{name}'");
+    let _ = run_lua(&format!(
+        "vim.notify({}, vim.log.levels.ERROR)",
+        lua_quote(&msg)
+    ));
+    Err(Error::InvalidArgument)
+}
+
+/// Builds Lua code sourcing runtime files matching `patterns`
+///
+/// With `all`, every match is sourced (`:runtime!`) rather than only the
+/// first. Spaces and bars inside a pattern are escaped so each pattern
+/// reaches `:runtime` as a single argument.
+pub(crate) fn runtime_cmd(patterns: &[&str], all: bool) -> String {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| pattern.replace(' ', "\\ ").replace('|', "\\|"))
+        .collect::<Vec<_>>();
+    let bang = if all { "!" } else { "" };
+
+    format!(
+        "vim.cmd({})",
+        lua_quote(&format!("runtime{bang} {}", patterns.join(" ")))
+    )
+}
+
+/// Checks whether the current thread is the one that loaded the module
+///
+/// Before the module is loaded every thread is assumed to be the main one.
+fn on_main_thread() -> bool {
+    MAIN_THREAD
+        .get()
+        .is_none_or(|main| *main == thread::current().id())
+}
+
+/// Evaluates `expr` in the host state and returns its integer result
+///
+/// Unlike `run_lua`, this hands a value back to C callers, such as the id of
+/// something just created. It fails before the module is loaded, off the main
+/// thread, or if `expr` does not evaluate to a number.
+pub(crate) fn eval_integer(expr: &str) -> Result<i64> {
+    if MAIN_THREAD.get().is_none() || !on_main_thread() {
+        return Err(Error::CommandExecution);
+    }
+
+    let lua = unsafe { Lua::new(HOST_STATE.load(Ordering::Relaxed)) }?;
+    lua.ensure_stack(1)?;
+    lua.eval(expr)?;
+    let n = lua.check_integer(-1);
+    lua.pop(1);
+    n
+}
+
+/// Evaluates `expr` in the host state and returns its string result
+///
+/// Fails under the same conditions as `eval_integer`, or if `expr` does not
+/// evaluate to a string or number.
+pub(crate) fn eval_string(expr: &str) -> Result<String> {
+    if MAIN_THREAD.get().is_none() || !on_main_thread() {
+        return Err(Error::CommandExecution);
+    }
+
+    let lua = unsafe { Lua::new(HOST_STATE.load(Ordering::Relaxed)) }?;
+    lua.ensure_stack(1)?;
+    lua.eval(expr)?;
+    let s = lua.check_string(-1);
+    lua.pop(1);
+    s
+}
+
+/// Commands issued off the main thread, waiting for the main thread to run them
+fn queued_cmds() -> &'static Mutex<Vec<String>> {
+    QUEUED_CMDS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Takes the commands queued by other threads, oldest first
+fn take_queued_cmds() -> Vec<String> {
+    queued_cmds()
+        .lock()
+        .map(|mut cmds| std::mem::take(&mut *cmds))
+        .unwrap_or_default()
+}
+
 /// Runs a Neovim command
+///
+/// `do_cmdline_cmd` is not thread-safe, so commands issued from any thread
+/// other than the one that loaded the module are queued instead, and run on
+/// the main thread before its next command or from the timer started by
+/// `start_queue_timer`. On the main thread, commands issued from a fast event
+/// context such as a libuv callback are handed to `vim.schedule`.
 pub(crate) fn run_cmd(cmd: &str) -> Result<()> {
+    if !on_main_thread() {
+        return queued_cmds()
+            .lock()
+            .map(|mut cmds| cmds.push(cmd.to_string()))
+            .map_err(|_| Error::CommandExecution);
+    }
+
+    let host = unsafe { Lua::new(HOST_STATE.load(Ordering::Relaxed)) }.ok();
+    run_cmd_in(host.as_ref(), take_queued_cmds(), cmd)
+}
+
+/// Runs `queued` and then `cmd` on the main thread, checking for a fast event
+/// context in `lua`, if any
+fn run_cmd_in(lua: Option<&Lua<'_>>, queued: Vec<String>, cmd: &str) -> Result<()> {
+    // A failed queued command has no caller left to report to
+    for queued in queued {
+        let _ = run_cmd_now(lua, &queued);
+    }
+
+    run_cmd_now(lua, cmd)
+}
+
+/// Runs `cmd` right away, or schedules it if `lua` is in a fast event context
+fn run_cmd_now(lua: Option<&Lua<'_>>, cmd: &str) -> Result<()> {
+    if let Some(lua) = lua {
+        if matches!(
+            eval_truthy(Some(lua), "vim.in_fast_event()"),
+            Ok(Some(true))
+        ) {
+            lua.ensure_stack(1)?;
+            return lua.exec(
+                &format!("vim.schedule(function() vim.cmd({}) end)", lua_quote(cmd)),
+                0,
+            );
+        }
+    }
+
     let c_cmd = CString::new(cmd).map_err(|_| Error::StringConversion)?;
 
     let result = unsafe { do_cmdline_cmd(c_cmd.as_ptr()) };
@@ -126,6 +630,65 @@ pub(crate) fn run_cmd(cmd: &str) -> Result<()> {
     }
 }
 
+/// Starts the timer that runs commands queued by other threads
+///
+/// Other threads cannot wake the main loop themselves, so the queue is
+/// polled instead; an empty queue costs a single lock.
+fn start_queue_timer(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_run_queued_cmds(l: *mut LuaState) -> c_int {
+        if let Ok(lua) = unsafe { Lua::new(l) } {
+            for queued in take_queued_cmds() {
+                let _ = run_cmd_now(Some(&lua), &queued);
+            }
+        }
+        0
+    }
+
+    if !lua.is_neovim_host() {
+        return Ok(());
+    }
+
+    lua.ensure_stack(2)?;
+    lua.eval(
+        "function(run) \
+           local timer = (vim.uv or vim.loop).new_timer() \
+           timer:start(100, 100, vim.schedule_wrap(run)) \
+         end",
+    )?;
+    lua.push_cclosure(lua_run_queued_cmds, 0);
+    lua.call(1)
+}
+
+/// Runs a chunk of generated Lua code through `:lua`
+///
+/// In safe mode the chunk is wrapped in `pcall` and any error is reported
+/// through `vim.notify`, so one broken statement does not abort the rest of
+/// the configuration. With deferred commands enabled the chunk is handed to
+/// `vim.schedule`, which is safe to call from fast event contexts such as
+/// libuv callbacks where running it directly is not.
+pub(crate) fn run_lua(code: &str) -> Result<()> {
+    if DEFER_COMMANDS.load(Ordering::Relaxed) {
+        return run_cmd(&format!(
+            "lua vim.schedule(function()\n{}\nend)",
+            wrap_safe(code)
+        ));
+    }
+
+    run_cmd(&format!("lua {}", wrap_safe(code)))
+}
+
+/// Wraps a chunk in `pcall` when safe mode is enabled
+fn wrap_safe(code: &str) -> String {
+    if SAFE_MODE.load(Ordering::Relaxed) {
+        format!(
+            "local ok, err = pcall(function()\n{code}\nend) \
+             if not ok then vim.notify('rns: ' .. tostring(err), vim.log.levels.ERROR) end"
+        )
+    } else {
+        code.to_string()
+    }
+}
+
 /// Safe wrapper around Lua state pointer
 pub struct Lua<'a> {
     state: *mut LuaState,
@@ -151,6 +714,15 @@ impl Lua<'_> {
         })
     }
 
+    /// Ensures the Lua stack has room for `n` more values
+    pub fn ensure_stack(&self, n: c_int) -> Result<()> {
+        if unsafe { lua_checkstack(self.state, n) } == 0 {
+            Err(Error::StackOverflow)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Creates a new table on the Lua stack
     pub fn create_table(&self, narr: c_int, nrec: c_int) {
         unsafe {
@@ -165,6 +737,20 @@ impl Lua<'_> {
         }
     }
 
+    /// Pushes an integer onto the Lua stack
+    pub fn push_integer(&self, n: i64) {
+        unsafe {
+            lua_pushinteger(self.state, n as isize);
+        }
+    }
+
+    /// Pushes a string onto the Lua stack
+    pub fn push_string(&self, s: &str) {
+        unsafe {
+            lua_pushlstring(self.state, s.as_ptr().cast::<c_char>(), s.len());
+        }
+    }
+
     /// Sets a field in the table at the given index
     pub fn set_field(&self, idx: c_int, k: &str) -> Result<()> {
         let c_key = CString::new(k).map_err(|_| Error::StringConversion)?;
@@ -180,107 +766,457 @@ impl Lua<'_> {
     pub fn check_string(&self, idx: c_int) -> Result<String> {
         lua_check_string(self.state, idx)
     }
-}
 
-/// Concatenates two strings using Neovim's string concatenation function
-fn concat_strings(s1: &str, s2: &str) -> Result<String> {
-    let c_s1 = CString::new(s1).map_err(|_| Error::StringConversion)?;
-    let c_s2 = CString::new(s2).map_err(|_| Error::StringConversion)?;
+    /// Checks and retrieves a string from the Lua stack, rejecting invalid UTF-8
+    ///
+    /// Interior NUL bytes are rejected too, since the string could not be
+    /// passed on as a C string without being cut short.
+    pub fn check_string_strict(&self, idx: c_int) -> Result<String> {
+        unsafe {
+            let mut len: usize = 0;
+            let ptr = luaL_checklstring(self.state, idx, &mut len);
+            if ptr.is_null() {
+                return Err(Error::NullPointer);
+            }
 
-    unsafe {
-        let result = concat_str(c_s1.as_ptr(), c_s2.as_ptr());
-        let neovim_str = NeovimString::new(result);
-        neovim_str.to_string()
+            let bytes = std::slice::from_raw_parts(ptr.cast::<u8>(), len);
+            if bytes.contains(&0) {
+                return Err(Error::StringConversion);
+            }
+
+            std::str::from_utf8(bytes)
+                .map(str::to_owned)
+                .map_err(|_| Error::StringConversion)
+        }
     }
-}
 
-/// Lua function for loading a configuration file
-extern "C" fn lua_load_config(l: *mut LuaState) -> c_int {
-    let lua = match unsafe { Lua::new(l) } {
-        Ok(lua) => lua,
-        Err(_) => return 0,
-    };
+    /// Checks and retrieves an integer from the Lua stack
+    ///
+    /// Unlike `luaL_checkinteger`, a non-number is reported as an error
+    /// instead of raising a Lua error.
+    pub fn check_integer(&self, idx: c_int) -> Result<i64> {
+        if unsafe { lua_type(self.state, idx) } != LUA_TNUMBER {
+            return Err(Error::InvalidArgument);
+        }
 
-    let path = match lua.check_string(1) {
-        Ok(path) => path,
-        Err(_) => return 0,
-    };
+        Ok(unsafe { lua_tointeger(self.state, idx) } as i64)
+    }
 
-    let cmd = format!("luafile {path}");
-    match run_cmd(&cmd) {
-        Ok(()) => 1,
-        Err(_) => 0,
+    /// Retrieves a scalar value from the Lua stack, preserving its type
+    ///
+    /// Tables, functions, and other non-scalar values are rejected.
+    pub fn check_value(&self, idx: c_int) -> Result<Value> {
+        match unsafe { lua_type(self.state, idx) } {
+            LUA_TNONE | LUA_TNIL => Ok(Value::Nil),
+            LUA_TBOOLEAN => Ok(Value::Bool(self.to_boolean(idx))),
+            LUA_TNUMBER => Ok(Value::Number(unsafe { lua_tonumber(self.state, idx) })),
+            LUA_TSTRING => self.check_string(idx).map(Value::String),
+            _ => Err(Error::InvalidArgument),
+        }
     }
-}
 
-/// Lua function for setting Neovim options
-extern "C" fn lua_opt(l: *mut LuaState) -> c_int {
-    let lua = match unsafe { Lua::new(l) } {
-        Ok(lua) => lua,
-        Err(_) => return 0,
-    };
+    /// Retrieves an optional string argument, returning `None` when it is absent or nil
+    pub fn opt_string(&self, idx: c_int) -> Result<Option<String>> {
+        match unsafe { lua_type(self.state, idx) } {
+            LUA_TNONE | LUA_TNIL => Ok(None),
+            _ => self.check_string(idx).map(Some),
+        }
+    }
 
-    let key = match lua.check_string(1) {
-        Ok(s) => s,
-        Err(_) => return 0,
-    };
+    /// Reads the array part of the table at the given index as a list of strings
+    ///
+    /// Numbers are converted to strings as Lua would; any other element type is rejected.
+    pub fn check_string_list(&self, idx: c_int) -> Result<Vec<String>> {
+        if unsafe { lua_type(self.state, idx) } != LUA_TTABLE {
+            return Err(Error::InvalidArgument);
+        }
 
-    let old_val = match lua.check_string(2) {
-        Ok(s) => s,
-        Err(_) => return 0,
-    };
+        let len = unsafe { lua_objlen(self.state, idx) };
+        let mut items = Vec::with_capacity(len);
 
-    let new_val = match lua.check_string(3) {
-        Ok(s) => s,
-        Err(_) => return 0,
-    };
+        for i in 1..=len {
+            let i = c_int::try_from(i).map_err(|_| Error::InvalidArgument)?;
+            unsafe { lua_rawgeti(self.state, idx, i) };
 
-    let temp = match concat_strings(&old_val, ",") {
-        Ok(s) => s,
-        Err(_) => return 0,
-    };
+            let item = match unsafe { lua_type(self.state, -1) } {
+                LUA_TSTRING | LUA_TNUMBER => {
+                    extract_c_string(unsafe { lua_tolstring(self.state, -1, std::ptr::null_mut()) })
+                }
+                _ => Err(Error::InvalidArgument),
+            };
 
-    let combined = match concat_strings(&temp, &new_val) {
-        Ok(s) => s,
-        Err(_) => return 0,
-    };
+            self.pop(1);
+            items.push(item?);
+        }
 
-    let cmd = format!("set {key}={combined}");
-    match run_cmd(&cmd) {
-        Ok(()) => 1,
-        Err(_) => 0,
+        Ok(items)
     }
-}
 
-/// Lua function for defining key mappings
-extern "C" fn lua_map(l: *mut LuaState) -> c_int {
-    let lua = match unsafe { Lua::new(l) } {
-        Ok(lua) => lua,
-        Err(_) => return 0,
-    };
+    /// Checks that the value at the given index is a table
+    pub fn check_table(&self, idx: c_int) -> Result<()> {
+        if unsafe { lua_type(self.state, idx) } == LUA_TTABLE {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument)
+        }
+    }
 
-    let mode = match lua.check_string(1) {
-        Ok(s) => s,
-        Err(_) => return 0,
-    };
+    /// Checks whether the value at the given index is a string
+    ///
+    /// Numbers are not considered strings, so keys can be inspected without
+    /// `lua_tolstring` converting them in place.
+    pub fn is_string(&self, idx: c_int) -> bool {
+        unsafe { lua_type(self.state, idx) == LUA_TSTRING }
+    }
 
-    let lhs = match lua.check_string(2) {
-        Ok(s) => s,
-        Err(_) => return 0,
-    };
+    /// Pushes the field `k` of the table at the given index, returning its type
+    pub fn get_field(&self, idx: c_int, k: &str) -> Result<c_int> {
+        let c_key = CString::new(k).map_err(|_| Error::StringConversion)?;
 
-    let rhs = match lua.check_string(3) {
+        unsafe {
+            lua_getfield(self.state, idx, c_key.as_ptr());
+            Ok(lua_type(self.state, -1))
+        }
+    }
+
+    /// Pushes the value of the Lua global `name`, returning its type
+    ///
+    /// `lua_getglobal` is a macro in Lua 5.1, so this reads the field from
+    /// the globals table directly.
+    pub fn get_global(&self, name: &str) -> Result<c_int> {
+        self.get_field(LUA_GLOBALSINDEX, name)
+    }
+
+    /// Pops the value on top of the stack into the Lua global `name`
+    pub fn set_global(&self, name: &str) -> Result<()> {
+        self.set_field(LUA_GLOBALSINDEX, name)
+    }
+
+    /// Checks whether the value on top of the stack is a table
+    pub fn is_table_on_top(&self) -> bool {
+        unsafe { lua_type(self.state, -1) == LUA_TTABLE }
+    }
+
+    /// Checks whether the value on top of the stack is nil, or the stack is empty
+    pub fn is_nil_on_top(&self) -> bool {
+        matches!(unsafe { lua_type(self.state, -1) }, LUA_TNONE | LUA_TNIL)
+    }
+
+    /// Checks whether this state belongs to Neovim, which defines the `vim` table
+    pub fn is_neovim_host(&self) -> bool {
+        if self.ensure_stack(1).is_err() || self.get_global("vim").is_err() {
+            return false;
+        }
+
+        let is_host = self.is_table_on_top();
+        self.pop(1);
+        is_host
+    }
+
+    /// Pushes element `n` of the table at the given index, returning its type
+    pub fn get_index(&self, idx: c_int, n: c_int) -> c_int {
+        unsafe {
+            lua_rawgeti(self.state, idx, n);
+            lua_type(self.state, -1)
+        }
+    }
+
+    /// Returns the length of the array part of the table at the given index
+    pub fn table_len(&self, idx: c_int) -> usize {
+        unsafe { lua_objlen(self.state, idx) }
+    }
+
+    /// Calls `f` for every key/value pair of the table at the given index
+    ///
+    /// While `f` runs the key is at index -2 and the value at -1; `f` must
+    /// leave the stack as it found it. Iteration stops at the first error.
+    pub fn for_each_pair(&self, idx: c_int, mut f: impl FnMut(&Self) -> Result<()>) -> Result<()> {
+        self.check_table(idx)?;
+        self.ensure_stack(3)?;
+
+        // Relative indices shift as the key and value are pushed
+        let idx = if idx < 0 {
+            (unsafe { lua_gettop(self.state) }) + idx + 1
+        } else {
+            idx
+        };
+
+        unsafe { lua_pushnil(self.state) };
+        while unsafe { lua_next(self.state, idx) } != 0 {
+            if let Err(e) = f(self) {
+                self.pop(2);
+                return Err(e);
+            }
+
+            self.pop(1);
+        }
+
+        Ok(())
+    }
+
+    /// Runs a chunk of Lua code in protected mode, leaving `nresults` values on the stack
+    ///
+    /// On failure the error message is discarded and nothing is left on the stack.
+    pub fn exec(&self, chunk: &str, nresults: c_int) -> Result<()> {
+        let c_chunk = CString::new(chunk).map_err(|_| Error::StringConversion)?;
+
+        unsafe {
+            if luaL_loadstring(self.state, c_chunk.as_ptr()) != 0 {
+                self.pop(1);
+                return Err(Error::LuaExecution);
+            }
+
+            if lua_pcall(self.state, 0, nresults, 0) != 0 {
+                self.pop(1);
+                return Err(Error::LuaExecution);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates a Lua expression and pushes its value onto the stack
+    pub fn eval(&self, expr: &str) -> Result<()> {
+        self.exec(&format!("return {expr}"), 1)
+    }
+
+    /// Pushes a table built from a JSON object or array onto the stack
+    pub fn push_lua_table_from_json(&self, json: &str) -> Result<()> {
+        self.eval(&json_to_lua_table(json)?)
+    }
+
+    /// Converts the value at the given index to a boolean using Lua truthiness
+    pub fn to_boolean(&self, idx: c_int) -> bool {
+        unsafe { lua_toboolean(self.state, idx) != 0 }
+    }
+
+    /// Pops `n` values from the Lua stack
+    pub fn pop(&self, n: c_int) {
+        unsafe {
+            lua_settop(self.state, -n - 1);
+        }
+    }
+
+    /// Pushes a copy of the value at the given index onto the stack
+    pub fn push_value(&self, idx: c_int) {
+        unsafe { lua_pushvalue(self.state, idx) };
+    }
+
+    /// Calls the function at the given index with no arguments in protected mode
+    ///
+    /// Errors raised by the function are caught and discarded from the stack.
+    pub fn call_function(&self, idx: c_int) -> Result<()> {
+        if unsafe { lua_type(self.state, idx) } != LUA_TFUNCTION {
+            return Err(Error::InvalidArgument);
+        }
+
+        unsafe {
+            lua_pushvalue(self.state, idx);
+            if lua_pcall(self.state, 0, 0, 0) != 0 {
+                self.pop(1);
+                return Err(Error::LuaExecution);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Calls the function below the top `nargs` values with them as arguments, in protected mode
+    ///
+    /// The function and its arguments are popped and any results discarded.
+    /// Errors raised by the function are caught and discarded from the stack.
+    pub fn call(&self, nargs: c_int) -> Result<()> {
+        if unsafe { lua_type(self.state, -nargs - 1) } != LUA_TFUNCTION {
+            return Err(Error::InvalidArgument);
+        }
+
+        if unsafe { lua_pcall(self.state, nargs, 0, 0) } != 0 {
+            self.pop(1);
+            return Err(Error::LuaExecution);
+        }
+
+        Ok(())
+    }
+}
+
+/// Concatenates two strings using Neovim's string concatenation function
+fn concat_strings(s1: &str, s2: &str) -> Result<String> {
+    let c_s1 = CString::new(s1).map_err(|_| Error::StringConversion)?;
+    let c_s2 = CString::new(s2).map_err(|_| Error::StringConversion)?;
+
+    unsafe {
+        let result = concat_str(c_s1.as_ptr(), c_s2.as_ptr());
+        let neovim_str = NeovimString::new(result);
+        neovim_str.to_string()
+    }
+}
+
+/// Lua function for loading a configuration file
+extern "C" fn lua_load_config(l: *mut LuaState) -> c_int {
+    let lua = match unsafe { Lua::new(l) } {
+        Ok(lua) => lua,
+        Err(_) => return 0,
+    };
+
+    let path = match lua.check_string_strict(1) {
+        Ok(path) => path,
+        Err(_) => return 0,
+    };
+
+    let cmd = format!("luafile {path}");
+    match run_cmd(&cmd) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Checks whether `key` names a boolean option, treating unknown options as non-boolean
+fn is_bool_option(lua: &Lua<'_>, key: &str) -> bool {
+    let expr = match interop::option_info_expr(key) {
+        Ok(info) => format!("({info} or {{}}).type == 'boolean'"),
+        Err(_) => return false,
+    };
+
+    if lua.eval(&expr).is_err() {
+        return false;
+    }
+
+    let is_bool = lua.to_boolean(-1);
+    lua.pop(1);
+    is_bool
+}
+
+/// Lua function for setting Neovim options
+extern "C" fn lua_opt(l: *mut LuaState) -> c_int {
+    let lua = match unsafe { Lua::new(l) } {
+        Ok(lua) => lua,
+        Err(_) => return 0,
+    };
+
+    let key = match lua.check_string(1) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let old_val = match lua.check_string(2) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let new_val = match lua.check_string(3) {
         Ok(s) => s,
         Err(_) => return 0,
     };
 
-    let cmd = format!("{mode}map {lhs} {rhs}");
+    if check_option(Some(&lua), &key).is_err() {
+        return 0;
+    }
+
+    // Boolean options take no value, so the new value only decides between
+    // `set key` and `set nokey`
+    let (cmd, value) = if is_bool_option(&lua, &key) {
+        match new_val.as_str() {
+            "false" | "0" | "no" | "off" => (format!("set no{key}"), "false".to_string()),
+            _ => (format!("set {key}"), "true".to_string()),
+        }
+    } else {
+        let temp = match concat_strings(&old_val, ",") {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let combined = match concat_strings(&temp, &new_val) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        (format!("set {key}={combined}"), lua_quote(&combined))
+    };
+
+    if option_unchanged(Some(&lua), &key, &value) {
+        return 1;
+    }
+
+    let option = format!("vim.o[{}]", lua_quote(&key));
+    if transaction::record(&lua, &option, |prior| format!("{option} = {prior}")).is_err() {
+        return 0;
+    }
+
     match run_cmd(&cmd) {
         Ok(()) => 1,
         Err(_) => 0,
     }
 }
 
+/// Lua function for defining key mappings
+///
+/// Mappings go through the same emitter as the other keymap functions, so
+/// the defaults from `set_default_map_opts`, legacy mode, and the overwrite
+/// and shadowing warnings all apply. Unlike `vim.keymap.set`, the mapping is
+/// recursive unless the defaults or the optional fourth argument set `noremap`.
+extern "C" fn lua_map(l: *mut LuaState) -> c_int {
+    let lua = match unsafe { Lua::new(l) } {
+        Ok(lua) => lua,
+        Err(_) => return 0,
+    };
+
+    let mode = match lua.check_string(1) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let lhs = match lua.check_string(2) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let rhs = match lua.check_string(3) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let opts = match keymap::KeymapOpts::from_lua(&lua, 4) {
+        Ok(opts) => opts,
+        Err(_) => return 0,
+    };
+
+    if keymap::validate_mode(&mode).is_err() {
+        return 0;
+    }
+
+    let (mode_lua, lhs_lua) = (lua_quote(&mode), lua_quote(&lhs));
+    let current = format!("vim.fn.maparg({lhs_lua}, {mode_lua}, false, true)");
+    let restore = |prior: &str| {
+        format!(
+            "local prior = {prior} \
+             if vim.tbl_isempty(prior) then pcall(vim.keymap.del, {mode_lua}, {lhs_lua}) \
+             else vim.fn.mapset({mode_lua}, false, prior) end"
+        )
+    };
+    if transaction::record(&lua, &current, restore).is_err() {
+        return 0;
+    }
+
+    // Global mappings are recorded for `:RnsMaps` by the emitter
+    // `rns.map` was recursive before it shared the emitter, so keep that as
+    // the lowest-precedence default
+    let recursive = keymap::KeymapOpts {
+        noremap: Some(false),
+        ..keymap::KeymapOpts::default()
+    };
+    let spec = keymap::KeymapSpec {
+        mode,
+        lhs,
+        rhs: keymap::Rhs::Command(rhs),
+        opts: recursive.merge(&keymap::default_opts()).merge(&opts),
+        buffer: None,
+    };
+    match keymap::emit_keymap(&spec) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
 /// Lua function for setting global variables
 extern "C" fn lua_g(l: *mut LuaState) -> c_int {
     let lua = match unsafe { Lua::new(l) } {
@@ -298,6 +1234,11 @@ extern "C" fn lua_g(l: *mut LuaState) -> c_int {
         Err(_) => return 0,
     };
 
+    let var = format!("vim.g[{}]", lua_quote(&key));
+    if transaction::record(&lua, &var, |prior| format!("{var} = {prior}")).is_err() {
+        return 0;
+    }
+
     let cmd = format!("let g:{key} = {val}");
     match run_cmd(&cmd) {
         Ok(()) => 1,
@@ -320,6 +1261,15 @@ pub unsafe extern "C" fn luaopen_init(l: *mut LuaState) -> c_int {
         Err(_) => return 0,
     };
 
+    // Commands may only be run from the thread Neovim loaded the module on
+    MAIN_THREAD.get_or_init(|| thread::current().id());
+    HOST_STATE.store(l, Ordering::Relaxed);
+
+    // Room for the module table and one binding at a time
+    if lua.ensure_stack(2).is_err() {
+        return 0;
+    }
+
     lua.create_table(0, 0);
 
     lua.push_cclosure(lua_load_config, 0);
@@ -342,11 +1292,51 @@ pub unsafe extern "C" fn luaopen_init(l: *mut LuaState) -> c_int {
         return 0;
     }
 
+    // Generated plugin manager code reports through the same per-key guard
+    lua.push_cclosure(lua_notify_once, 0);
+    if lua.set_global("rns_notify_once").is_err() {
+        return 0;
+    }
+
+    if start_queue_timer(&lua).is_err() {
+        return 0;
+    }
+
     // Register the extra Lua functions
     if register_extra_lua_functions(&lua).is_err() {
         return 0;
     }
 
+    // Register keymap functions
+    if register_keymap_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register user command functions
+    if register_command_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register buffer functions
+    if register_buffer_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register sign functions
+    if register_sign_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register namespace and extmark functions
+    if register_extmark_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register window management functions
+    if register_window_functions(&lua).is_err() {
+        return 0;
+    }
+
     // Register plugin manager functions
     if register_plugin_functions(&lua).is_err() {
         return 0;
@@ -357,6 +1347,31 @@ pub unsafe extern "C" fn luaopen_init(l: *mut LuaState) -> c_int {
         return 0;
     }
 
+    // Register transaction functions
+    if register_transaction_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register LSP functions
+    if register_lsp_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register command palette actions
+    if register_action_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register job control functions
+    if register_job_functions(&lua).is_err() {
+        return 0;
+    }
+
+    // Register the single-entry setup function
+    if register_setup_functions(&lua).is_err() {
+        return 0;
+    }
+
     extern "C" fn safe_luaopen_init(l: *mut LuaState) -> c_int {
         unsafe { luaopen_init(l) }
     }
@@ -397,6 +1412,10 @@ pub unsafe extern "C" fn opt(
         Err(_) => return 0,
     };
 
+    if check_option(None, &key_str).is_err() {
+        return 0;
+    }
+
     let temp = match concat_strings(&old_str, ",") {
         Ok(s) => s,
         Err(_) => return 0,
@@ -407,6 +1426,10 @@ pub unsafe extern "C" fn opt(
         Err(_) => return 0,
     };
 
+    if option_unchanged(None, &key_str, &lua_quote(&combined)) {
+        return 1;
+    }
+
     let cmd = format!("set {key_str}={combined}");
     match run_cmd(&cmd) {
         Ok(()) => 1,
@@ -468,7 +1491,7 @@ pub unsafe extern "C" fn autocmd(
         Err(_) => return 0,
     };
 
-    let cmd = format!("autocmd {event_str} {pattern_str} {command_str}");
+    let cmd = interop::autocmd_cmd(&event_str, &pattern_str, &command_str);
     match run_cmd(&cmd) {
         Ok(()) => 1,
         Err(_) => 0,
@@ -477,6 +1500,9 @@ pub unsafe extern "C" fn autocmd(
 
 /// Configures an LSP server with the given JSON configuration
 ///
+/// The configuration must be a JSON object (or empty), and is translated into
+/// a Lua table before being passed to `setup`.
+///
 /// # Safety
 ///
 /// Both `server` and `config_json` must be valid, properly null-terminated C strings.
@@ -490,12 +1516,16 @@ pub unsafe extern "C" fn setup_lsp(server: *const c_char, config_json: *const c_
     };
 
     let config_str = match extract_c_string(config_json) {
-        Ok(s) => s,
+        Ok(s) if s.trim().is_empty() => "{}".to_string(),
+        Ok(s) => match json_to_lua_table(&s) {
+            Ok(table) => table,
+            Err(_) => return 0,
+        },
         Err(_) => return 0,
     };
 
-    let cmd = format!("lua require'lspconfig'.{server_str}.setup({config_str})");
-    match run_cmd(&cmd) {
+    let code = format!("require'lspconfig'.{server_str}.setup({config_str})");
+    match run_lua(&code) {
         Ok(()) => 1,
         Err(_) => 0,
     }
@@ -516,13 +1546,285 @@ pub unsafe extern "C" fn exec_lua(code: *const c_char) -> c_int {
         Err(_) => return 0,
     };
 
-    let cmd = format!("lua {code_str}");
-    match run_cmd(&cmd) {
+    match run_lua(&code_str) {
         Ok(()) => 1,
         Err(_) => 0,
     }
 }
 
+/// Builds the Lua code that loads a file as a chunk and calls it with `args`
+///
+/// Load errors are reported through `vim.notify` rather than raised.
+fn exec_lua_file_cmd(path: &str, args: &[String]) -> String {
+    let path = lua_quote(path);
+    let args = args.iter().map(|arg| lua_quote(arg)).collect::<Vec<_>>();
+
+    format!(
+        "local chunk, err = loadfile({path}) \
+         if chunk then chunk({}) \
+         else vim.notify('rns: failed to load ' .. {path} .. ': ' .. err, vim.log.levels.ERROR) end",
+        args.join(", ")
+    )
+}
+
+/// Loads a Lua file as a chunk and calls it with the given string arguments
+///
+/// # Safety
+///
+/// `path` must be a valid, properly null-terminated C string. `args` must point
+/// to `nargs` valid C strings, and may only be null when `nargs` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn exec_lua_file_with_args(
+    path: *const c_char,
+    args: *const *const c_char,
+    nargs: c_int,
+) -> c_int {
+    let path_str = match extract_c_string_strict(path) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let nargs = usize::try_from(nargs).unwrap_or(0);
+    let mut arg_strs = Vec::with_capacity(nargs);
+    for i in 0..nargs {
+        match extract_c_string(*args.add(i)) {
+            Ok(s) => arg_strs.push(s),
+            Err(_) => return 0,
+        }
+    }
+
+    match run_lua(&exec_lua_file_cmd(&path_str, &arg_strs)) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Enables or disables safe mode for generated Lua
+///
+/// When enabled, every Lua chunk the crate runs is wrapped in `pcall` and
+/// errors are reported through `vim.notify` instead of aborting.
+#[no_mangle]
+pub extern "C" fn set_safe_mode(enabled: c_int) -> c_int {
+    SAFE_MODE.store(enabled != 0, Ordering::Relaxed);
+    1
+}
+
+/// Clears all state managed by the crate
+///
+/// The plugin registry, default keymap options, tracked jobs and buffer
+/// mappings, the open transaction, the active augroup scope, registered
+/// actions, and the set of shown `notify_once` keys are all emptied, giving
+/// plugins under development a clean slate without restarting Neovim. Jobs
+/// are forgotten rather than stopped; call `stop_all_jobs` first to stop
+/// them. Settings such as safe mode are kept.
+#[no_mangle]
+pub extern "C" fn reset_state() -> c_int {
+    keymap::reset();
+    job::reset();
+    transaction::reset();
+    interop::reset();
+    action::reset();
+
+    if let Ok(mut notified) = NOTIFIED.get_or_init(|| Mutex::new(HashSet::new())).lock() {
+        notified.clear();
+    }
+
+    match unsafe { pman::reset() } {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Builds the Lua code polling `condition_fn` every `interval_ms` until it
+/// returns true, then running `action_fn`
+///
+/// The condition is checked at most `max_polls` times, itself capped at
+/// `MAX_DEFER_POLLS`, so a condition that never holds cannot keep a timer
+/// running forever; giving up is reported through `vim.notify`. Errors raised
+/// by the condition count as false.
+fn defer_until_code(
+    condition_fn: &str,
+    action_fn: &str,
+    interval_ms: i64,
+    max_polls: i64,
+) -> Result<String> {
+    if interval_ms < 1 || !(1..=MAX_DEFER_POLLS).contains(&max_polls) {
+        return Err(Error::InvalidArgument);
+    }
+
+    let (condition, action) = (fn_ref(condition_fn)?, fn_ref(action_fn)?);
+    let desc = lua_quote(action_fn);
+
+    Ok(format!(
+        r"
+        local condition, action = {condition}, {action}
+        local timer = (vim.uv or vim.loop).new_timer()
+        local polls = 0
+        timer:start(0, {interval_ms}, vim.schedule_wrap(function()
+            if timer:is_closing() then return end
+            polls = polls + 1
+            local ok, ready = pcall(condition)
+            if ok and ready then
+                timer:close()
+                local ran, err = pcall(action)
+                if not ran then
+                    vim.notify('rns: ' .. tostring(err), vim.log.levels.ERROR)
+                end
+            elseif polls >= {max_polls} then
+                timer:close()
+                vim.notify('rns: gave up waiting to run ' .. {desc}, vim.log.levels.WARN)
+            end
+        end))
+    "
+    ))
+}
+
+/// Runs a Lua function once a condition function returns true
+///
+/// The condition is polled every `interval_ms` milliseconds, at most
+/// `max_polls` times.
+///
+/// # Safety
+///
+/// `condition_fn` and `action_fn` must be valid null-terminated C strings
+/// naming Lua functions.
+#[no_mangle]
+pub unsafe extern "C" fn defer_until(
+    condition_fn: *const c_char,
+    action_fn: *const c_char,
+    interval_ms: c_int,
+    max_polls: c_int,
+) -> c_int {
+    match (extract_c_string(condition_fn), extract_c_string(action_fn)) {
+        (Ok(condition_fn), Ok(action_fn)) => {
+            match defer_until_code(
+                &condition_fn,
+                &action_fn,
+                interval_ms.into(),
+                max_polls.into(),
+            )
+            .and_then(|code| run_lua(&code))
+            {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// Shows a notification the first time `key` is seen this session
+///
+/// Later calls with the same key are silently ignored. `level` is one of
+/// `vim.log.levels`.
+fn notify_once(key: &str, msg: &str, level: i64) -> Result<()> {
+    if !(0..=5).contains(&level) {
+        return Err(Error::InvalidArgument);
+    }
+
+    let first = NOTIFIED
+        .get_or_init(|| Mutex::new(HashSet::new()))
+        .lock()
+        .map(|mut notified| notified.insert(key.to_string()))
+        .unwrap_or(true);
+    if !first {
+        return Ok(());
+    }
+
+    // The key has already been checked, and `vim.notify_once` would also
+    // dedupe by message in a way `rns.reset` cannot clear
+    run_lua(&format!("vim.notify({}, {level})", lua_quote(msg)))
+}
+
+/// Shows a notification only the first time `key` is seen
+///
+/// # Safety
+///
+/// `key` and `msg` must be valid null-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn nvim_notify_once(
+    key: *const c_char,
+    msg: *const c_char,
+    level: c_int,
+) -> c_int {
+    let key_str = match extract_c_string(key) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let msg_str = match extract_c_string(msg) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    match notify_once(&key_str, &msg_str, level.into()) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Lua function for showing a notification only the first time its key is seen
+extern "C" fn lua_notify_once(l: *mut LuaState) -> c_int {
+    let lua = match unsafe { Lua::new(l) } {
+        Ok(lua) => lua,
+        Err(_) => return 0,
+    };
+
+    let key = match lua.check_string(1) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    let msg = match lua.check_string(2) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
+
+    // Defaults to vim.log.levels.INFO
+    let level = match unsafe { lua_type(l, 3) } {
+        LUA_TNONE | LUA_TNIL => 2,
+        _ => match lua.check_integer(3) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        },
+    };
+
+    match notify_once(&key, &msg, level) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Enables or disables checking that options exist before setting them
+///
+/// This costs an extra API call per option, so it is off by default.
+#[no_mangle]
+pub extern "C" fn set_strict_options(enabled: c_int) -> c_int {
+    STRICT_OPTIONS.store(enabled != 0, Ordering::Relaxed);
+    1
+}
+
+/// Enables or disables skipping option sets that would not change the value
+///
+/// Re-setting an option still fires `OptionSet`, so reloading a config can
+/// set off a storm of autocmds. Skipping costs a read per option, so it is off
+/// by default.
+#[no_mangle]
+pub extern "C" fn set_skip_noop(enabled: c_int) -> c_int {
+    SKIP_NOOP.store(enabled != 0, Ordering::Relaxed);
+    1
+}
+
+/// Enables or disables deferring generated Lua with `vim.schedule`
+///
+/// Enable this before calling into the crate from fast event contexts, where
+/// running commands synchronously is not allowed.
+#[no_mangle]
+pub extern "C" fn set_defer_commands(enabled: c_int) -> c_int {
+    DEFER_COMMANDS.store(enabled != 0, Ordering::Relaxed);
+    1
+}
+
 /// Registers additional Lua functions with the module
 fn register_extra_lua_functions(lua: &Lua<'_>) -> Result<()> {
     extern "C" fn lua_autocmd(l: *mut LuaState) -> c_int {
@@ -546,7 +1848,7 @@ fn register_extra_lua_functions(lua: &Lua<'_>) -> Result<()> {
             Err(_) => return 0,
         };
 
-        let cmd = format!("autocmd {event} {pattern} {command}");
+        let cmd = interop::autocmd_cmd(&event, &pattern, &command);
         match run_cmd(&cmd) {
             Ok(()) => 1,
             Err(_) => 0,
@@ -564,18 +1866,580 @@ fn register_extra_lua_functions(lua: &Lua<'_>) -> Result<()> {
             Err(_) => return 0,
         };
 
-        let cmd = format!("lua {code}");
-        match run_cmd(&cmd) {
+        match run_lua(&code) {
             Ok(()) => 1,
             Err(_) => 0,
         }
     }
 
+    extern "C" fn lua_exec_lua_file_with_args(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let path = match lua.check_string_strict(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let args = match unsafe { lua_type(l, 2) } {
+            LUA_TNONE | LUA_TNIL => Vec::new(),
+            _ => match lua.check_string_list(2) {
+                Ok(args) => args,
+                Err(_) => return 0,
+            },
+        };
+
+        match run_lua(&exec_lua_file_cmd(&path, &args)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_safe_mode(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        set_safe_mode(c_int::from(lua.to_boolean(1)))
+    }
+
+    extern "C" fn lua_reset(_l: *mut LuaState) -> c_int {
+        reset_state()
+    }
+
+    extern "C" fn lua_set_skip_noop(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        set_skip_noop(c_int::from(lua.to_boolean(1)))
+    }
+
+    extern "C" fn lua_set_strict_options(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        set_strict_options(c_int::from(lua.to_boolean(1)))
+    }
+
+    extern "C" fn lua_set_defer_commands(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        set_defer_commands(c_int::from(lua.to_boolean(1)))
+    }
+
+    extern "C" fn lua_defer_until(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let condition_fn = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let action_fn = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        // Defaults to checking every 100ms for up to 10 seconds
+        let interval_ms = match unsafe { lua_type(l, 3) } {
+            LUA_TNONE | LUA_TNIL => 100,
+            _ => match lua.check_integer(3) {
+                Ok(n) => n,
+                Err(_) => return 0,
+            },
+        };
+
+        let max_polls = match unsafe { lua_type(l, 4) } {
+            LUA_TNONE | LUA_TNIL => 100,
+            _ => match lua.check_integer(4) {
+                Ok(n) => n,
+                Err(_) => return 0,
+            },
+        };
+
+        match defer_until_code(&condition_fn, &action_fn, interval_ms, max_polls)
+            .and_then(|code| run_lua(&code))
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_version(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        lua.create_table(0, 2);
+
+        lua.push_string(env!("CARGO_PKG_VERSION"));
+        if lua.set_field(-2, "rns").is_err() {
+            return 0;
+        }
+
+        if lua.eval("tostring(vim.version())").is_ok() && lua.set_field(-2, "nvim").is_err() {
+            return 0;
+        }
+
+        1
+    }
+
+    lua.ensure_stack(1)?;
+
     lua.push_cclosure(lua_autocmd, 0);
     lua.set_field(-2, "autocmd")?;
 
     lua.push_cclosure(lua_exec, 0);
     lua.set_field(-2, "exec_lua")?;
 
+    lua.push_cclosure(lua_exec_lua_file_with_args, 0);
+    lua.set_field(-2, "exec_lua_file_with_args")?;
+
+    lua.push_cclosure(lua_set_safe_mode, 0);
+    lua.set_field(-2, "set_safe_mode")?;
+
+    lua.push_cclosure(lua_reset, 0);
+    lua.set_field(-2, "reset")?;
+
+    lua.push_cclosure(lua_set_strict_options, 0);
+    lua.set_field(-2, "set_strict_options")?;
+
+    lua.push_cclosure(lua_set_skip_noop, 0);
+    lua.set_field(-2, "set_skip_noop")?;
+
+    lua.push_cclosure(lua_set_defer_commands, 0);
+    lua.set_field(-2, "set_defer_commands")?;
+
+    lua.push_cclosure(lua_notify_once, 0);
+    lua.set_field(-2, "notify_once")?;
+
+    lua.push_cclosure(lua_defer_until, 0);
+    lua.set_field(-2, "defer_until")?;
+
+    lua.push_cclosure(lua_version, 0);
+    lua.set_field(-2, "version")?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockValue};
+
+    #[test]
+    fn map_is_recursive_and_takes_the_default_opts() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        keymap::set_default_opts(keymap::KeymapOpts::parse("silent").unwrap());
+        mock::push(mock::string("n"));
+        mock::push(mock::string("x"));
+        mock::push(mock::string(":y<CR>"));
+        let plain = lua_map(lua.state);
+
+        mock::push(mock::table([("noremap", true.into())]));
+        let noremap = lua_map(lua.state);
+        keymap::set_default_opts(keymap::KeymapOpts::default());
+
+        assert_eq!((plain, noremap), (1, 1));
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.keymap.set('n', 'x', ':y<CR>', { silent = true, remap = true })",
+                "lua vim.keymap.set('n', 'x', ':y<CR>', { silent = true })",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_string_strict_rejects_interior_nul() {
+        let lua = mock::lua();
+        mock::push(mock::string("ok"));
+        mock::push(mock::string("a\0b"));
+
+        assert_eq!(lua.check_string_strict(1).unwrap(), "ok");
+        assert!(matches!(
+            lua.check_string_strict(2),
+            Err(Error::StringConversion)
+        ));
+    }
+
+    #[test]
+    fn fnameescape_cmd_escapes_the_file_at_runtime() {
+        assert_eq!(fnameescape_cmd("split", None), "vim.cmd('split')");
+        assert_eq!(
+            fnameescape_cmd("edit", Some("my file")),
+            "vim.cmd('edit ' .. vim.fn.fnameescape('my file'))"
+        );
+    }
+
+    #[test]
+    fn version_reports_the_crate_and_neovim_versions() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_extra_lua_functions(&lua).unwrap();
+        let version = mock::get(-1).field("version");
+
+        mock::set_result("return tostring(vim.version())", mock::string("0.10.2"));
+        let versions = mock::call(&version, []).remove(0);
+        assert_eq!(
+            versions.field("rns").as_str(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(versions.field("nvim").as_str(), Some("0.10.2"));
+    }
+
+    #[test]
+    fn ensure_stack_is_requested_before_pushes() {
+        let register: [fn(&Lua<'_>) -> Result<()>; 14] = [
+            register_extra_lua_functions,
+            register_keymap_functions,
+            register_command_functions,
+            register_buffer_functions,
+            register_sign_functions,
+            register_extmark_functions,
+            register_nvim_interop_functions,
+            register_plugin_functions,
+            register_window_functions,
+            register_job_functions,
+            register_lsp_functions,
+            register_action_functions,
+            register_setup_functions,
+            register_transaction_functions,
+        ];
+
+        for register in register {
+            let lua = mock::lua();
+            lua.create_table(0, 0);
+            register(&lua).unwrap();
+
+            let calls = mock::calls();
+            let first_check = calls.iter().position(|c| *c == "lua_checkstack");
+            let first_push = calls.iter().position(|c| *c == "lua_pushcclosure");
+            assert!(first_check.unwrap() < first_push.unwrap());
+
+            // A full stack fails the registration before anything is pushed
+            let lua = mock::lua();
+            lua.create_table(0, 0);
+            mock::fill_stack();
+            assert!(matches!(register(&lua), Err(Error::StackOverflow)));
+            assert!(!mock::calls().contains(&"lua_pushcclosure"));
+        }
+    }
+
+    #[test]
+    fn opt_sets_boolean_options_without_a_value() {
+        let _serial = mock::serial();
+        mock::lua();
+        let is_bool = format!(
+            "return ({} or {{}}).type == 'boolean'",
+            interop::option_info_expr("number").unwrap()
+        );
+        mock::set_result(&is_bool, true.into());
+
+        let opt = MockValue::Function(lua_opt, Default::default());
+        for value in ["true", "false", "off"] {
+            mock::call(&opt, ["number".into(), "".into(), value.into()]);
+        }
+        assert_eq!(
+            mock::commands(),
+            ["set number", "set nonumber", "set nonumber"]
+        );
+    }
+
+    #[test]
+    fn notify_once_notifies_once_per_key_until_reset() {
+        let _serial = mock::serial();
+        mock::lua();
+        let key = "rns-test:deprecated";
+        notify_once(key, "Deprecated", 3).unwrap();
+        notify_once(key, "Deprecated", 3).unwrap();
+        notify_once("rns-test:other", "Other", 2).unwrap();
+        reset_state();
+        notify_once(key, "Deprecated", 3).unwrap();
+
+        let notified: Vec<_> = mock::commands()
+            .into_iter()
+            .filter(|cmd| cmd.starts_with("lua vim.notify("))
+            .collect();
+        assert_eq!(
+            notified,
+            [
+                "lua vim.notify('Deprecated', 3)",
+                "lua vim.notify('Other', 2)",
+                "lua vim.notify('Deprecated', 3)",
+            ]
+        );
+    }
+
+    #[test]
+    fn lua_quote_escapes_quotes_and_control_characters() {
+        assert_eq!(lua_quote("it's"), r"'it\'s'");
+        assert_eq!(lua_quote("a\\b\nc"), r"'a\\b\nc'");
+        assert_eq!(lua_quote("\t"), r"'\009'");
+    }
+
+    #[test]
+    fn check_string_list_converts_numbers() {
+        let lua = mock::lua();
+        mock::push(mock::list([mock::string("a"), MockValue::Number(2.0)]));
+        assert_eq!(lua.check_string_list(1).unwrap(), ["a", "2"]);
+
+        mock::push(mock::list([MockValue::Bool(true)]));
+        assert!(lua.check_string_list(-1).is_err());
+    }
+
+    #[test]
+    fn value_to_lua_keeps_the_type() {
+        assert_eq!(Value::Nil.to_lua(), "nil");
+        assert_eq!(Value::Bool(true).to_lua(), "true");
+        assert_eq!(Value::Number(4.0).to_lua(), "4");
+        assert_eq!(Value::Number(f64::NEG_INFINITY).to_lua(), "-math.huge");
+        assert_eq!(Value::String("4".to_string()).to_lua(), "'4'");
+    }
+
+    #[test]
+    fn lua_long_string_picks_an_unused_level() {
+        assert_eq!(lua_long_string("plain"), "[[plain]]");
+        assert_eq!(lua_long_string("a]]b"), "[=[a]]b]=]");
+        assert_eq!(lua_long_string("ends]"), "[=[ends]]=]");
+        assert_eq!(lua_long_string("\nx"), "[[\n\nx]]");
+    }
+
+    #[test]
+    fn fn_paths_allow_dotted_names_and_require() {
+        assert!(validate_fn_path("vim.lsp.buf.format").is_ok());
+        assert!(validate_fn_path("require('telescope.builtin').find_files").is_ok());
+        assert!(validate_fn_path("require(\"mod\")").is_ok());
+        assert!(validate_fn_path("require('a').b()").is_err());
+        assert!(validate_fn_path("os.execute('x')").is_err());
+    }
+
+    #[test]
+    fn identifiers_and_highlight_groups_are_validated() {
+        assert!(validate_identifier("tabstop").is_ok());
+        assert!(validate_identifier("_x1").is_ok());
+        assert!(validate_identifier("1x").is_err());
+        assert!(validate_identifier("a.b").is_err());
+
+        assert!(validate_hl_group("DiagnosticVirtualText.Error").is_ok());
+        assert!(validate_hl_group("@comment.lua").is_ok());
+        assert!(validate_hl_group("@").is_err());
+        assert!(validate_hl_group("Bad Group").is_err());
+    }
+
+    #[test]
+    fn runtime_cmd_escapes_patterns() {
+        assert_eq!(
+            runtime_cmd(&["plugin/a b.lua", "x|y"], true),
+            r"vim.cmd('runtime! plugin/a\\ b.lua x\\|y')"
+        );
+        assert_eq!(
+            runtime_cmd(&["colors/*.vim"], false),
+            "vim.cmd('runtime colors/*.vim')"
+        );
+    }
+
+    #[test]
+    fn get_global_sees_a_set_global() {
+        let lua = mock::lua();
+        assert!(!lua.is_neovim_host());
+
+        mock::set_global("plugins", mock::table([]));
+        assert_eq!(lua.get_global("plugins").unwrap(), LUA_TTABLE);
+        assert!(lua.is_table_on_top());
+        lua.pop(1);
+
+        assert_eq!(lua.get_global("missing").unwrap(), LUA_TNIL);
+        assert!(lua.is_nil_on_top());
+        lua.pop(1);
+
+        mock::set_global("vim", mock::table([]));
+        assert!(lua.is_neovim_host());
+    }
+
+    #[test]
+    fn runs_queued_commands_first_and_schedules_in_fast_events() {
+        let lua = mock::lua();
+        let queued = vec!["echo 'queued'".to_string()];
+
+        mock::set_result("return vim.in_fast_event()", false.into());
+        run_cmd_in(Some(&lua), queued, "echo 'now'").unwrap();
+        assert_eq!(mock::commands(), ["echo 'queued'", "echo 'now'"]);
+
+        let lua = mock::lua();
+        mock::set_result("return vim.in_fast_event()", true.into());
+        run_cmd_in(Some(&lua), Vec::new(), "echo 'later'").unwrap();
+        assert!(mock::commands().is_empty());
+        assert_eq!(
+            mock::chunks().last().unwrap(),
+            r"vim.schedule(function() vim.cmd('echo \'later\'') end)"
+        );
+    }
+
+    #[test]
+    fn strict_options_reject_unknown_options() {
+        let _serial = mock::serial();
+        mock::lua();
+        mock::set_result(
+            "return (pcall(vim.api.nvim_get_option_info2, 'foobar', {}))",
+            false.into(),
+        );
+        let opt = MockValue::Function(lua_opt, Default::default());
+
+        set_strict_options(1);
+        let results = mock::call(&opt, ["foobar".into(), "".into(), "x".into()]);
+        set_strict_options(0);
+
+        assert!(results.is_empty());
+        assert_eq!(
+            mock::commands(),
+            [format!(
+                "lua vim.notify({}, vim.log.levels.ERROR)",
+                lua_quote("rns: unknown option 'foobar'")
+            )]
+        );
+    }
+
+    #[test]
+    fn map_accepts_an_opts_table() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        lua.push_cclosure(lua_map, 0);
+        let map = mock::get(-1);
+
+        let opts = mock::table([("silent", true.into()), ("desc", "Save".into())]);
+        let results = mock::call(
+            &map,
+            ["n".into(), "<leader>w".into(), ":w<CR>".into(), opts],
+        );
+
+        // Bindings report success by returning a value
+        assert_eq!(results.len(), 1);
+        let commands = mock::commands();
+        assert!(commands.last().unwrap().contains("desc = 'Save'"));
+        assert!(commands.last().unwrap().contains("silent = true"));
+    }
+
+    #[test]
+    fn reset_state_empties_every_store() {
+        static AUGROUP_CLEARED: AtomicBool = AtomicBool::new(false);
+
+        extern "C" fn reset_in_augroup(_l: *mut LuaState) -> c_int {
+            reset_state();
+            AUGROUP_CLEARED.store(interop::current_augroup().is_none(), Ordering::Relaxed);
+            0
+        }
+
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_job_functions(&lua).unwrap();
+        register_action_functions(&lua).unwrap();
+        register_nvim_interop_functions(&lua).unwrap();
+        let rns = mock::get(-1);
+
+        // Fill every store: a job, default map opts, a buffer mapping, an
+        // action, and a notify_once key
+        let jobstart = rns.field("jobstart");
+        mock::set_result("return vim.fn.jobstart({ 'ls' }, {})", 7.0.into());
+        mock::call(&jobstart, [r#"["ls"]"#.into()]);
+        keymap::set_default_opts(keymap::KeymapOpts::parse("silent").unwrap());
+        keymap::emit_keymap(&keymap::KeymapSpec {
+            mode: "n".to_string(),
+            lhs: "K".to_string(),
+            rhs: keymap::Rhs::Command(":help<CR>".to_string()),
+            opts: keymap::KeymapOpts::default(),
+            buffer: Some(2),
+        })
+        .unwrap();
+        mock::call(
+            &rns.field("register_action"),
+            ["format".into(), "vim.lsp.buf.format".into(), "".into()],
+        );
+        notify_once("reset", "once", 2).unwrap();
+
+        // The augroup scope is only active inside its callback
+        let callback = MockValue::Function(reset_in_augroup, Default::default());
+        mock::call(&rns.field("augroup"), ["lsp".into(), callback]);
+        assert!(AUGROUP_CLEARED.load(Ordering::Relaxed));
+
+        let before = mock::commands().len();
+        assert_eq!(job::stop_all_jobs(), 1);
+        assert_eq!(keymap::nvim_clear_buffer_keymaps(2), 1);
+        notify_once("reset", "once", 2).unwrap();
+        let commands = mock::commands().split_off(before);
+
+        assert_eq!(
+            commands,
+            [format!("lua vim.notify({}, 2)", lua_quote("once"))]
+        );
+        assert_eq!(keymap::default_opts(), keymap::KeymapOpts::default());
+        assert!(mock::commands().contains(&"lua _G.plugins = {}".to_string()));
+
+        mock::call(&rns.field("get_actions"), []);
+        assert_eq!(mock::chunks().last().unwrap(), "return {}");
+    }
+
+    #[test]
+    fn skips_setting_an_option_to_its_current_value() {
+        let _serial = mock::serial();
+        mock::lua();
+        mock::set_result(
+            "return select(2, pcall(function() return tostring(vim.o['path']) \
+             == tostring('.,**') end)) == true",
+            true.into(),
+        );
+        let opt = MockValue::Function(lua_opt, Default::default());
+
+        set_skip_noop(1);
+        mock::call(&opt, ["path".into(), ".".into(), "**".into()]);
+        mock::call(&opt, ["suffixesadd".into(), ".rs".into(), ".lua".into()]);
+        set_skip_noop(0);
+
+        assert_eq!(mock::commands(), ["set suffixesadd=.rs,.lua"]);
+    }
+
+    #[test]
+    fn callbacks_can_be_scheduled() {
+        assert_eq!(
+            callback_ref("vim.cmd.redraw", false).unwrap(),
+            "function(...) return vim.cmd.redraw(...) end"
+        );
+        assert_eq!(
+            callback_ref("vim.cmd.redraw", true).unwrap(),
+            "vim.schedule_wrap(function(...) return vim.cmd.redraw(...) end)"
+        );
+        assert!(callback_ref("os.execute('x')", true).is_err());
+    }
+
+    #[test]
+    fn defer_until_code_polls_a_bounded_number_of_times() {
+        let code = defer_until_code("require('lsp').ready", "vim.cmd.LspInfo", 50, 20).unwrap();
+        assert!(code.contains(
+            "local condition, action = function(...) return require('lsp').ready(...) end"
+        ));
+        assert!(code.contains("timer:start(0, 50, vim.schedule_wrap(function()"));
+        assert!(code.contains("elseif polls >= 20 then"));
+        assert!(code.contains("'rns: gave up waiting to run ' .. 'vim.cmd.LspInfo'"));
+
+        assert!(defer_until_code("ready", "go", 0, 10).is_err());
+        assert!(defer_until_code("ready", "go", 10, 0).is_err());
+        assert!(defer_until_code("ready", "go", 10, MAX_DEFER_POLLS + 1).is_err());
+        assert!(defer_until_code("ready()", "go", 10, 10).is_err());
+    }
+}
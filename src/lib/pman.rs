@@ -1,14 +1,152 @@
-use std::ffi::CString;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use std::sync::{Mutex, OnceLock};
 
-use crate::extract_c_string;
+use crate::{extract_c_string, extract_c_string_strict};
 
 // Plugin configuration state
 static mut CURRENT_PLUGIN: Option<String> = None;
 static mut PLUGIN_CONFIG: Option<String> = None;
 
+// History depth used when cloning plugins, where 0 means a full clone
+static CLONE_DEPTH: AtomicU32 = AtomicU32::new(1);
+
+/// Configuration accumulated by a builder returned from `rns.plugin_config`
+struct ConfigBuilder {
+    plugin: String,
+    config: String,
+}
+
+// Builders that have not been applied yet, keyed by the id their methods capture
+static CONFIG_BUILDERS: OnceLock<Mutex<HashMap<i64, ConfigBuilder>>> = OnceLock::new();
+static NEXT_BUILDER_ID: AtomicI64 = AtomicI64::new(1);
+
+fn config_builders() -> &'static Mutex<HashMap<i64, ConfigBuilder>> {
+    CONFIG_BUILDERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Empties the plugin registry and drops any unfinished configuration, for `rns.reset`
+///
+/// # Safety
+///
+/// This function modifies static mutable state and must not be called concurrently.
+pub(crate) unsafe fn reset() -> crate::Result<()> {
+    CURRENT_PLUGIN = None;
+    PLUGIN_CONFIG = None;
+    if let Ok(mut builders) = config_builders().lock() {
+        builders.clear();
+    }
+
+    crate::run_lua("_G.plugins = {}")
+}
+
+/// Expands `owner/repo` shorthand into a GitHub URL
+///
+/// Shorthand is a single slash between two non-empty segments made of the
+/// characters GitHub allows in account and repository names, with no scheme.
+/// `file://` URLs are reduced to the local path they name. Anything else,
+/// including full URLs, `git@host:path` SSH remotes, and relative paths such
+/// as `./plugin`, is returned unchanged.
+fn normalize_plugin_url(url: &str) -> String {
+    if let Some(path) = url.strip_prefix("file://") {
+        return path.to_string();
+    }
+
+    let is_segment = |segment: &str| {
+        !segment.is_empty()
+            && !segment.starts_with('.')
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    };
+
+    match url.split_once('/') {
+        Some((owner, repo)) if is_segment(owner) && is_segment(repo) => {
+            format!("https://github.com/{owner}/{repo}")
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Checks whether a normalized plugin URL is a local directory rather than a git remote
+///
+/// Local plugins are put on the runtimepath where they are instead of cloned.
+fn is_local_plugin(url: &str) -> bool {
+    url.starts_with('/')
+}
+
+/// Builds the Lua code storing `entry` as the registration of plugin `name`
+///
+/// Registering a name again with an identical entry leaves the existing one,
+/// and any configuration attached to it, untouched. An entry for the same URL
+/// that differs in other fields, such as a changed `ref`, replaces it but
+/// keeps the configuration. A different URL replaces the entry after warning
+/// about the conflict, since it usually means a typo or two configs
+/// disagreeing.
+fn register_entry_cmd(name: &str, url: &str, entry: &str) -> String {
+    let (name, url) = (crate::lua_quote(name), crate::lua_quote(url));
+
+    format!(
+        r"
+        if not _G.plugins then _G.plugins = {{}} end
+        local existing = _G.plugins[{name}]
+        local entry = {entry}
+        if existing and existing.url == {url} then
+            local same = true
+            for key, value in pairs(entry) do
+                if not vim.deep_equal(existing[key], value) then
+                    same = false
+                    break
+                end
+            end
+            if same then return end
+            entry.config = existing.config
+        elseif existing then
+            vim.notify('rns: plugin ' .. {name} .. ' was registered from ' .. existing.url
+                .. ', replacing it with ' .. {url}, vim.log.levels.WARN)
+        end
+        _G.plugins[{name}] = entry
+    "
+    )
+}
+
+/// Checks that a plugin name is usable as a single directory under the managed plugin dir
+fn validate_plugin_name(name: &str) -> crate::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        Err(crate::Error::InvalidArgument)
+    } else {
+        Ok(())
+    }
+}
+
+/// Builds an expression evaluating to the commit checked out for plugin `name`
+///
+/// Evaluates to nil unless the plugin is registered and installed in the
+/// managed directory. Local plugins are not managed, so they are nil too.
+fn plugin_commit_expr(name: &str) -> crate::Result<String> {
+    validate_plugin_name(name)?;
+
+    Ok(format!(
+        r"(function(name)
+            local plugin = _G.plugins and _G.plugins[name]
+            if not plugin or plugin['local'] then return nil end
+            local plugin_path = vim.fn.stdpath('data') .. '/site/pack/managed/start/' .. name
+            if vim.fn.isdirectory(plugin_path) == 0 then return nil end
+            local sha = vim.fn.system({{'git', '-C', plugin_path, 'rev-parse', 'HEAD'}})
+            if vim.v.shell_error ~= 0 then return nil end
+            return vim.trim(sha)
+        end)({})",
+        crate::lua_quote(name)
+    ))
+}
+
 /// Registers a plugin with the plugin manager
 ///
+/// `url` may be a full git URL, `owner/repo` shorthand for a GitHub
+/// repository, or an absolute path or `file://` URL naming a local checkout.
+///
 /// # Safety
 ///
 /// `name` and `url` must be valid null-terminated C strings.
@@ -20,22 +158,181 @@ pub unsafe extern "C" fn register_plugin(name: *const c_char, url: *const c_char
         Err(_) => return 0,
     };
 
-    let url_str = match extract_c_string(url) {
+    let url_str = match extract_c_string_strict(url) {
         Ok(s) => s,
         Err(_) => return 0,
     };
 
-    let cmd = format!(
-        "if not _G.plugins then _G.plugins = {{}} end;\
-         _G.plugins['{name_str}'] = {{ url = '{url_str}', enabled = true }}"
-    );
-
-    match crate::run_cmd(&format!("lua {cmd}")) {
+    match register_plugin_url(&name_str, &url_str) {
         Ok(()) => 1,
         Err(_) => 0,
     }
 }
 
+/// Registers plugin `name` from `url`, normalizing shorthand and `file://` URLs
+fn register_plugin_url(name: &str, url: &str) -> crate::Result<()> {
+    let url = normalize_plugin_url(url);
+    let entry = format!(
+        "{{ url = {}, enabled = true, ['local'] = {} }}",
+        crate::lua_quote(&url),
+        is_local_plugin(&url)
+    );
+
+    crate::run_lua(&register_entry_cmd(name, &url, &entry))
+}
+
+/// A plugin specification in the style of lazy.nvim
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginSpec {
+    pub name: String,
+    /// Clone URL, with `owner/repo` shorthand already expanded, or the path of a local plugin
+    pub url: String,
+    /// Whether `url` is a local directory to use in place instead of cloning
+    pub local: bool,
+    /// Branch, tag, or commit to check out
+    pub git_ref: Option<String>,
+    /// Shell command run after the plugin is installed or updated
+    pub build: Option<String>,
+    /// Names of plugins that must be loaded first
+    pub deps: Vec<String>,
+    /// Autocmd event that the plugin's configuration waits for, if any
+    pub event: Option<String>,
+    /// Pattern the event must match, such as `VeryLazy` for `User` events
+    pub event_pattern: Option<String>,
+    pub lazy: bool,
+    pub enabled: bool,
+}
+
+impl PluginSpec {
+    /// Reads a spec from the table at the given absolute stack index
+    ///
+    /// `name` and `url` are required; every other field is optional, with
+    /// `enabled` defaulting to true.
+    fn from_lua(lua: &crate::Lua<'_>, idx: c_int) -> crate::Result<Self> {
+        lua.check_table(idx)?;
+        lua.ensure_stack(1)?;
+
+        let name = spec_string(lua, idx, "name")?.ok_or(crate::Error::InvalidArgument)?;
+        let url = spec_string(lua, idx, "url")?.ok_or(crate::Error::InvalidArgument)?;
+        if name.is_empty() || url.is_empty() {
+            return Err(crate::Error::InvalidArgument);
+        }
+
+        let deps = match lua.get_field(idx, "deps")? {
+            crate::LUA_TNIL => Ok(Vec::new()),
+            _ => lua.check_string_list(-1),
+        };
+        lua.pop(1);
+
+        let (event, event_pattern) = match spec_string(lua, idx, "event")? {
+            Some(event) => {
+                let (event, pattern) = normalize_event(&event)?;
+                (Some(event), pattern)
+            }
+            None => (None, None),
+        };
+
+        let url = normalize_plugin_url(&url);
+        Ok(Self {
+            name,
+            local: is_local_plugin(&url),
+            url,
+            git_ref: spec_string(lua, idx, "ref")?,
+            build: spec_string(lua, idx, "build")?,
+            deps: deps?,
+            event,
+            event_pattern,
+            lazy: spec_bool(lua, idx, "lazy")?.unwrap_or(false),
+            enabled: spec_bool(lua, idx, "enabled")?.unwrap_or(true),
+        })
+    }
+
+    /// Renders the entry stored in `_G.plugins` for this spec
+    fn to_lua(&self) -> String {
+        let mut fields = vec![
+            format!("url = {}", crate::lua_quote(&self.url)),
+            format!("enabled = {}", self.enabled),
+            format!("lazy = {}", self.lazy),
+            format!("['local'] = {}", self.local),
+        ];
+
+        if let Some(git_ref) = &self.git_ref {
+            fields.push(format!("ref = {}", crate::lua_quote(git_ref)));
+        }
+
+        if let Some(build) = &self.build {
+            fields.push(format!("build = {}", crate::lua_quote(build)));
+        }
+
+        if let Some(event) = &self.event {
+            fields.push(format!("event = {}", crate::lua_quote(event)));
+        }
+
+        if let Some(pattern) = &self.event_pattern {
+            fields.push(format!("pattern = {}", crate::lua_quote(pattern)));
+        }
+
+        let deps = self
+            .deps
+            .iter()
+            .map(|dep| crate::lua_quote(dep))
+            .collect::<Vec<_>>();
+        fields.push(format!("deps = {{ {} }}", deps.join(", ")));
+
+        format!("{{ {} }}", fields.join(", "))
+    }
+}
+
+/// Splits a spec's `event` trigger into the autocmd event and pattern it waits for
+///
+/// The trigger may be written with an `event:` prefix. `VeryLazy` is not a
+/// real event but the `User VeryLazy` event fired once after startup, and
+/// `User Name` waits for the user event `Name`.
+fn normalize_event(trigger: &str) -> crate::Result<(String, Option<String>)> {
+    let trigger = trigger.strip_prefix("event:").unwrap_or(trigger);
+    if trigger == "VeryLazy" {
+        return Ok(("User".to_string(), Some("VeryLazy".to_string())));
+    }
+
+    let mut words = trigger.split_whitespace();
+    let event = words.next().ok_or(crate::Error::InvalidArgument)?;
+    crate::validate_identifier(event)?;
+
+    let pattern = words.next().map(str::to_string);
+    if words.next().is_some() {
+        return Err(crate::Error::InvalidArgument);
+    }
+
+    Ok((event.to_string(), pattern))
+}
+
+/// Reads an optional string field of a spec table
+fn spec_string(lua: &crate::Lua<'_>, idx: c_int, key: &str) -> crate::Result<Option<String>> {
+    let value = match lua.get_field(idx, key)? {
+        crate::LUA_TNIL => Ok(None),
+        crate::LUA_TSTRING => lua.check_string(-1).map(Some),
+        _ => Err(crate::Error::InvalidArgument),
+    };
+    lua.pop(1);
+    value
+}
+
+/// Reads an optional boolean field of a spec table
+fn spec_bool(lua: &crate::Lua<'_>, idx: c_int, key: &str) -> crate::Result<Option<bool>> {
+    let value = match lua.get_field(idx, key)? {
+        crate::LUA_TNIL => Ok(None),
+        crate::LUA_TBOOLEAN => Ok(Some(lua.to_boolean(-1))),
+        _ => Err(crate::Error::InvalidArgument),
+    };
+    lua.pop(1);
+    value
+}
+
+/// Stores a plugin spec in `_G.plugins`, replacing any previous registration that differs
+fn register_plugin_spec(spec: &PluginSpec) -> crate::Result<()> {
+    crate::run_lua(&register_entry_cmd(&spec.name, &spec.url, &spec.to_lua()))
+}
+
 /// Applies configuration to a plugin
 ///
 /// # Safety
@@ -54,16 +351,33 @@ pub unsafe extern "C" fn configure_plugin(name: *const c_char, config: *const c_
         Err(_) => return 0,
     };
 
-    let cmd = format!(
-        "if _G.plugins and _G.plugins['{name_str}'] then _G.plugins['{name_str}'].config = [===[{config_str}]===] end"
-    );
-
-    match crate::run_cmd(&format!("lua {cmd}")) {
+    match apply_plugin_config(&name_str, &config_str) {
         Ok(()) => 1,
         Err(_) => 0,
     }
 }
 
+/// Stores `config` as the Lua code run when the registered plugin `name` is configured
+fn apply_plugin_config(name: &str, config: &str) -> crate::Result<()> {
+    let name = crate::lua_quote(name);
+    crate::run_lua(&format!(
+        "if _G.plugins and _G.plugins[{name}] then _G.plugins[{name}].config = {} end",
+        crate::lua_long_string(config)
+    ))
+}
+
+/// Builds the config line setting up an LSP server with default options
+fn server_setup_line(server: &str) -> String {
+    format!("require('lspconfig').{server}.setup({{}});\n")
+}
+
+/// Builds the config line adding a default mapping to a plugin's setup
+fn plugin_mapping_line(plugin: &str, mode: &str, key: &str, action: &str) -> String {
+    format!(
+        "require('{plugin}').setup({{ defaults = {{ mappings = {{ {mode} = {{ ['{key}'] = '{action}' }} }} }} }});\n"
+    )
+}
+
 /// Begins configuration for a plugin
 ///
 /// # Safety
@@ -92,10 +406,10 @@ pub unsafe extern "C" fn plugin_config_begin(plugin_name: *const c_char) -> c_in
 #[no_mangle]
 pub unsafe extern "C" fn plugin_config_end() -> c_int {
     if let (Some(plugin), Some(config)) = (&CURRENT_PLUGIN, &PLUGIN_CONFIG) {
-        let result = configure_plugin(
-            CString::new(plugin.as_str()).unwrap().as_ptr(),
-            CString::new(config.as_str()).unwrap().as_ptr(),
-        );
+        let result = match apply_plugin_config(plugin, config) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        };
 
         CURRENT_PLUGIN = None;
         PLUGIN_CONFIG = None;
@@ -106,6 +420,52 @@ pub unsafe extern "C" fn plugin_config_end() -> c_int {
     }
 }
 
+/// Starts a builder accumulating configuration for `plugin`, returning its id
+fn new_config_builder(plugin: &str) -> crate::Result<i64> {
+    if plugin.is_empty() {
+        return Err(crate::Error::InvalidArgument);
+    }
+
+    let id = NEXT_BUILDER_ID.fetch_add(1, Ordering::Relaxed);
+    let mut builders = config_builders()
+        .lock()
+        .map_err(|_| crate::Error::CommandExecution)?;
+    builders.insert(
+        id,
+        ConfigBuilder {
+            plugin: plugin.to_string(),
+            config: String::new(),
+        },
+    );
+
+    Ok(id)
+}
+
+/// Appends a config line built from the plugin name to the builder `id`
+///
+/// Fails once the builder has been applied.
+fn extend_config_builder(id: i64, line: impl FnOnce(&str) -> String) -> crate::Result<()> {
+    let mut builders = config_builders()
+        .lock()
+        .map_err(|_| crate::Error::CommandExecution)?;
+    let builder = builders.get_mut(&id).ok_or(crate::Error::InvalidArgument)?;
+    let line = line(&builder.plugin);
+    builder.config.push_str(&line);
+
+    Ok(())
+}
+
+/// Applies and discards the builder `id`, as `plugin_config_end` does for the C API
+fn apply_config_builder(id: i64) -> crate::Result<()> {
+    let builder = config_builders()
+        .lock()
+        .map_err(|_| crate::Error::CommandExecution)?
+        .remove(&id)
+        .ok_or(crate::Error::InvalidArgument)?;
+
+    apply_plugin_config(&builder.plugin, &builder.config)
+}
+
 /// Adds an LSP server to the current plugin configuration
 ///
 /// # Safety
@@ -118,7 +478,7 @@ pub unsafe extern "C" fn plugin_config_add_server(server_name: *const c_char) ->
     match extract_c_string(server_name) {
         Ok(server) => {
             if let Some(config) = &mut PLUGIN_CONFIG {
-                config.push_str(&format!("require('lspconfig').{server}.setup({{}});\n"));
+                config.push_str(&server_setup_line(&server));
                 1
             } else {
                 0
@@ -182,8 +542,11 @@ pub unsafe extern "C" fn plugin_config_set_mapping(
     ) {
         (Ok(plugin_str), Ok(mode_str), Ok(key_str), Ok(action_str)) => {
             if let Some(config) = &mut PLUGIN_CONFIG {
-                config.push_str(&format!(
-                    "require('{plugin_str}').setup({{ defaults = {{ mappings = {{ {mode_str} = {{ ['{key_str}'] = '{action_str}' }} }} }} }});\n"
+                config.push_str(&plugin_mapping_line(
+                    &plugin_str,
+                    &mode_str,
+                    &key_str,
+                    &action_str,
                 ));
                 1
             } else {
@@ -228,6 +591,34 @@ pub unsafe extern "C" fn plugin_config_add_keymap(
     }
 }
 
+/// Sets how much history is fetched when installing plugins
+///
+/// A depth of 0 performs a full clone, which some plugins need for tags or
+/// history. The default is a shallow clone of depth 1.
+#[no_mangle]
+pub extern "C" fn set_clone_depth(depth: c_int) -> c_int {
+    match u32::try_from(depth) {
+        Ok(depth) => {
+            CLONE_DEPTH.store(depth, Ordering::Relaxed);
+            1
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Builds the leading `git clone` arguments for the configured clone depth
+fn clone_args() -> String {
+    match CLONE_DEPTH.load(Ordering::Relaxed) {
+        0 => "'git', 'clone'".to_string(),
+        depth => format!("'git', 'clone', '--depth', '{depth}'"),
+    }
+}
+
+/// Builds the Lua code sourcing the plugin scripts of every installed plugin
+fn plugin_runtime_cmd() -> String {
+    crate::runtime_cmd(&["plugin/**/*.vim", "plugin/**/*.lua"], true)
+}
+
 /// Installs all registered plugins
 ///
 /// # Safety
@@ -236,8 +627,10 @@ pub unsafe extern "C" fn plugin_config_add_keymap(
 /// It should be called when Neovim is ready to load plugins.
 #[no_mangle]
 pub unsafe extern "C" fn install_plugins() -> c_int {
-    let cmd = r"
+    let cmd = format!(
+        r"
         if not _G.plugins then return end
+        {NOTIFY_ONCE_LUA}
         local data_dir = vim.fn.stdpath('data')
         local plugin_dir = data_dir .. '/site/pack/managed/start/'
 
@@ -245,77 +638,202 @@ pub unsafe extern "C" fn install_plugins() -> c_int {
             vim.fn.mkdir(plugin_dir, 'p')
         end
 
+        local pending = {{}}
+        for name, plugin in pairs(_G.plugins) do
+            if plugin.enabled and not plugin['local'] and vim.fn.isdirectory(plugin_dir .. name) == 0 then
+                table.insert(pending, name)
+            end
+        end
+        table.sort(pending)
+
+        -- Progress is a single message redrawn in place rather than one
+        -- notification per plugin
+        for i, name in ipairs(pending) do
+            local msg = ('Installing %d/%d: %s'):format(i, #pending, name)
+            vim.api.nvim_echo({{ {{ msg }} }}, false, {{}})
+            vim.cmd('redraw')
+            vim.fn.system({{{}, _G.plugins[name].url, plugin_dir .. name}})
+        end
+
+        if #pending > 0 then
+            vim.notify(('Installed %d plugin(s)'):format(#pending))
+        end
+
+        -- Local plugins are used where they are, so edits to the checkout
+        -- take effect without reinstalling
         for name, plugin in pairs(_G.plugins) do
             if plugin.enabled then
-                local plugin_path = plugin_dir .. name
-                if vim.fn.isdirectory(plugin_path) == 0 then
-                    vim.notify('Installing ' .. name .. '...')
-                    vim.fn.system({'git', 'clone', '--depth', '1', plugin.url, plugin_path})
+                local plugin_path = plugin['local'] and plugin.url or plugin_dir .. name
+                if plugin['local'] and vim.fn.isdirectory(plugin_path) == 0 then
+                    notify_once('local:' .. name,
+                        'rns: local plugin ' .. name .. ' not found at ' .. plugin_path,
+                        vim.log.levels.WARN)
+                else
+                    plugin.path = plugin_path
+                    vim.opt.rtp:prepend(plugin_path)
                 end
-                plugin.path = plugin_path
-                vim.opt.rtp:prepend(plugin_path)
             end
         end
 
         vim.cmd('packloadall')
-        vim.cmd('runtime! plugin/**/*.vim plugin/**/*.lua')
+        {}
         vim.cmd('silent! helptags ALL')
-    ";
+    ",
+        clone_args(),
+        plugin_runtime_cmd()
+    );
 
-    match crate::run_cmd(&format!("lua {cmd}")) {
+    match crate::run_lua(&cmd) {
         Ok(()) => 1,
         Err(_) => 0,
     }
 }
 
+// Defines `notify_once(key, msg, level)`, which shares the per-key guard of
+// `nvim_notify_once` so that `rns.reset` makes the messages show again.
+// Without the module loaded it falls back to deduping by message.
+const NOTIFY_ONCE_LUA: &str = r"
+    local notify_once = _G.rns_notify_once or function(_, msg, level)
+        vim.notify_once(msg, level)
+    end
+";
+
+// Defines `run_config(name, plugin)`, which runs a plugin's stored
+// configuration and retries once on the next event loop iteration on failure
+const RUN_CONFIG_LUA: &str = r#"
+    local function run_config(name, plugin)
+        local success, err = pcall(function()
+            local status, mod = pcall(require, name)
+            if status then
+                local chunk, err = loadstring(plugin.config)
+                if chunk then
+                    chunk()
+                else
+                    error("Failed to parse configuration: " .. err)
+                end
+            else
+                error("Module not found")
+            end
+        end)
+
+        if not success then
+            vim.schedule(function()
+                local retry, rerr = pcall(function()
+                    local status, mod = pcall(require, name)
+                    if status then
+                        local chunk = loadstring(plugin.config)
+                        if chunk then
+                            chunk()
+                        end
+                    end
+                end)
+
+                if not retry then
+                    notify_once('config:' .. name,
+                        'Cannot configure ' .. name .. ': ' .. tostring(err), vim.log.levels.WARN)
+                end
+            end)
+        end
+    end
+"#;
+
+// Fires `User VeryLazy` once, on the next event loop iteration after startup
+// has finished, or right away if it already has
+const VERY_LAZY_LUA: &str = r"
+    local function fire_very_lazy()
+        if vim.g.rns_very_lazy then return end
+        vim.g.rns_very_lazy = true
+        vim.api.nvim_exec_autocmds('User', { pattern = 'VeryLazy', modeline = false })
+    end
+
+    if vim.v.vim_did_enter == 1 then
+        vim.schedule(fire_very_lazy)
+    else
+        vim.api.nvim_create_autocmd({ 'VimEnter', 'UIEnter' }, {
+            group = vim.api.nvim_create_augroup('rns_very_lazy', { clear = true }),
+            once = true,
+            callback = function() vim.schedule(fire_very_lazy) end,
+        })
+    end
+";
+
 /// Loads configurations for all registered plugins
 ///
+/// Plugins registered with an `event` are configured the first time that
+/// event fires instead. A `VeryLazy` plugin registered after `User VeryLazy`
+/// has already fired is configured right away.
+///
 /// # Safety
 ///
 /// This function evaluates arbitrary Lua code stored in plugin configurations.
 /// It should be called after plugins are installed and Neovim is fully initialized.
 #[no_mangle]
 pub unsafe extern "C" fn load_plugin_configs() -> c_int {
-    let cmd = r#"
+    let cmd = format!(
+        r"{NOTIFY_ONCE_LUA}{RUN_CONFIG_LUA}
         if not _G.plugins then return end
         for name, plugin in pairs(_G.plugins) do
             if plugin.enabled and plugin.config then
-                local success, err = pcall(function()
-                    local status, mod = pcall(require, name)
-                    if status then
-                        local chunk, err = loadstring(plugin.config)
-                        if chunk then
-                            chunk()
-                        else
-                            error("Failed to parse configuration: " .. err)
-                        end
-                    else
-                        error("Module not found")
-                    end
-                end)
-
-                if not success then
-                    vim.schedule(function()
-                        local retry, rerr = pcall(function()
-                            local status, mod = pcall(require, name)
-                            if status then
-                                local chunk = loadstring(plugin.config)
-                                if chunk then
-                                    chunk()
-                                end
-                            end
-                        end)
-
-                        if not retry then
-                            vim.notify('Cannot configure ' .. name .. ': ' .. tostring(err), vim.log.levels.WARN)
-                        end
-                    end)
+                local fired = plugin.pattern == 'VeryLazy' and vim.g.rns_very_lazy
+                if plugin.event and not fired then
+                    vim.api.nvim_create_autocmd(plugin.event, {{
+                        pattern = plugin.pattern,
+                        once = true,
+                        callback = function() run_config(name, plugin) end,
+                    }})
+                else
+                    run_config(name, plugin)
                 end
             end
         end
-    "#;
+        {VERY_LAZY_LUA}
+    "
+    );
+
+    match crate::run_lua(&cmd) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Builds the Lua code that runs a single plugin's stored configuration
+///
+/// Unregistered plugins are reported once per session through `nvim_notify_once`.
+fn load_plugin_config_cmd(name: &str) -> String {
+    format!(
+        r"{NOTIFY_ONCE_LUA}{RUN_CONFIG_LUA}
+        local name = {}
+        local plugin = _G.plugins and _G.plugins[name]
+        if not plugin then
+            notify_once('unregistered:' .. name, 'Plugin ' .. name .. ' is not registered',
+                vim.log.levels.WARN)
+            return
+        end
+        if plugin.enabled and plugin.config then
+            run_config(name, plugin)
+        end
+    ",
+        crate::lua_quote(name)
+    )
+}
+
+/// Loads the configuration of a single registered plugin immediately
+///
+/// This lets callers sequence plugins that must be configured after others,
+/// instead of relying on the order of `load_plugin_configs`.
+///
+/// # Safety
+///
+/// `name` must be a valid null-terminated C string.
+/// This function evaluates arbitrary Lua code stored in the plugin configuration.
+#[no_mangle]
+pub unsafe extern "C" fn load_plugin_config(name: *const c_char) -> c_int {
+    let name_str = match extract_c_string(name) {
+        Ok(s) => s,
+        Err(_) => return 0,
+    };
 
-    match crate::run_cmd(&format!("lua {cmd}")) {
+    match crate::run_lua(&load_plugin_config_cmd(&name_str)) {
         Ok(()) => 1,
         Err(_) => 0,
     }
@@ -329,27 +847,150 @@ pub unsafe extern "C" fn load_plugin_configs() -> c_int {
 /// It should be called when Neovim is ready to update plugins.
 #[no_mangle]
 pub unsafe extern "C" fn update_plugins() -> c_int {
-    let cmd = r"
+    let cmd = format!(
+        r"
         if not _G.plugins then return end
         local data_dir = vim.fn.stdpath('data')
         local plugin_dir = data_dir .. '/site/pack/managed/start/'
 
         for name, plugin in pairs(_G.plugins) do
-            if plugin.enabled then
+            if plugin.enabled and not plugin['local'] then
                 local plugin_path = plugin_dir .. name
                 if vim.fn.isdirectory(plugin_path) == 1 then
                     vim.notify('Updating ' .. name)
-                    vim.fn.system({'git', '-C', plugin_path, 'pull', '--ff-only'})
+                    vim.fn.system({{'git', '-C', plugin_path, 'pull', '--ff-only'}})
                 end
             end
         end
 
         vim.cmd('packloadall')
-        vim.cmd('runtime! plugin/**/*.vim plugin/**/*.lua')
+        {}
         vim.cmd('silent! helptags ALL')
-    ";
+    ",
+        plugin_runtime_cmd()
+    );
 
-    match crate::run_cmd(&format!("lua {cmd}")) {
+    match crate::run_lua(&cmd) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+// Fetches each installed plugin and counts the upstream commits missing locally
+const CHECK_UPDATES_LUA: &str = r"
+    if not _G.plugins then return 0 end
+    local data_dir = vim.fn.stdpath('data')
+    local plugin_dir = data_dir .. '/site/pack/managed/start/'
+    local outdated = {}
+
+    for name, plugin in pairs(_G.plugins) do
+        if plugin.enabled and not plugin['local'] then
+            local plugin_path = plugin_dir .. name
+            if vim.fn.isdirectory(plugin_path) == 1 then
+                vim.fn.system({'git', '-C', plugin_path, 'fetch', '--quiet'})
+                if vim.v.shell_error == 0 then
+                    local behind = vim.fn.system({'git', '-C', plugin_path, 'rev-list', '--count', 'HEAD..@{u}'})
+                    local count = vim.v.shell_error == 0 and tonumber(vim.trim(behind)) or 0
+                    if count > 0 then
+                        table.insert(outdated, name .. ' (' .. count .. ' new)')
+                    end
+                end
+            end
+        end
+    end
+
+    table.sort(outdated)
+    if #outdated == 0 then
+        vim.notify('All plugins are up to date')
+    else
+        vim.notify('Updates available: ' .. table.concat(outdated, ', '))
+    end
+
+    return #outdated
+";
+
+/// Checks all registered plugins for upstream changes without updating them
+///
+/// Each installed plugin is fetched and compared against its upstream branch,
+/// and a summary of plugins with new commits is shown through `vim.notify`.
+/// Working trees are left untouched. Returns the number of plugins with
+/// updates available, which is also 0 if the check could not be run.
+///
+/// # Safety
+///
+/// This function executes system commands that interact with the filesystem
+/// and the network.
+#[no_mangle]
+pub unsafe extern "C" fn check_plugin_updates() -> c_int {
+    crate::eval_integer(&format!("(function() {CHECK_UPDATES_LUA} end)()"))
+        .and_then(|count| c_int::try_from(count).map_err(|_| crate::Error::InvalidArgument))
+        .unwrap_or(0)
+}
+
+// Reports the plugin manager's environment and per-plugin install status
+const HEALTH_LUA: &str = r"
+    local health = vim.health
+    local start = health.start or health.report_start
+    local ok = health.ok or health.report_ok
+    local warn = health.warn or health.report_warn
+    local error = health.error or health.report_error
+
+    start('rns')
+    local data_dir = vim.fn.stdpath('data')
+    local plugin_dir = data_dir .. '/site/pack/managed/start/'
+
+    if vim.fn.isdirectory(plugin_dir) == 0 then
+        warn('Plugin directory does not exist yet: ' .. plugin_dir)
+    elseif vim.fn.filewritable(plugin_dir) ~= 2 then
+        error('Plugin directory is not writable: ' .. plugin_dir)
+    else
+        ok('Plugin directory is writable: ' .. plugin_dir)
+    end
+
+    if vim.fn.executable('git') == 1 then
+        ok('git is available')
+    else
+        error('git was not found on PATH')
+    end
+
+    start('rns: plugins')
+    local names = vim.tbl_keys(_G.plugins or {})
+    table.sort(names)
+
+    if #names == 0 then
+        ok('No plugins registered')
+    end
+
+    for _, name in ipairs(names) do
+        local plugin = _G.plugins[name]
+        if not plugin.enabled then
+            ok(name .. ' is disabled')
+        elseif plugin['local'] then
+            if vim.fn.isdirectory(plugin.url) == 1 then
+                ok(name .. ' is a local plugin at ' .. plugin.url)
+            else
+                error(name .. ' is a local plugin, but ' .. plugin.url .. ' does not exist')
+            end
+        elseif vim.fn.isdirectory(plugin_dir .. name) == 1 then
+            ok(name .. ' is installed')
+        else
+            warn(name .. ' is not installed', { 'Run install_plugins()' })
+        end
+    end
+";
+
+/// Produces a healthcheck report for the plugin manager
+///
+/// The report is written through `vim.health`, so calling this from the
+/// `check` function of a `lua/<name>/health.lua` module makes it show up
+/// in `:checkhealth <name>`.
+///
+/// # Safety
+///
+/// This function executes Lua code that inspects the filesystem.
+#[no_mangle]
+pub unsafe extern "C" fn check_health() -> c_int {
+    match crate::run_lua(HEALTH_LUA) {
         Ok(()) => 1,
         Err(_) => 0,
     }
@@ -368,16 +1009,14 @@ pub fn register_plugin_functions(lua: &crate::Lua<'_>) -> crate::Result<()> {
             Err(_) => return 0,
         };
 
-        let url = match lua.check_string(2) {
+        let url = match lua.check_string_strict(2) {
             Ok(s) => s,
             Err(_) => return 0,
         };
 
-        unsafe {
-            register_plugin(
-                CString::new(name).unwrap().as_ptr(),
-                CString::new(url).unwrap().as_ptr(),
-            )
+        match register_plugin_url(&name, &url) {
+            Ok(()) => 1,
+            Err(_) => 0,
         }
     }
 
@@ -397,11 +1036,9 @@ pub fn register_plugin_functions(lua: &crate::Lua<'_>) -> crate::Result<()> {
             Err(_) => return 0,
         };
 
-        unsafe {
-            configure_plugin(
-                CString::new(name).unwrap().as_ptr(),
-                CString::new(config).unwrap().as_ptr(),
-            )
+        match apply_plugin_config(&name, &config) {
+            Ok(()) => 1,
+            Err(_) => 0,
         }
     }
 
@@ -409,28 +1046,570 @@ pub fn register_plugin_functions(lua: &crate::Lua<'_>) -> crate::Result<()> {
         unsafe { install_plugins() }
     }
 
+    extern "C" fn lua_register_plugin_spec(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match PluginSpec::from_lua(&lua, 1).and_then(|spec| register_plugin_spec(&spec)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_clone_depth(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let depth = match lua.check_integer(1).map(c_int::try_from) {
+            Ok(Ok(n)) => n,
+            _ => return 0,
+        };
+
+        set_clone_depth(depth)
+    }
+
+    extern "C" fn lua_check_health(_l: *mut crate::LuaState) -> c_int {
+        unsafe { check_health() }
+    }
+
     extern "C" fn lua_update_plugins(_l: *mut crate::LuaState) -> c_int {
         unsafe { update_plugins() }
     }
 
+    extern "C" fn lua_check_plugin_updates(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.exec(CHECK_UPDATES_LUA, 1) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_get_plugin_commit(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match plugin_commit_expr(&name).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
     extern "C" fn lua_load_plugin_configs(_l: *mut crate::LuaState) -> c_int {
         unsafe { load_plugin_configs() }
     }
 
+    extern "C" fn lua_load_plugin_config(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match crate::run_lua(&load_plugin_config_cmd(&name)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_plugin_config(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let id = match lua
+            .check_string(1)
+            .and_then(|name| new_config_builder(&name))
+        {
+            Ok(id) => id,
+            Err(_) => return 0,
+        };
+
+        if lua.ensure_stack(3).is_err() {
+            return 0;
+        }
+
+        // Each method captures the builder id as its only upvalue
+        lua.create_table(0, 3);
+        let methods: [(extern "C" fn(*mut crate::LuaState) -> c_int, &str); 3] = [
+            (lua_builder_server, "server"),
+            (lua_builder_mapping, "mapping"),
+            (lua_builder_apply, "apply"),
+        ];
+        for (method, name) in methods {
+            lua.push_integer(id);
+            lua.push_cclosure(method, 1);
+            if lua.set_field(-2, name).is_err() {
+                return 0;
+            }
+        }
+
+        1
+    }
+
+    extern "C" fn lua_builder_server(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let id = match lua.check_integer(crate::upvalue_index(1)) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let server = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match extend_config_builder(id, |_| server_setup_line(&server)) {
+            Ok(()) => {
+                lua.push_value(1);
+                1
+            }
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_builder_mapping(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let id = match lua.check_integer(crate::upvalue_index(1)) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let mode = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let key = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let action = match lua.check_string(4) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match extend_config_builder(id, |plugin| {
+            plugin_mapping_line(plugin, &mode, &key, &action)
+        }) {
+            Ok(()) => {
+                lua.push_value(1);
+                1
+            }
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_builder_apply(l: *mut crate::LuaState) -> c_int {
+        let lua = match unsafe { crate::Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua
+            .check_integer(crate::upvalue_index(1))
+            .and_then(apply_config_builder)
+        {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
     lua.push_cclosure(lua_register_plugin, 0);
     lua.set_field(-2, "register_plugin")?;
 
+    lua.push_cclosure(lua_register_plugin_spec, 0);
+    lua.set_field(-2, "register_plugin_spec")?;
+
     lua.push_cclosure(lua_configure_plugin, 0);
     lua.set_field(-2, "configure_plugin")?;
 
+    lua.push_cclosure(lua_plugin_config, 0);
+    lua.set_field(-2, "plugin_config")?;
+
     lua.push_cclosure(lua_install_plugins, 0);
     lua.set_field(-2, "install_plugins")?;
 
+    lua.push_cclosure(lua_set_clone_depth, 0);
+    lua.set_field(-2, "set_clone_depth")?;
+
     lua.push_cclosure(lua_update_plugins, 0);
     lua.set_field(-2, "update_plugins")?;
 
+    lua.push_cclosure(lua_check_plugin_updates, 0);
+    lua.set_field(-2, "check_plugin_updates")?;
+
+    lua.push_cclosure(lua_get_plugin_commit, 0);
+    lua.set_field(-2, "get_plugin_commit")?;
+
+    lua.push_cclosure(lua_check_health, 0);
+    lua.set_field(-2, "health")?;
+
     lua.push_cclosure(lua_load_plugin_configs, 0);
     lua.set_field(-2, "load_configs")?;
 
+    lua.push_cclosure(lua_load_plugin_config, 0);
+    lua.set_field(-2, "load_plugin_config")?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn counts_commits_behind_upstream() {
+        assert!(CHECK_UPDATES_LUA.contains("'rev-list', '--count', 'HEAD..@{u}'"));
+    }
+
+    #[test]
+    fn loads_only_the_named_plugin_config() {
+        let cmd = load_plugin_config_cmd("it's");
+        assert!(cmd.contains(r"local name = 'it\'s'"));
+        assert!(cmd.contains("local plugin = _G.plugins and _G.plugins[name]"));
+        assert!(cmd.contains("run_config(name, plugin)"));
+        assert!(!cmd.contains("pairs(_G.plugins)"));
+
+        // Unregistered plugins are reported instead of raising an error
+        let unregistered = cmd.find("if not plugin then").unwrap();
+        let notify = cmd.find("' is not registered'").unwrap();
+        let run = cmd.rfind("run_config(name, plugin)").unwrap();
+        assert!(unregistered < notify && notify < run);
+    }
+
+    #[test]
+    fn clone_depth_is_configurable() {
+        let _serial = mock::serial();
+        assert_eq!(clone_args(), "'git', 'clone', '--depth', '1'");
+
+        assert_eq!(set_clone_depth(0), 1);
+        let full = clone_args();
+        assert_eq!(set_clone_depth(5), 1);
+        let deep = clone_args();
+        assert_eq!(set_clone_depth(-1), 0);
+        let unchanged = clone_args();
+        set_clone_depth(1);
+
+        assert_eq!(full, "'git', 'clone'");
+        assert_eq!(deep, "'git', 'clone', '--depth', '5'");
+        assert_eq!(unchanged, deep);
+    }
+
+    #[test]
+    fn health_report_has_a_line_per_plugin() {
+        assert!(HEALTH_LUA.contains("local health = vim.health"));
+        assert!(HEALTH_LUA.contains("local start = health.start or health.report_start"));
+        assert!(HEALTH_LUA.contains("start('rns: plugins')"));
+        assert!(HEALTH_LUA.contains("for _, name in ipairs(names) do"));
+        for status in [
+            "ok(name .. ' is disabled')",
+            "ok(name .. ' is installed')",
+            "warn(name .. ' is not installed', { 'Run install_plugins()' })",
+        ] {
+            assert!(HEALTH_LUA.contains(status), "missing {:?}", status);
+        }
+
+        mock::lua();
+        assert_eq!(unsafe { check_health() }, 1);
+        assert_eq!(mock::commands(), [format!("lua {HEALTH_LUA}")]);
+    }
+
+    #[test]
+    fn expands_github_shorthand() {
+        assert_eq!(
+            normalize_plugin_url("nvim-lua/plenary.nvim"),
+            "https://github.com/nvim-lua/plenary.nvim"
+        );
+        assert_eq!(
+            normalize_plugin_url("folke/lazy.nvim.git"),
+            "https://github.com/folke/lazy.nvim.git"
+        );
+
+        for url in [
+            "https://github.com/a/b",
+            "git@github.com:a/b.git",
+            "./plugin",
+            "a/b/c",
+            "a/",
+            "../b",
+        ] {
+            assert_eq!(normalize_plugin_url(url), url);
+        }
+    }
+
+    #[test]
+    fn plugin_warnings_go_through_the_notify_once_guard() {
+        assert!(NOTIFY_ONCE_LUA.contains("local notify_once = _G.rns_notify_once or"));
+        let cmd = load_plugin_config_cmd("x");
+        assert!(cmd.starts_with(&format!("{NOTIFY_ONCE_LUA}{RUN_CONFIG_LUA}")));
+        assert!(cmd.contains("notify_once('unregistered:' .. name,"));
+        assert!(RUN_CONFIG_LUA.contains("notify_once('config:' .. name,"));
+        assert!(!cmd.contains("vim.notify_once('"));
+    }
+
+    #[test]
+    fn reports_install_progress_as_a_running_count() {
+        mock::lua();
+        assert_eq!(unsafe { install_plugins() }, 1);
+        let commands = mock::commands();
+        assert_eq!(commands.len(), 1);
+        assert!(
+            commands[0].contains("local msg = ('Installing %d/%d: %s'):format(i, #pending, name)")
+        );
+        assert!(commands[0].contains("vim.api.nvim_echo({ { msg } }, false, {})"));
+        assert!(!commands[0].contains("vim.notify('Installing"));
+    }
+
+    #[test]
+    fn reads_a_full_spec() {
+        let lua = mock::lua();
+        mock::push(mock::table([
+            ("name", "telescope".into()),
+            ("url", "nvim-telescope/telescope.nvim".into()),
+            ("ref", "0.1.x".into()),
+            ("build", "make".into()),
+            (
+                "deps",
+                mock::list([mock::string("plenary"), mock::string("fzf")]),
+            ),
+            ("event", "VeryLazy".into()),
+            ("lazy", true.into()),
+            ("enabled", false.into()),
+        ]));
+
+        let spec = PluginSpec::from_lua(&lua, 1).unwrap();
+        assert_eq!(
+            spec,
+            PluginSpec {
+                name: "telescope".to_string(),
+                url: "https://github.com/nvim-telescope/telescope.nvim".to_string(),
+                local: false,
+                git_ref: Some("0.1.x".to_string()),
+                build: Some("make".to_string()),
+                deps: vec!["plenary".to_string(), "fzf".to_string()],
+                event: Some("User".to_string()),
+                event_pattern: Some("VeryLazy".to_string()),
+                lazy: true,
+                enabled: false,
+            }
+        );
+        assert_eq!(
+            spec.to_lua(),
+            "{ url = 'https://github.com/nvim-telescope/telescope.nvim', enabled = false, \
+             lazy = true, ['local'] = false, ref = '0.1.x', build = 'make', event = 'User', \
+             pattern = 'VeryLazy', deps = { 'plenary', 'fzf' } }"
+        );
+    }
+
+    #[test]
+    fn reads_a_minimal_spec() {
+        let lua = mock::lua();
+        mock::push(mock::table([
+            ("name", "plenary".into()),
+            ("url", "nvim-lua/plenary.nvim".into()),
+        ]));
+
+        let spec = PluginSpec::from_lua(&lua, 1).unwrap();
+        assert!(spec.enabled && !spec.lazy);
+        assert!(spec.deps.is_empty() && spec.git_ref.is_none() && spec.event.is_none());
+
+        register_plugin_spec(&spec).unwrap();
+        let commands = mock::commands();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].contains("_G.plugins['plenary'] = entry"));
+    }
+
+    #[test]
+    fn rejects_incomplete_specs() {
+        let lua = mock::lua();
+        mock::push(mock::table([("name", "x".into())]));
+        assert!(PluginSpec::from_lua(&lua, 1).is_err());
+
+        mock::push(mock::table([
+            ("name", "x".into()),
+            ("url", "a/b".into()),
+            ("lazy", "yes".into()),
+        ]));
+        assert!(PluginSpec::from_lua(&lua, 2).is_err());
+    }
+
+    #[test]
+    fn register_entry_keeps_config_for_the_same_url() {
+        let cmd = register_entry_cmd("it's", "https://x/y", "{ url = 'https://x/y' }");
+        assert!(cmd.contains(r"local existing = _G.plugins['it\'s']"));
+        assert!(cmd.contains("local entry = { url = 'https://x/y' }"));
+        assert!(cmd.contains("entry.config = existing.config"));
+    }
+
+    #[test]
+    fn reregistering_warns_only_for_a_different_url() {
+        let cmd = register_entry_cmd("telescope", "https://x/new", "{ url = 'https://x/new' }");
+        let same_url = cmd.find("if existing and existing.url == 'https://x/new' then");
+        let unchanged = cmd.find("if same then return end");
+        let conflict = cmd.find(
+            "vim.notify('rns: plugin ' .. 'telescope' .. ' was registered from ' .. existing.url",
+        );
+
+        assert!(
+            same_url.is_some() && same_url < unchanged && unchanged < conflict,
+            "{}",
+            cmd
+        );
+        assert!(cmd.contains(".. ', replacing it with ' .. 'https://x/new', vim.log.levels.WARN)"));
+        assert!(cmd.trim_end().ends_with("_G.plugins['telescope'] = entry"));
+    }
+
+    #[test]
+    fn file_urls_name_local_plugins() {
+        assert_eq!(
+            normalize_plugin_url("file:///home/me/src/oil.nvim"),
+            "/home/me/src/oil.nvim"
+        );
+        assert!(is_local_plugin(&normalize_plugin_url(
+            "file:///home/me/src/oil.nvim"
+        )));
+        assert!(is_local_plugin("/opt/plugins/fzf"));
+        assert!(!is_local_plugin(&normalize_plugin_url("stevearc/oil.nvim")));
+        assert!(!is_local_plugin("./plugin"));
+    }
+
+    #[test]
+    fn local_plugins_are_prepended_and_never_cloned() {
+        let _serial = mock::serial();
+        mock::lua();
+        register_plugin_url("oil", "file:///home/me/src/oil.nvim").unwrap();
+        unsafe {
+            install_plugins();
+            update_plugins();
+        }
+
+        let commands = mock::commands();
+        assert!(commands[0].contains(
+            "local entry = { url = '/home/me/src/oil.nvim', enabled = true, ['local'] = true }"
+        ));
+
+        let install = &commands[1];
+        assert!(install.contains(
+            "if plugin.enabled and not plugin['local'] and vim.fn.isdirectory(plugin_dir .. name) == 0 then"
+        ));
+        assert!(install
+            .contains("local plugin_path = plugin['local'] and plugin.url or plugin_dir .. name"));
+        assert!(install.contains("vim.opt.rtp:prepend(plugin_path)"));
+
+        let update = &commands[2];
+        let skip = update.find("if plugin.enabled and not plugin['local'] then");
+        let pull = update.find("'pull', '--ff-only'");
+        assert!(skip.is_some() && skip < pull, "{}", update);
+    }
+
+    #[test]
+    fn builds_config_lines() {
+        assert_eq!(
+            server_setup_line("lua_ls"),
+            "require('lspconfig').lua_ls.setup({});\n"
+        );
+        assert_eq!(
+            plugin_mapping_line("telescope", "i", "<C-j>", "move_selection_next"),
+            "require('telescope').setup({ defaults = { mappings = { i = { ['<C-j>'] = 'move_selection_next' } } } });\n"
+        );
+    }
+
+    #[test]
+    fn builder_applies_the_accumulated_config() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_plugin_functions(&lua).unwrap();
+        let plugin_config = mock::get(-1).field("plugin_config");
+
+        let builder = mock::call(&plugin_config, [mock::string("telescope")]).remove(0);
+        let server = builder.field("server");
+        let mapping = builder.field("mapping");
+        let apply = builder.field("apply");
+
+        mock::call(&server, [builder.clone(), mock::string("lua_ls")]);
+        mock::call(
+            &mapping,
+            [
+                builder.clone(),
+                mock::string("i"),
+                mock::string("<C-j>"),
+                mock::string("move_selection_next"),
+            ],
+        );
+        assert!(mock::commands().is_empty());
+
+        assert_eq!(mock::call(&apply, [builder.clone()]).len(), 1);
+        let commands = mock::commands();
+        assert_eq!(commands.len(), 1);
+        assert!(commands[0].starts_with(
+            "lua if _G.plugins and _G.plugins['telescope'] then _G.plugins['telescope'].config = [["
+        ));
+        assert!(commands[0].contains("require('lspconfig').lua_ls.setup({});"));
+        assert!(commands[0].contains("['<C-j>'] = 'move_selection_next'"));
+
+        // The builder is discarded once applied
+        assert!(mock::call(&apply, [builder]).is_empty());
+    }
+
+    #[test]
+    fn validates_plugin_names() {
+        assert!(validate_plugin_name("telescope.nvim").is_ok());
+        for name in ["", ".", "..", "a/b", "a\\b"] {
+            assert!(validate_plugin_name(name).is_err());
+        }
+        assert!(plugin_commit_expr("../x").is_err());
+        assert!(plugin_commit_expr("x").unwrap().ends_with("end)('x')"));
+    }
+
+    #[test]
+    fn normalizes_events() {
+        assert_eq!(
+            normalize_event("VeryLazy").unwrap(),
+            ("User".to_string(), Some("VeryLazy".to_string()))
+        );
+        assert_eq!(
+            normalize_event("event:BufReadPost").unwrap(),
+            ("BufReadPost".to_string(), None)
+        );
+        assert_eq!(
+            normalize_event("User Ready").unwrap(),
+            ("User".to_string(), Some("Ready".to_string()))
+        );
+        assert!(normalize_event("").is_err());
+        assert!(normalize_event("User a b").is_err());
+        assert!(normalize_event("Bad-Event").is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::os::raw::c_int;
+
+use crate::keymap::{emit_keymap, KeymapOpts, KeymapSpec, Rhs};
+use crate::{lua_quote, validate_identifier, Error, Lua, LuaState, Result, Value};
+
+/// Reports an entry of the setup table that could not be applied
+fn skip(section: &str, entry: &str) {
+    let msg = format!("rns.setup: skipping invalid {section} entry {entry}");
+    let _ = crate::run_lua(&format!(
+        "vim.notify({}, vim.log.levels.WARN)",
+        lua_quote(&msg)
+    ));
+}
+
+/// Reads the string at the given index, rejecting every other type
+fn string_at(lua: &Lua<'_>, idx: c_int) -> Result<String> {
+    if lua.is_string(idx) {
+        lua.check_string(idx)
+    } else {
+        Err(Error::InvalidArgument)
+    }
+}
+
+/// Sets one `name = value` pair of the `options` section
+fn apply_option(lua: &Lua<'_>) -> Result<()> {
+    let name = string_at(lua, -2)?;
+    validate_identifier(&name)?;
+    crate::check_option(Some(lua), &name)?;
+
+    let value = lua.check_value(-1)?;
+    if value == Value::Nil {
+        return Err(Error::InvalidArgument);
+    }
+
+    if crate::option_unchanged(Some(lua), &name, &value.to_lua()) {
+        return Ok(());
+    }
+
+    crate::run_lua(&format!("vim.o[{}] = {}", lua_quote(&name), value.to_lua()))
+}
+
+/// Creates the mapping described by the `{ mode, lhs, rhs, opts = {...} }`
+/// table on top of the stack, where `opts` may also be a packed options string
+fn apply_keymap(lua: &Lua<'_>) -> Result<()> {
+    lua.check_table(-1)?;
+
+    let mut fields = Vec::with_capacity(3);
+    for i in 1..=3 {
+        lua.get_index(-1, i);
+        let field = string_at(lua, -1);
+        lua.pop(1);
+        fields.push(field?);
+    }
+
+    lua.get_field(-1, "opts")?;
+    let opts = KeymapOpts::from_lua(lua, -1);
+    lua.pop(1);
+
+    let rhs = fields.pop().unwrap_or_default();
+    let lhs = fields.pop().unwrap_or_default();
+    let mode = fields.pop().unwrap_or_default();
+
+    emit_keymap(&KeymapSpec {
+        mode,
+        lhs,
+        rhs: Rhs::Command(rhs),
+        opts: opts?,
+        buffer: None,
+    })
+}
+
+/// Registers one `name = url` pair of the `plugins` section
+fn apply_plugin(lua: &Lua<'_>) -> Result<()> {
+    let name = CString::new(string_at(lua, -2)?).map_err(|_| Error::StringConversion)?;
+    let url = CString::new(string_at(lua, -1)?).map_err(|_| Error::StringConversion)?;
+
+    match unsafe { crate::pman::register_plugin(name.as_ptr(), url.as_ptr()) } {
+        1 => Ok(()),
+        _ => Err(Error::CommandExecution),
+    }
+}
+
+/// Applies each pair of the map-like section on top of the stack, returning
+/// whether every entry was applied
+fn apply_pairs(lua: &Lua<'_>, section: &str, apply: fn(&Lua<'_>) -> Result<()>) -> Result<bool> {
+    let mut all_applied = true;
+
+    lua.for_each_pair(-1, |lua| {
+        if apply(lua).is_err() {
+            let entry = string_at(lua, -2).unwrap_or_else(|_| "?".to_string());
+            skip(section, &entry);
+            all_applied = false;
+        }
+        Ok(())
+    })?;
+
+    Ok(all_applied)
+}
+
+/// Applies each element of the `keymaps` list on top of the stack, returning
+/// whether every entry was applied
+fn apply_keymaps(lua: &Lua<'_>) -> Result<bool> {
+    lua.check_table(-1)?;
+    lua.ensure_stack(2)?;
+
+    let mut all_applied = true;
+    for i in 1..=lua.table_len(-1) {
+        let i = c_int::try_from(i).map_err(|_| Error::InvalidArgument)?;
+
+        lua.get_index(-1, i);
+        let result = apply_keymap(lua);
+        lua.pop(1);
+
+        if result.is_err() {
+            skip("keymaps", &format!("#{i}"));
+            all_applied = false;
+        }
+    }
+
+    Ok(all_applied)
+}
+
+/// Applies every section of the setup table at the given index
+///
+/// Malformed entries are reported through `vim.notify` and skipped, so one
+/// typo does not prevent the rest of the configuration from loading. Returns
+/// whether every entry was applied.
+fn setup(lua: &Lua<'_>, idx: c_int) -> Result<bool> {
+    let mut all_applied = true;
+
+    lua.for_each_pair(idx, |lua| {
+        let section = string_at(lua, -2).unwrap_or_else(|_| "?".to_string());
+
+        let applied = match section.as_str() {
+            "options" => apply_pairs(lua, "options", apply_option),
+            "keymaps" => apply_keymaps(lua),
+            "plugins" => apply_pairs(lua, "plugins", apply_plugin),
+            _ => Err(Error::InvalidArgument),
+        };
+
+        match applied {
+            Ok(applied) => all_applied &= applied,
+            Err(_) => {
+                skip("section", &section);
+                all_applied = false;
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(all_applied)
+}
+
+/// Registers the Lua binding for `setup`
+pub fn register_setup_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_setup(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        if lua.check_table(1).is_err() {
+            return 0;
+        }
+
+        match setup(&lua, 1) {
+            Ok(true) => 1,
+            _ => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_setup, 0);
+    lua.set_field(-2, "setup")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn applies_options_and_keymaps_sections() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        mock::push(mock::table([
+            (
+                "options",
+                mock::table([("number", true.into()), ("shiftwidth", 2.0.into())]),
+            ),
+            (
+                "keymaps",
+                mock::list([
+                    mock::list(["n".into(), "<leader>w".into(), ":w<CR>".into()]),
+                    mock::list(["i".into(), "jk".into(), "<Esc>".into()])
+                        .with("opts", mock::table([("desc", "Leave".into())])),
+                ]),
+            ),
+        ]));
+
+        assert!(setup(&lua, 1).unwrap());
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.o['number'] = true",
+                "lua vim.o['shiftwidth'] = 2",
+                "lua vim.keymap.set('n', '<leader>w', ':w<CR>', {})",
+                "lua vim.keymap.set('i', 'jk', '<Esc>', { desc = 'Leave' })",
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_and_reports_malformed_entries() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        mock::push(mock::table([
+            (
+                "options",
+                mock::table([("number", true.into()), ("x-y", 1.0.into())]),
+            ),
+            (
+                "keymaps",
+                mock::list([mock::list(["n".into(), "a".into()])]),
+            ),
+            ("colors", mock::table([])),
+        ]));
+
+        assert!(!setup(&lua, 1).unwrap());
+
+        let commands = mock::commands();
+        assert_eq!(commands[0], "lua vim.o['number'] = true");
+        assert_eq!(commands.len(), 4);
+        assert!(commands[1].contains("skipping invalid options entry x-y"));
+        assert!(commands[2].contains("skipping invalid keymaps entry #1"));
+        assert!(commands[3].contains("skipping invalid section entry colors"));
+    }
+}
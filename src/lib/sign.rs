@@ -0,0 +1,291 @@
+use std::os::raw::{c_char, c_int};
+
+use crate::{
+    extract_c_string, lua_quote, validate_hl_group, validate_identifier, Error, Lua, LuaState,
+    Result,
+};
+
+/// Builds the `sign_define` call for a sign
+///
+/// An empty `texthl` leaves the sign text with the default highlight.
+fn sign_define_expr(name: &str, text: &str, texthl: &str) -> Result<String> {
+    validate_identifier(name)?;
+
+    let mut fields = vec![format!("text = {}", lua_quote(text))];
+    if !texthl.is_empty() {
+        validate_hl_group(texthl)?;
+        fields.push(format!("texthl = {}", lua_quote(texthl)));
+    }
+
+    Ok(format!(
+        "vim.fn.sign_define({}, {{ {} }})",
+        lua_quote(name),
+        fields.join(", ")
+    ))
+}
+
+/// Builds the `sign_place` call for a sign, evaluating to the placed sign's id
+///
+/// An `id` of 0 lets Neovim allocate one, an empty `group` places the sign in
+/// the global group, and a `buffer` of 0 refers to the current buffer.
+fn sign_place_expr(id: i64, group: &str, name: &str, buffer: i64, lnum: i64) -> Result<String> {
+    validate_identifier(name)?;
+    if !group.is_empty() {
+        validate_identifier(group)?;
+    }
+
+    if id < 0 || buffer < 0 || lnum < 1 {
+        return Err(Error::InvalidArgument);
+    }
+
+    let buffer = if buffer == 0 {
+        "vim.api.nvim_get_current_buf()".to_string()
+    } else {
+        buffer.to_string()
+    };
+
+    Ok(format!(
+        "vim.fn.sign_place({id}, {}, {}, {buffer}, {{ lnum = {lnum} }})",
+        lua_quote(group),
+        lua_quote(name)
+    ))
+}
+
+/// Builds the `sign_unplace` call removing the signs of `group`
+///
+/// Unlike `sign_place`, a `buffer` of 0 means every buffer rather than the
+/// current one. An empty `group` refers to the global group and `*` to all
+/// groups.
+fn sign_unplace_expr(group: &str, buffer: i64) -> Result<String> {
+    if !group.is_empty() && group != "*" {
+        validate_identifier(group)?;
+    }
+
+    let opts = match buffer {
+        0 => "{}".to_string(),
+        n if n > 0 => format!("{{ buffer = {n} }}"),
+        _ => return Err(Error::InvalidArgument),
+    };
+
+    Ok(format!("vim.fn.sign_unplace({}, {opts})", lua_quote(group)))
+}
+
+/// Defines a sign with the given text and highlight group
+///
+/// # Safety
+///
+/// `name`, `text`, and `texthl` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_sign_define(
+    name: *const c_char,
+    text: *const c_char,
+    texthl: *const c_char,
+) -> c_int {
+    match (
+        extract_c_string(name),
+        extract_c_string(text),
+        extract_c_string(texthl),
+    ) {
+        (Ok(name_str), Ok(text_str), Ok(texthl_str)) => {
+            match sign_define_expr(&name_str, &text_str, &texthl_str)
+                .and_then(|code| crate::run_lua(&code))
+            {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// Places a defined sign on a line of a buffer
+///
+/// # Safety
+///
+/// `group` and `name` must be valid null-terminated C strings.
+#[no_mangle]
+pub extern "C" fn nvim_sign_place(
+    id: c_int,
+    group: *const c_char,
+    name: *const c_char,
+    buffer: c_int,
+    lnum: c_int,
+) -> c_int {
+    match (extract_c_string(group), extract_c_string(name)) {
+        (Ok(group_str), Ok(name_str)) => {
+            let expr =
+                sign_place_expr(id.into(), &group_str, &name_str, buffer.into(), lnum.into());
+
+            match expr.and_then(|code| crate::run_lua(&code)) {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// Removes the signs of a group from one buffer, or from all buffers if `buffer` is 0
+///
+/// # Safety
+///
+/// `group` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_sign_unplace(group: *const c_char, buffer: c_int) -> c_int {
+    match extract_c_string(group)
+        .and_then(|group| sign_unplace_expr(&group, buffer.into()))
+        .and_then(|code| crate::run_lua(&code))
+    {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Registers Lua bindings for sign functions
+pub fn register_sign_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_sign_define(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let text = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let texthl = match lua.opt_string(3) {
+            Ok(s) => s.unwrap_or_default(),
+            Err(_) => return 0,
+        };
+
+        match sign_define_expr(&name, &text, &texthl).and_then(|code| crate::run_lua(&code)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_sign_place(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let id = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let group = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(3) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(4) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let lnum = match lua.check_integer(5) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        // Return the id of the placed sign
+        match sign_place_expr(id, &group, &name, buffer, lnum).and_then(|expr| lua.eval(&expr)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_sign_unplace(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let group = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let buffer = match lua.check_integer(2) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match sign_unplace_expr(&group, buffer).and_then(|code| crate::run_lua(&code)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_sign_define, 0);
+    lua.set_field(-2, "sign_define")?;
+
+    lua.push_cclosure(lua_sign_place, 0);
+    lua.set_field(-2, "sign_place")?;
+
+    lua.push_cclosure(lua_sign_unplace, 0);
+    lua.set_field(-2, "sign_unplace")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_sign_definitions() {
+        assert_eq!(
+            sign_define_expr("Breakpoint", "●", "DiagnosticSign.Error").unwrap(),
+            "vim.fn.sign_define('Breakpoint', { text = '●', texthl = 'DiagnosticSign.Error' })"
+        );
+        assert_eq!(
+            sign_define_expr("Mark", ">", "").unwrap(),
+            "vim.fn.sign_define('Mark', { text = '>' })"
+        );
+        assert!(sign_define_expr("bad name", ">", "").is_err());
+    }
+
+    #[test]
+    fn places_signs_in_the_current_buffer_for_zero() {
+        assert_eq!(
+            sign_place_expr(0, "", "Mark", 0, 3).unwrap(),
+            "vim.fn.sign_place(0, '', 'Mark', vim.api.nvim_get_current_buf(), { lnum = 3 })"
+        );
+        assert_eq!(
+            sign_place_expr(7, "dap", "Mark", 2, 1).unwrap(),
+            "vim.fn.sign_place(7, 'dap', 'Mark', 2, { lnum = 1 })"
+        );
+        assert!(sign_place_expr(0, "", "Mark", 0, 0).is_err());
+        assert!(sign_place_expr(-1, "", "Mark", 0, 1).is_err());
+    }
+
+    #[test]
+    fn unplaces_signs_in_every_buffer_for_zero() {
+        assert_eq!(
+            sign_unplace_expr("*", 0).unwrap(),
+            "vim.fn.sign_unplace('*', {})"
+        );
+        assert_eq!(
+            sign_unplace_expr("dap", 4).unwrap(),
+            "vim.fn.sign_unplace('dap', { buffer = 4 })"
+        );
+        assert!(sign_unplace_expr("", -1).is_err());
+        assert!(sign_unplace_expr("a-b", 0).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::os::raw::c_int;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use crate::{Error, Lua, LuaState, Result};
+
+// Restore statements for the open transaction, in the order they were recorded.
+// `None` means no transaction is open.
+static UNDO_STACK: OnceLock<Mutex<Option<Vec<String>>>> = OnceLock::new();
+
+fn undo_stack() -> MutexGuard<'static, Option<Vec<String>>> {
+    UNDO_STACK
+        .get_or_init(|| Mutex::new(None))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Discards the open transaction without restoring anything, for `rns.reset`
+pub(crate) fn reset() {
+    *undo_stack() = None;
+}
+
+/// Opens a transaction, failing if one is already open
+fn begin() -> Result<()> {
+    let mut stack = undo_stack();
+    if stack.is_some() {
+        return Err(Error::InvalidArgument);
+    }
+
+    *stack = Some(Vec::new());
+    Ok(())
+}
+
+/// Closes the open transaction, keeping every change made within it
+fn commit() -> Result<()> {
+    undo_stack()
+        .take()
+        .map(|_| ())
+        .ok_or(Error::InvalidArgument)
+}
+
+/// Closes the open transaction, restoring prior values in reverse order
+///
+/// Every recorded change is restored even if an earlier restore fails.
+fn rollback() -> Result<()> {
+    let undo = undo_stack().take().ok_or(Error::InvalidArgument)?;
+
+    let mut result = Ok(());
+    for restore in undo.iter().rev() {
+        if let Err(e) = crate::run_lua(restore) {
+            result = Err(e);
+        }
+    }
+
+    result
+}
+
+/// Records how to undo a change before it is made, if a transaction is open
+///
+/// `current` is a Lua expression for the value about to be overwritten. It is
+/// read through `vim.inspect` so it can be embedded back into Lua source, and
+/// `restore` builds the statement that puts that literal back.
+pub(crate) fn record(
+    lua: &Lua<'_>,
+    current: &str,
+    restore: impl FnOnce(&str) -> String,
+) -> Result<()> {
+    if undo_stack().is_none() {
+        return Ok(());
+    }
+
+    lua.eval(&format!("vim.inspect({current})"))?;
+    let prior = lua.check_string(-1);
+    lua.pop(1);
+    let restore = restore(&prior?);
+
+    if let Some(undo) = undo_stack().as_mut() {
+        undo.push(restore);
+    }
+
+    Ok(())
+}
+
+/// Registers Lua bindings for transactions
+pub fn register_transaction_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_begin_transaction(_l: *mut LuaState) -> c_int {
+        match begin() {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_commit(_l: *mut LuaState) -> c_int {
+        match commit() {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_rollback(_l: *mut LuaState) -> c_int {
+        match rollback() {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_begin_transaction, 0);
+    lua.set_field(-2, "begin_transaction")?;
+
+    lua.push_cclosure(lua_commit, 0);
+    lua.set_field(-2, "commit")?;
+
+    lua.push_cclosure(lua_rollback, 0);
+    lua.set_field(-2, "rollback")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn rollback_restores_in_reverse_order() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+        mock::set_result("return vim.inspect(vim.o['tabstop'])", mock::string("8"));
+        mock::set_result("return vim.inspect(vim.o['number'])", mock::string("false"));
+
+        begin().unwrap();
+        assert!(begin().is_err());
+
+        for option in ["vim.o['tabstop']", "vim.o['number']"] {
+            record(&lua, option, |prior| format!("{option} = {prior}")).unwrap();
+        }
+        assert!(mock::commands().is_empty());
+
+        rollback().unwrap();
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.o['number'] = false", "lua vim.o['tabstop'] = 8"]
+        );
+        assert!(rollback().is_err());
+    }
+
+    #[test]
+    fn records_nothing_outside_a_transaction() {
+        let _serial = mock::serial();
+        let lua = mock::lua();
+
+        record(&lua, "vim.o['tabstop']", |_| unreachable!()).unwrap();
+        assert!(mock::chunks().is_empty());
+
+        begin().unwrap();
+        commit().unwrap();
+        assert!(commit().is_err());
+        assert!(mock::commands().is_empty());
+    }
+}
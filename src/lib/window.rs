@@ -0,0 +1,565 @@
+use std::os::raw::{c_char, c_int};
+
+use crate::{
+    extract_c_string_strict, fnameescape_cmd, lua_quote, validate_identifier, Error, Lua, LuaState,
+    Result, Value,
+};
+
+/// Reads an optional file path, treating null as no file
+fn optional_path(file: *const c_char) -> Result<Option<String>> {
+    if file.is_null() {
+        Ok(None)
+    } else {
+        extract_c_string_strict(file).map(Some)
+    }
+}
+
+/// Splits the current window, optionally editing `file` in the new window
+///
+/// `direction` is `h` for a horizontal split or `v` for a vertical one.
+fn split(direction: &str, file: Option<&str>) -> Result<()> {
+    let cmd = match direction {
+        "h" => "split",
+        "v" => "vsplit",
+        _ => return Err(Error::InvalidArgument),
+    };
+
+    crate::run_lua(&fnameescape_cmd(cmd, file))
+}
+
+/// Opens a new tabpage, optionally editing `file` in it
+fn tabnew(file: Option<&str>) -> Result<()> {
+    crate::run_lua(&fnameescape_cmd("tabnew", file))
+}
+
+/// Changes the global working directory to `path`
+fn set_current_dir(path: &str) -> Result<()> {
+    if path.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&fnameescape_cmd("cd", Some(path)))
+}
+
+/// Makes `win` the current window
+fn set_current_win(win: i64) -> Result<()> {
+    if win < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!("vim.api.nvim_set_current_win({win})"))
+}
+
+/// Makes `tabpage` the current tabpage
+fn set_current_tabpage(tabpage: i64) -> Result<()> {
+    if tabpage < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!("vim.api.nvim_set_current_tabpage({tabpage})"))
+}
+
+/// Runs a window API call on `win`, reporting failures through `vim.notify`
+///
+/// Closing or hiding the last window raises an error, which is better shown
+/// to the user than left to abort the surrounding Lua chunk.
+fn win_call(win: i64, call: &str) -> Result<()> {
+    if win < 0 {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!(
+        "local ok, err = pcall({call}) \
+         if not ok then vim.notify('rns: ' .. tostring(err), vim.log.levels.ERROR) end"
+    ))
+}
+
+/// Closes `win`, which may be 0 for the current window
+///
+/// With `force` set, a window on a modified buffer is closed and the buffer
+/// hidden instead of failing.
+fn win_close(win: i64, force: bool) -> Result<()> {
+    win_call(win, &format!("vim.api.nvim_win_close, {win}, {force}"))
+}
+
+/// Hides `win`, which may be 0 for the current window, keeping its buffer loaded
+fn win_hide(win: i64) -> Result<()> {
+    win_call(win, &format!("vim.api.nvim_win_hide, {win}"))
+}
+
+/// Sets an option local to `win`, which may be 0 for the current window
+fn win_set_option(win: i64, name: &str, value: &Value) -> Result<()> {
+    validate_identifier(name)?;
+    if win < 0 || *value == Value::Nil {
+        return Err(Error::InvalidArgument);
+    }
+
+    crate::run_lua(&format!(
+        "vim.api.nvim_set_option_value({}, {}, {{ win = {win} }})",
+        lua_quote(name),
+        value.to_lua()
+    ))
+}
+
+/// Splits the current window
+///
+/// # Safety
+///
+/// `direction` must be a valid null-terminated C string, either `"h"` or `"v"`.
+/// `file` must be either null or a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_split(direction: *const c_char, file: *const c_char) -> c_int {
+    match (extract_c_string_strict(direction), optional_path(file)) {
+        (Ok(direction_str), Ok(file_str)) => match split(&direction_str, file_str.as_deref()) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        _ => 0,
+    }
+}
+
+/// Opens a new tabpage
+///
+/// # Safety
+///
+/// `file` must be either null or a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_tabnew(file: *const c_char) -> c_int {
+    match optional_path(file) {
+        Ok(file_str) => match tabnew(file_str.as_deref()) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        Err(_) => 0,
+    }
+}
+
+/// Changes the global working directory
+///
+/// # Safety
+///
+/// `path` must be a valid null-terminated C string.
+#[no_mangle]
+pub extern "C" fn nvim_set_current_dir(path: *const c_char) -> c_int {
+    match extract_c_string_strict(path) {
+        Ok(path_str) => match set_current_dir(&path_str) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        },
+        Err(_) => 0,
+    }
+}
+
+/// Makes the given window the current window
+#[no_mangle]
+pub extern "C" fn nvim_set_current_win(win: c_int) -> c_int {
+    match set_current_win(win.into()) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Closes the given window, hiding a modified buffer rather than failing if `force` is non-zero
+#[no_mangle]
+pub extern "C" fn nvim_win_close(win: c_int, force: c_int) -> c_int {
+    match win_close(win.into(), force != 0) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Hides the given window
+#[no_mangle]
+pub extern "C" fn nvim_win_hide(win: c_int) -> c_int {
+    match win_hide(win.into()) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Makes the given tabpage the current tabpage
+#[no_mangle]
+pub extern "C" fn nvim_set_current_tabpage(tabpage: c_int) -> c_int {
+    match set_current_tabpage(tabpage.into()) {
+        Ok(()) => 1,
+        Err(_) => 0,
+    }
+}
+
+/// Registers Lua bindings for window management functions
+pub fn register_window_functions(lua: &Lua<'_>) -> Result<()> {
+    extern "C" fn lua_split(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let file = match lua.opt_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match split("h", file.as_deref()) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_vsplit(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let file = match lua.opt_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match split("v", file.as_deref()) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_tabnew(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let file = match lua.opt_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match tabnew(file.as_deref()) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_current_dir(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let path = match lua.check_string(1) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        match set_current_dir(&path) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_getcwd(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.eval("vim.fn.getcwd()") {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_win_is_valid(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let win = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match lua.eval(&format!("vim.api.nvim_win_is_valid({win})")) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_current_win(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let win = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match set_current_win(win) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_list_tabpages(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.eval("vim.api.nvim_list_tabpages()") {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_get_current_tabpage(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.eval("vim.api.nvim_get_current_tabpage()") {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_set_current_tabpage(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let tabpage = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match set_current_tabpage(tabpage) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_win_close(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let win = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        match win_close(win, lua.to_boolean(2)) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_win_hide(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        match lua.check_integer(1).and_then(win_hide) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    extern "C" fn lua_win_set_option(l: *mut LuaState) -> c_int {
+        let lua = match unsafe { Lua::new(l) } {
+            Ok(lua) => lua,
+            Err(_) => return 0,
+        };
+
+        let win = match lua.check_integer(1) {
+            Ok(n) => n,
+            Err(_) => return 0,
+        };
+
+        let name = match lua.check_string(2) {
+            Ok(s) => s,
+            Err(_) => return 0,
+        };
+
+        let value = match lua.check_value(3) {
+            Ok(v) => v,
+            Err(_) => return 0,
+        };
+
+        match win_set_option(win, &name, &value) {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    lua.ensure_stack(1)?;
+
+    lua.push_cclosure(lua_split, 0);
+    lua.set_field(-2, "split")?;
+
+    lua.push_cclosure(lua_vsplit, 0);
+    lua.set_field(-2, "vsplit")?;
+
+    lua.push_cclosure(lua_tabnew, 0);
+    lua.set_field(-2, "tabnew")?;
+
+    lua.push_cclosure(lua_set_current_dir, 0);
+    lua.set_field(-2, "set_current_dir")?;
+
+    lua.push_cclosure(lua_getcwd, 0);
+    lua.set_field(-2, "getcwd")?;
+
+    lua.push_cclosure(lua_win_is_valid, 0);
+    lua.set_field(-2, "win_is_valid")?;
+
+    lua.push_cclosure(lua_set_current_win, 0);
+    lua.set_field(-2, "set_current_win")?;
+
+    lua.push_cclosure(lua_list_tabpages, 0);
+    lua.set_field(-2, "list_tabpages")?;
+
+    lua.push_cclosure(lua_get_current_tabpage, 0);
+    lua.set_field(-2, "get_current_tabpage")?;
+
+    lua.push_cclosure(lua_set_current_tabpage, 0);
+    lua.set_field(-2, "set_current_tabpage")?;
+
+    lua.push_cclosure(lua_win_set_option, 0);
+    lua.set_field(-2, "win_set_option")?;
+
+    lua.push_cclosure(lua_win_close, 0);
+    lua.set_field(-2, "win_close")?;
+
+    lua.push_cclosure(lua_win_hide, 0);
+    lua.set_field(-2, "win_hide")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn escapes_file_names_when_splitting() {
+        mock::lua();
+        split("v", Some("my notes.md")).unwrap();
+        split("h", None).unwrap();
+        tabnew(None).unwrap();
+        assert!(split("x", None).is_err());
+
+        assert_eq!(
+            mock::commands(),
+            [
+                "lua vim.cmd('vsplit ' .. vim.fn.fnameescape('my notes.md'))",
+                "lua vim.cmd('split')",
+                "lua vim.cmd('tabnew')",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_window_validity_as_a_boolean() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_window_functions(&lua).unwrap();
+        let win_is_valid = mock::get(-1).field("win_is_valid");
+
+        mock::set_result("return vim.api.nvim_win_is_valid(1000)", true.into());
+        let results = mock::call(&win_is_valid, [1000.0.into()]);
+        assert!(matches!(results[..], [mock::MockValue::Bool(true)]));
+    }
+
+    #[test]
+    fn sets_the_current_window() {
+        mock::lua();
+        assert_eq!(nvim_set_current_win(1001), 1);
+        assert_eq!(nvim_set_current_win(-1), 0);
+        assert_eq!(mock::commands(), ["lua vim.api.nvim_set_current_win(1001)"]);
+    }
+
+    #[test]
+    fn sets_window_local_options() {
+        mock::lua();
+        win_set_option(1000, "wrap", &Value::Bool(false)).unwrap();
+        assert!(win_set_option(0, "wrap", &Value::Nil).is_err());
+        assert!(win_set_option(0, "not valid", &Value::Bool(true)).is_err());
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.api.nvim_set_option_value('wrap', false, { win = 1000 })"]
+        );
+    }
+
+    #[test]
+    fn lists_tabpages() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_window_functions(&lua).unwrap();
+        let list_tabpages = mock::get(-1).field("list_tabpages");
+
+        let tabpages = mock::list([1.0.into(), 2.0.into()]);
+        mock::set_result("return vim.api.nvim_list_tabpages()", tabpages);
+        let results = mock::call(&list_tabpages, []);
+        assert!(matches!(&results[..], [mock::MockValue::Table(t)] if t.borrow().len() == 2));
+    }
+
+    #[test]
+    fn sets_the_current_tabpage() {
+        mock::lua();
+        assert_eq!(nvim_set_current_tabpage(2), 1);
+        assert_eq!(nvim_set_current_tabpage(-1), 0);
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.api.nvim_set_current_tabpage(2)"]
+        );
+    }
+
+    #[test]
+    fn reports_window_close_failures() {
+        mock::lua();
+        win_close(0, true).unwrap();
+        win_hide(1001).unwrap();
+        assert!(win_hide(-1).is_err());
+
+        let commands = mock::commands();
+        assert_eq!(commands.len(), 2);
+        assert!(
+            commands[0].starts_with("lua local ok, err = pcall(vim.api.nvim_win_close, 0, true)")
+        );
+        assert!(commands[1].starts_with("lua local ok, err = pcall(vim.api.nvim_win_hide, 1001)"));
+        assert!(commands[1].ends_with(
+            "if not ok then vim.notify('rns: ' .. tostring(err), vim.log.levels.ERROR) end"
+        ));
+    }
+
+    #[test]
+    fn changes_directory_to_paths_with_spaces() {
+        let lua = mock::lua();
+        lua.create_table(0, 0);
+        register_window_functions(&lua).unwrap();
+        let getcwd = mock::get(-1).field("getcwd");
+
+        let path = CString::new("/home/me/My Projects").unwrap();
+        let empty = CString::new("").unwrap();
+        assert_eq!(nvim_set_current_dir(path.as_ptr()), 1);
+        assert_eq!(nvim_set_current_dir(empty.as_ptr()), 0);
+        assert_eq!(
+            mock::commands(),
+            ["lua vim.cmd('cd ' .. vim.fn.fnameescape('/home/me/My Projects'))"]
+        );
+
+        mock::set_result("return vim.fn.getcwd()", "/home/me/My Projects".into());
+        let results = mock::call(&getcwd, []);
+        assert_eq!(results[0].as_str(), Some("/home/me/My Projects"));
+    }
+}